@@ -0,0 +1,596 @@
+use std::io::{BufReader, Cursor, Read};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::{Bytes, BytesMut};
+use indicatif::ProgressBar;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::fastq_reader::*;
+use crate::fastq_record::FastqRecord;
+use crate::seq_tag::make_description;
+use crate::utils::*;
+
+pub(crate) const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
+
+/// Largest record accepted, so that the size of a corrupt record never
+/// allocates more: even the longest reads and their qualities fit in it
+const MAX_BLOCK_SIZE: usize = 1 << 30;
+
+/// 4-bit encoded nucleotides of the BAM `seq` field
+const BAM_NT16: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+// Record flags
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/// SAM tags carried into the record description: cell barcode and UMI
+pub(crate) const BAM_TAGS: [&[u8; 2]; 2] = [b"CB", b"UB"];
+
+/// BamReader: Reads records from a decompressed BAM stream.
+///
+/// Each primary record is converted into a `FastqRecord`: reads stored on the
+/// reverse strand are reverse complemented back to their sequenced
+/// orientation, and the SAM tags in [`BAM_TAGS`] are embedded into the
+/// description as `MIRE{CB:...:UB:...}`, the same format written by
+/// `seq_refine()`. Secondary and supplementary alignments are skipped so that
+/// each read is reported once.
+pub(crate) struct BamReader<R> {
+    reader: R,
+    offset: usize, // Record count
+    header_read: bool,
+}
+
+impl<R: Read> BamReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            reader,
+            offset: 0,
+            header_read: false,
+        }
+    }
+
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn read_header(&mut self) -> Result<()> {
+        let mut magic = [0u8; 4];
+        self.reader
+            .read_exact(&mut magic)
+            .with_context(|| format!("BAM parse error: failed to read magic"))?;
+        if &magic != BAM_MAGIC {
+            return Err(anyhow!("BAM parse error: invalid magic, not a BAM file"));
+        }
+        // SAM header text
+        let l_text = self.read_i32()?;
+        self.skip(l_text as u64)?;
+        // Reference sequences
+        let n_ref = self.read_i32()?;
+        for _ in 0 .. n_ref {
+            let l_name = self.read_i32()?;
+            self.skip(l_name as u64 + 4)?;
+        }
+        self.header_read = true;
+        Ok(())
+    }
+
+    fn read_i32(&mut self) -> Result<i32> {
+        let mut buf = [0u8; 4];
+        self.reader
+            .read_exact(&mut buf)
+            .with_context(|| format!("BAM parse error: unexpected end of header"))?;
+        Ok(i32::from_le_bytes(buf))
+    }
+
+    fn skip(&mut self, n: u64) -> Result<()> {
+        let skipped = std::io::copy(&mut (&mut self.reader).take(n), &mut std::io::sink())?;
+        if skipped != n {
+            return Err(anyhow!("BAM parse error: unexpected end of header"));
+        }
+        Ok(())
+    }
+
+    /// Reads the size of the next record, `None` at the end of the stream
+    fn read_block_size(&mut self) -> Result<Option<usize>> {
+        let mut buf = [0u8; 4];
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = self.reader.read(&mut buf[filled ..])?;
+            if n == 0 {
+                if filled == 0 {
+                    return Ok(None);
+                }
+                return Err(anyhow!(
+                    "BAM parse error (record: {}): truncated record",
+                    self.offset + 1
+                ));
+            }
+            filled += n;
+        }
+        Ok(Some(u32::from_le_bytes(buf) as usize))
+    }
+
+    /// Reads the next primary record
+    pub(crate) fn read_record(&mut self) -> Result<Option<FastqRecord<Bytes>>> {
+        if !self.header_read {
+            self.read_header()?;
+        }
+        loop {
+            let block_size = match self.read_block_size()? {
+                Some(size) => size,
+                None => return Ok(None),
+            };
+            if block_size > MAX_BLOCK_SIZE {
+                return Err(anyhow!(
+                    "BAM parse error (record: {}): record size {} exceeds {} bytes",
+                    self.offset + 1,
+                    block_size,
+                    MAX_BLOCK_SIZE
+                ));
+            }
+            // The buffer only grows with the bytes read, a truncated record
+            // stops at the end of the stream
+            let mut block = Vec::with_capacity(block_size.min(BUFFER_SIZE));
+            (&mut self.reader)
+                .take(block_size as u64)
+                .read_to_end(&mut block)
+                .with_context(|| format!("BAM parse error (record: {})", self.offset + 1))?;
+            if block.len() != block_size {
+                return Err(anyhow!(
+                    "BAM parse error (record: {}): truncated record",
+                    self.offset + 1
+                ));
+            }
+            self.offset += 1;
+            if let Some(record) = parse_record(Bytes::from(block))
+                .with_context(|| format!("BAM parse error (record: {})", self.offset))?
+            {
+                return Ok(Some(record));
+            }
+        }
+    }
+}
+
+/// SequenceReader: Reads records from FASTQ/FASTA files or from unaligned BAM
+/// files, told apart by the BAM magic bytes once decompressed.
+///
+/// Several files, e.g. the lanes of a sequencing run, are read one after the
+/// other as a single stream, each may have its own format and compression.
+/// Line numbers in errors and in the quarantine file count from the start of
+/// the file they occur in.
+pub(crate) struct SequenceReader<'a> {
+    reader: FileReader<'a>,
+    next_paths: std::vec::IntoIter<&'a Path>,
+    progress_bar: Option<ProgressBar>,
+    parse_options: ParseOptions<'a>,
+}
+
+enum FileReader<'a> {
+    Fastq(Box<FastqReader<'a, Box<dyn Read>>>),
+    Bam(Box<BamReader<BufReader<Box<dyn Read>>>>),
+}
+
+impl<'a> SequenceReader<'a> {
+    /// Opens the first of `paths`, the others are opened once the previous
+    /// one has been read. The progress bar is shared by all files.
+    pub(crate) fn open<P: AsRef<Path>>(
+        paths: &'a [P],
+        progress_bar: Option<ProgressBar>,
+        parse_options: ParseOptions<'a>,
+    ) -> Result<Self> {
+        let mut next_paths = paths
+            .iter()
+            .map(|path| path.as_ref())
+            .collect::<Vec<_>>()
+            .into_iter();
+        let path = next_paths
+            .next()
+            .ok_or_else(|| anyhow!("No input file specified"))?;
+        Ok(Self {
+            reader: FileReader::open(path, progress_bar.clone(), parse_options)?,
+            next_paths,
+            progress_bar,
+            parse_options,
+        })
+    }
+
+    /// Line (FASTQ) or record (BAM) number in the file being read
+    pub(crate) fn offset(&self) -> usize {
+        match &self.reader {
+            FileReader::Fastq(reader) => reader.offset(),
+            FileReader::Bam(reader) => reader.offset(),
+        }
+    }
+
+    pub(crate) fn read_record(&mut self) -> Result<Option<FastqRecord<Bytes>>> {
+        loop {
+            match self.read_or_skip()? {
+                Some(Some(record)) => return Ok(Some(record)),
+                Some(None) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// See [`FastqReader::read_or_skip`], BAM records are never skipped
+    pub(crate) fn read_or_skip(&mut self) -> Result<Option<Option<FastqRecord<Bytes>>>> {
+        loop {
+            let record = match &mut self.reader {
+                FileReader::Fastq(reader) => reader.read_or_skip()?,
+                FileReader::Bam(reader) => reader.read_record()?.map(Some),
+            };
+            if record.is_some() {
+                return Ok(record);
+            }
+            let Some(path) = self.next_paths.next() else {
+                return Ok(None);
+            };
+            self.reader = FileReader::open(path, self.progress_bar.clone(), self.parse_options)?;
+        }
+    }
+}
+
+impl<'a> FileReader<'a> {
+    fn open(
+        path: &Path,
+        progress_bar: Option<ProgressBar>,
+        parse_options: ParseOptions<'a>,
+    ) -> Result<Self> {
+        // BAM is BGZF compressed, its magic bytes follow once decompressed,
+        // which works for the standard input and named pipes alike
+        let mut reader = new_reader(path, BUFFER_SIZE, progress_bar, parse_options.decode_threads)?;
+        let mut magic = Vec::with_capacity(BAM_MAGIC.len());
+        (&mut reader)
+            .take(BAM_MAGIC.len() as u64)
+            .read_to_end(&mut magic)
+            .with_context(|| format!("Failed to read file: {}", path.display()))?;
+        let bam = magic == BAM_MAGIC;
+        let reader: Box<dyn Read> = Box::new(Cursor::new(magic).chain(reader));
+        if bam {
+            return Ok(Self::Bam(Box::new(BamReader::new(BufReader::with_capacity(
+                BUFFER_SIZE,
+                reader,
+            )))));
+        }
+        let mut reader = FastqReader::with_options(BUFFER_SIZE, reader, parse_options);
+        reader.set_name(path.display().to_string());
+        Ok(Self::Fastq(Box::new(reader)))
+    }
+}
+
+/// Converts a BAM record block (without the leading `block_size`), returns
+/// `None` for secondary and supplementary alignments.
+fn parse_record(block: Bytes) -> Result<Option<FastqRecord<Bytes>>> {
+    if block.len() < 32 {
+        return Err(anyhow!("record shorter than its fixed-length fields"));
+    }
+    let l_read_name = block[8] as usize;
+    let n_cigar_op = u16::from_le_bytes([block[12], block[13]]) as usize;
+    let flag = u16::from_le_bytes([block[14], block[15]]);
+    let l_seq = u32::from_le_bytes([block[16], block[17], block[18], block[19]]) as usize;
+    if flag & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) != 0 {
+        return Ok(None);
+    }
+
+    let name_start = 32;
+    let seq_start = name_start + l_read_name + n_cigar_op * 4;
+    let qual_start = seq_start + l_seq.div_ceil(2);
+    let aux_start = qual_start + l_seq;
+    if aux_start > block.len() || l_read_name == 0 {
+        return Err(anyhow!("field lengths exceed the record size"));
+    }
+
+    // read_name is NUL-terminated
+    let id = block.slice(name_start .. name_start + l_read_name - 1);
+
+    let reverse = flag & FLAG_REVERSE != 0;
+    let mut seq = BytesMut::with_capacity(l_seq);
+    for i in 0 .. l_seq {
+        let byte = block[seq_start + i / 2];
+        let code = if i % 2 == 0 { byte >> 4 } else { byte & 0x0F };
+        seq.extend_from_slice(&[BAM_NT16[code as usize]]);
+    }
+    let qual = &block[qual_start .. aux_start];
+    // A missing quality string is stored as 0xFF
+    let mut qual = if l_seq > 0 && qual[0] == 0xFF {
+        None
+    } else {
+        // Phred scores above 93 have no printable FASTQ character
+        if let Some(q) = qual.iter().find(|&&q| q > 93) {
+            return Err(anyhow!("invalid base quality {}, expected at most 93", q));
+        }
+        Some(qual.iter().map(|q| q + 33).collect::<BytesMut>())
+    };
+    if reverse {
+        seq.reverse();
+        for base in seq.iter_mut() {
+            *base = complement(*base);
+        }
+        if let Some(qual) = &mut qual {
+            qual.reverse();
+        }
+    }
+
+    let tags = parse_tags(&block[aux_start ..])?;
+    let desc = if tags.is_empty() {
+        None
+    } else {
+        Some(make_description(&tags, &None))
+    };
+
+    let record = match qual {
+        Some(qual) => FastqRecord::new(
+            id,
+            desc,
+            seq.freeze(),
+            Bytes::from_static(b"+"),
+            qual.freeze(),
+        ),
+        None => FastqRecord::fasta(id, desc, seq.freeze()),
+    };
+    Ok(Some(record))
+}
+
+/// Collects the `Z`-typed values of [`BAM_TAGS`] from the auxiliary fields
+fn parse_tags(mut aux: &[u8]) -> Result<HashMap<Bytes, Vec<&[u8]>>> {
+    let mut tags = HashMap::with_capacity_and_hasher(BAM_TAGS.len(), rustc_hash::FxBuildHasher);
+    while aux.len() >= 3 {
+        let tag = &aux[.. 2];
+        let value_type = aux[2];
+        aux = &aux[3 ..];
+        let size = match value_type {
+            b'A' | b'c' | b'C' => 1,
+            b's' | b'S' => 2,
+            b'i' | b'I' | b'f' => 4,
+            b'Z' | b'H' => match memchr::memchr(0, aux) {
+                Some(end) => {
+                    if value_type == b'Z' && BAM_TAGS.iter().any(|t| t.as_slice() == tag) {
+                        tags.insert(Bytes::copy_from_slice(tag), vec![&aux[.. end]]);
+                    }
+                    end + 1
+                }
+                None => return Err(anyhow!("unterminated string in tag {}", tag_name(tag))),
+            },
+            b'B' => {
+                if aux.len() < 5 {
+                    return Err(anyhow!("truncated array in tag {}", tag_name(tag)));
+                }
+                let width = match aux[0] {
+                    b'c' | b'C' => 1,
+                    b's' | b'S' => 2,
+                    b'i' | b'I' | b'f' => 4,
+                    other => {
+                        return Err(anyhow!(
+                            "invalid array type '{}' in tag {}",
+                            other as char,
+                            tag_name(tag)
+                        ))
+                    }
+                };
+                let count = u32::from_le_bytes([aux[1], aux[2], aux[3], aux[4]]) as usize;
+                5 + width * count
+            }
+            other => {
+                return Err(anyhow!(
+                    "invalid value type '{}' in tag {}",
+                    other as char,
+                    tag_name(tag)
+                ))
+            }
+        };
+        if size > aux.len() {
+            return Err(anyhow!("truncated value in tag {}", tag_name(tag)));
+        }
+        aux = &aux[size ..];
+    }
+    Ok(tags)
+}
+
+fn tag_name(tag: &[u8]) -> String {
+    String::from_utf8_lossy(tag).to_string()
+}
+
+#[inline]
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'M' => b'K',
+        b'K' => b'M',
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'V' => b'B',
+        b'B' => b'V',
+        b'H' => b'D',
+        b'D' => b'H',
+        other => other,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Encodes a minimal BAM record (unmapped, without CIGAR)
+    pub(crate) fn bam_record(name: &str, flag: u16, seq: &str, qual: &[u8], aux: &[u8]) -> Vec<u8> {
+        let mut block = Vec::new();
+        block.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+        block.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+        block.push(name.len() as u8 + 1); // l_read_name
+        block.push(0); // mapq
+        block.extend_from_slice(&4680u16.to_le_bytes()); // bin
+        block.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+        block.extend_from_slice(&flag.to_le_bytes());
+        block.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+        block.extend_from_slice(&(-1i32).to_le_bytes()); // next_refID
+        block.extend_from_slice(&(-1i32).to_le_bytes()); // next_pos
+        block.extend_from_slice(&0i32.to_le_bytes()); // tlen
+        block.extend_from_slice(name.as_bytes());
+        block.push(0);
+        let codes = seq
+            .bytes()
+            .map(|b| BAM_NT16.iter().position(|&x| x == b).unwrap() as u8)
+            .collect::<Vec<_>>();
+        for pair in codes.chunks(2) {
+            block.push(pair[0] << 4 | pair.get(1).copied().unwrap_or(0));
+        }
+        block.extend_from_slice(qual);
+        block.extend_from_slice(aux);
+        let mut out = (block.len() as u32).to_le_bytes().to_vec();
+        out.extend_from_slice(&block);
+        out
+    }
+
+    pub(crate) fn bam_header() -> Vec<u8> {
+        let mut out = BAM_MAGIC.to_vec();
+        let text = b"@HD\tVN:1.6\n";
+        out.extend_from_slice(&(text.len() as i32).to_le_bytes());
+        out.extend_from_slice(text);
+        out.extend_from_slice(&0i32.to_le_bytes()); // n_ref
+        out
+    }
+
+    #[test]
+    fn test_read_bam_records() -> Result<()> {
+        let mut data = bam_header();
+        data.extend(bam_record(
+            "read1",
+            4,
+            "ACGTN",
+            &[30, 31, 32, 33, 34],
+            b"CBZAAAC-1\0NMi\x01\0\0\0UBZTTGG\0",
+        ));
+        // secondary alignment, skipped
+        data.extend(bam_record("read1", 0x100 | 4, "ACG", &[30; 3], b""));
+        // reverse strand, without quality
+        data.extend(bam_record("read2", 0x10, "AACG", &[0xFF; 4], b"XAB\x43\x02\0\0\0\x01\x02"));
+        let mut reader = BamReader::new(Cursor::new(data));
+
+        let record = reader.read_record()?.unwrap();
+        assert_eq!(record.id, "read1");
+        assert_eq!(record.seq, "ACGTN");
+        assert_eq!(record.qual.unwrap(), "?@ABC");
+        let desc = record.desc.unwrap();
+        assert!(desc.starts_with(b"MIRE{") && desc.ends_with(b"}"));
+        assert!(desc.windows(10).any(|w| w == b"CB:AAAC-1:" || w == b"CB:AAAC-1}"));
+        assert!(desc.windows(7).any(|w| w == b"UB:TTGG"));
+
+        let record = reader.read_record()?.unwrap();
+        assert_eq!(record.id, "read2");
+        assert_eq!(record.seq, "CGTT");
+        assert!(record.is_fasta());
+        assert!(record.desc.is_none());
+
+        assert!(reader.read_record()?.is_none());
+        assert_eq!(reader.offset(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sequence_reader_bam() -> Result<()> {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir()?;
+        // Told apart by the magic bytes rather than the file name
+        let path = tmp.path().join("reads.unaligned");
+        // One BGZF-like member for the header and one for the records
+        let mut data = Vec::new();
+        for block in [bam_header(), bam_record("r1", 4, "ACGT", &[40; 4], b"CBZAAAC\0")] {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&block)?;
+            data.extend(encoder.finish()?);
+        }
+        std::fs::write(&path, data)?;
+
+        let paths = [&path];
+        let mut reader = SequenceReader::open(&paths, None, ParseOptions::default())?;
+        assert!(matches!(reader.reader, FileReader::Bam(_)));
+        let record = reader.read_record()?.unwrap();
+        assert_eq!(record.id, "r1");
+        assert_eq!(record.desc.unwrap(), "MIRE{CB:AAAC}");
+        assert_eq!(record.qual.unwrap(), "IIII");
+        assert!(reader.read_record()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sequence_reader_files() -> Result<()> {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir()?;
+        let lane1 = tmp.path().join("reads_L001.fq");
+        std::fs::write(&lane1, "@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nIIII\n")?;
+        let lane2 = tmp.path().join("reads_L002.fq.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"@r3\nACGT\n+\nIIII\n@r4\nACG\n+\nIIII\n")?;
+        std::fs::write(&lane2, encoder.finish()?)?;
+
+        let paths = [&lane1, &lane2];
+        let mut reader = SequenceReader::open(&paths, None, ParseOptions::default())?;
+        for id in ["r1", "r2", "r3"] {
+            assert_eq!(reader.read_record()?.unwrap().id, id);
+        }
+        // Line numbers are those of the file being read
+        assert_eq!(reader.offset(), 4);
+        let error = reader.read_record().unwrap_err().to_string();
+        assert!(error.contains("(line: 8)"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bam_record_to_fastq() -> Result<()> {
+        let mut data = bam_header();
+        data.extend(bam_record("r1", 4, "ACGT", &[0, 30, 40, 93], b""));
+        let mut reader = BamReader::new(Cursor::new(data));
+        let fastq = reader.read_record()?.unwrap().as_vec();
+        assert_eq!(fastq, b"@r1\nACGT\n+\n!?I~\n");
+
+        let mut reader = crate::fastq_reader::FastqReader::new(Cursor::new(fastq));
+        let record = reader.read_record()?.unwrap();
+        assert_eq!(record.id.as_ref(), b"r1");
+        assert_eq!(record.seq.as_ref(), b"ACGT");
+        assert_eq!(record.qual.unwrap().as_ref(), b"!?I~");
+        assert!(reader.read_record()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bam_quality_out_of_range() {
+        let mut data = bam_header();
+        data.extend(bam_record("r1", 4, "ACGT", &[30, 30, 94, 250], b""));
+        let mut reader = BamReader::new(Cursor::new(data));
+        let error = format!("{:?}", reader.read_record().unwrap_err());
+        assert!(error.contains("invalid base quality 94"), "{}", error);
+    }
+
+    #[test]
+    fn test_bam_record_size() {
+        // A corrupt size is rejected before any allocation
+        let mut data = bam_header();
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = BamReader::new(Cursor::new(data));
+        let error = format!("{:?}", reader.read_record().unwrap_err());
+        assert!(error.contains("exceeds"), "{}", error);
+
+        let mut data = bam_header();
+        data.extend_from_slice(&1000u32.to_le_bytes());
+        data.extend_from_slice(&[0; 10]);
+        let mut reader = BamReader::new(Cursor::new(data));
+        let error = format!("{:?}", reader.read_record().unwrap_err());
+        assert!(error.contains("truncated record"), "{}", error);
+    }
+
+    #[test]
+    fn test_invalid_bam() {
+        let mut reader = BamReader::new(Cursor::new(b"@r1\nACGT\n+\n!!!!\n".to_vec()));
+        assert!(reader.read_record().is_err());
+    }
+}
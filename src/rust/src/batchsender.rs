@@ -2,54 +2,65 @@ use crossbeam_channel::{SendError, Sender};
 
 const DEFEALT_BATCH_SIZE: usize = 20;
 
-#[derive(Clone)]
+/// BatchSender: Groups messages into batches numbered in sending order, so
+/// that the consumers running in parallel can restore the input order.
 pub struct BatchSender<T> {
     msg_vec: Vec<T>,
-    tx: Sender<Vec<T>>,
+    tx: Sender<(usize, Vec<T>)>,
     capacity: usize,
+    index: usize,
 }
 
 impl<T> BatchSender<T> {
     #[allow(dead_code)]
-    pub fn new(sender: Sender<Vec<T>>) -> Self {
+    pub fn new(sender: Sender<(usize, Vec<T>)>) -> Self {
         Self::with_capacity(DEFEALT_BATCH_SIZE, sender)
     }
 
-    pub fn with_capacity(capacity: usize, sender: Sender<Vec<T>>) -> Self {
+    pub fn with_capacity(capacity: usize, sender: Sender<(usize, Vec<T>)>) -> Self {
         Self {
             msg_vec: Vec::with_capacity(capacity),
             tx: sender,
             capacity,
+            index: 0,
         }
     }
 
-    pub fn send(&mut self, msg: T) -> Result<(), SendError<Vec<T>>> {
+    pub fn send(&mut self, msg: T) -> Result<(), SendError<(usize, Vec<T>)>> {
         if self.msg_vec.capacity() == 0 {
-            self.tx.send(vec![msg])
+            self.send_batch(vec![msg])
         } else {
             if self.msg_vec.len() >= self.msg_vec.capacity() {
                 let mut pack = Vec::with_capacity(self.capacity);
                 std::mem::swap(&mut self.msg_vec, &mut pack);
-                self.tx.send(pack)?
+                self.send_batch(pack)?
             }
             self.msg_vec.push(msg);
             Ok(())
         }
     }
 
-    pub fn flush(&mut self) -> Result<(), SendError<Vec<T>>> {
+    pub fn flush(&mut self) -> Result<(), SendError<(usize, Vec<T>)>> {
         if !self.msg_vec.is_empty() {
-            self.tx.send(std::mem::take(&mut self.msg_vec))?;
+            let pack = std::mem::take(&mut self.msg_vec);
+            self.send_batch(pack)?;
         }
         Ok(())
     }
+
+    fn send_batch(&mut self, pack: Vec<T>) -> Result<(), SendError<(usize, Vec<T>)>> {
+        let index = self.index;
+        self.index += 1;
+        self.tx.send((index, pack))
+    }
 }
 
 impl<T> Drop for BatchSender<T> {
     fn drop(&mut self) {
         if !self.msg_vec.is_empty() {
             // Just omit the Error message
-            let _ = self.tx.send(std::mem::take(&mut self.msg_vec));
+            let pack = std::mem::take(&mut self.msg_vec);
+            let _ = self.send_batch(pack);
         }
     }
 }
@@ -78,7 +89,7 @@ mod tests {
         batcher.send(3).unwrap();
         batcher.send(4).unwrap(); // should trigger send
         let batch = rx.try_recv().unwrap();
-        assert_eq!(batch, vec![1, 2, 3]);
+        assert_eq!(batch, (0, vec![1, 2, 3]));
     }
 
     #[test]
@@ -90,8 +101,8 @@ mod tests {
         }
         let batch1 = rx.try_recv().unwrap();
         let batch2 = rx.try_recv().unwrap();
-        assert_eq!(batch1, vec![0, 1]);
-        assert_eq!(batch2, vec![2, 3]);
+        assert_eq!(batch1, (0, vec![0, 1]));
+        assert_eq!(batch2, (1, vec![2, 3]));
         assert!(rx.try_recv().is_err()); // 4 is still buffered
     }
 
@@ -102,7 +113,7 @@ mod tests {
         batcher.send(42).unwrap();
         batcher.flush().unwrap();
         let batch = rx.try_recv().unwrap();
-        assert_eq!(batch, vec![42]);
+        assert_eq!(batch, (0, vec![42]));
     }
 
     #[test]
@@ -113,6 +124,6 @@ mod tests {
             batcher.send(99).unwrap();
         } // dropped
         let batch = rx.recv().unwrap();
-        assert_eq!(batch, vec![99]);
+        assert_eq!(batch, (0, vec![99]));
     }
 }
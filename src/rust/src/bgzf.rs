@@ -0,0 +1,133 @@
+use std::io::Write;
+
+use anyhow::{anyhow, Result};
+use libdeflater::{CompressionLvl, Compressor};
+
+/// Maximal number of uncompressed bytes in one BGZF block, the same limit as
+/// `bgzip`, so that every compressed block fits into 64 KiB.
+pub(crate) const BGZF_BLOCK_SIZE: usize = 0xff00;
+const BGZF_MAX_BLOCK_SIZE: usize = 0x10000;
+const BGZF_HEADER_SIZE: usize = 18;
+const BGZF_FOOTER_SIZE: usize = 8;
+
+/// The empty block terminating every BGZF file
+pub(crate) const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Compresses `bytes` into consecutive BGZF blocks: gzip members carrying the
+/// `BC` extra field with the size of the block.
+pub(crate) fn bgzf_pack(bytes: &[u8], compressor: &mut Compressor) -> Result<Vec<u8>> {
+    let mut pack = Vec::with_capacity(
+        compressor.deflate_compress_bound(bytes.len())
+            + bytes.len().div_ceil(BGZF_BLOCK_SIZE) * (BGZF_HEADER_SIZE + BGZF_FOOTER_SIZE),
+    );
+    let mut store: Option<Compressor> = None;
+    let mut cdata = vec![0u8; BGZF_MAX_BLOCK_SIZE];
+    for block in bytes.chunks(BGZF_BLOCK_SIZE) {
+        let mut size = compressor
+            .deflate_compress(block, &mut cdata)
+            .unwrap_or(usize::MAX);
+        if size > BGZF_MAX_BLOCK_SIZE - BGZF_HEADER_SIZE - BGZF_FOOTER_SIZE {
+            // Incompressible data, store the block uncompressed
+            let store = store.get_or_insert_with(|| {
+                Compressor::new(CompressionLvl::new(0).expect("level 0 is valid"))
+            });
+            size = store.deflate_compress(block, &mut cdata)?;
+        }
+        let block_size = BGZF_HEADER_SIZE + size + BGZF_FOOTER_SIZE;
+        pack.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0]);
+        pack.extend_from_slice(&((block_size - 1) as u16).to_le_bytes());
+        pack.extend_from_slice(&cdata[.. size]);
+        pack.extend_from_slice(&libdeflater::crc32(block).to_le_bytes());
+        pack.extend_from_slice(&(block.len() as u32).to_le_bytes());
+    }
+    Ok(pack)
+}
+
+/// BgzfIndex: Collects the block offsets of a BGZF file for its `.gzi` index.
+///
+/// The index lists the compressed and uncompressed offsets of every block
+/// but the first one, in the layout written by `bgzip --index`.
+#[derive(Debug, Default)]
+pub(crate) struct BgzfIndex {
+    entries: Vec<(u64, u64)>,
+    compressed_offset: u64,
+    uncompressed_offset: u64,
+}
+
+impl BgzfIndex {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the blocks of a chunk produced by [`bgzf_pack`], chunks must be
+    /// added in the order they are written.
+    pub(crate) fn add_blocks(&mut self, mut chunk: &[u8]) -> Result<()> {
+        while !chunk.is_empty() {
+            if chunk.len() < BGZF_HEADER_SIZE || chunk[12 .. 14] != *b"BC" {
+                return Err(anyhow!("Invalid BGZF block header"));
+            }
+            let block_size = u16::from_le_bytes([chunk[16], chunk[17]]) as usize + 1;
+            if block_size > chunk.len() {
+                return Err(anyhow!("Truncated BGZF block"));
+            }
+            let isize = &chunk[block_size - 4 .. block_size];
+            if self.compressed_offset > 0 {
+                self.entries
+                    .push((self.compressed_offset, self.uncompressed_offset));
+            }
+            self.compressed_offset += block_size as u64;
+            self.uncompressed_offset +=
+                u32::from_le_bytes([isize[0], isize[1], isize[2], isize[3]]) as u64;
+            chunk = &chunk[block_size ..];
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for (compressed, uncompressed) in &self.entries {
+            writer.write_all(&compressed.to_le_bytes())?;
+            writer.write_all(&uncompressed.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::MultiGzDecoder;
+
+    use super::*;
+
+    #[test]
+    fn test_bgzf_pack() -> Result<()> {
+        let data = (0 .. 200_000u32)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let mut compressor = Compressor::new(CompressionLvl::default());
+        let mut pack = bgzf_pack(&data, &mut compressor)?;
+        let mut index = BgzfIndex::new();
+        index.add_blocks(&pack)?;
+        assert_eq!(
+            index.entries.iter().map(|e| e.1).collect::<Vec<_>>(),
+            vec![0xff00, 0x1fe00, 0x2fd00]
+        );
+        assert_eq!(index.entries[0].0, (pack[16] as u64 | (pack[17] as u64) << 8) + 1);
+
+        pack.extend_from_slice(&BGZF_EOF);
+        let mut out = Vec::new();
+        MultiGzDecoder::new(&pack[..]).read_to_end(&mut out)?;
+        assert_eq!(out, data);
+
+        let mut gzi = Vec::new();
+        index.write(&mut gzi)?;
+        assert_eq!(gzi.len(), 8 + 3 * 16);
+        assert_eq!(&gzi[.. 8], &3u64.to_le_bytes());
+        Ok(())
+    }
+}
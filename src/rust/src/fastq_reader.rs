@@ -1,40 +1,279 @@
+use std::collections::VecDeque;
 use std::io::Read;
 
 use anyhow::Result;
 use bytes::{Bytes, BytesMut};
-use memchr::memchr2;
+use memchr::{memchr2, memchr_iter};
 
 use crate::fastq_record::FastqParseError;
-use crate::fastq_record::FastqRecord;
+use crate::fastq_record::{FastqRecord, RecordViolation};
+use crate::quarantine::Quarantine;
+use crate::read_id::ReadIdNormalizer;
 use crate::reader::*;
 
-pub(crate) struct FastqReader<R> {
+/// Sequence file format, detected from the first record of the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SeqFormat {
+    Fastq,
+    Fasta,
+}
+
+/// Options controlling how FASTQ records are parsed
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ParseOptions<'a> {
+    /// Accept wrapped FASTQ, where sequence and quality span several lines
+    pub(crate) multiline: bool,
+    /// How read IDs are normalised when pairing mates and matching Kraken2
+    /// output
+    pub(crate) id_normalizer: ReadIdNormalizer,
+    /// Number of threads decompressing each gzip and BGZF input, see
+    /// [`decode_threads`](crate::utils::decode_threads)
+    pub(crate) decode_threads: usize,
+    /// Validation mode: also check bases, quality characters and separator
+    /// lines, see [`FastqRecord::validate`]
+    pub(crate) validate: bool,
+    /// Lenient mode: malformed FASTQ records are skipped and handed to the
+    /// quarantine instead of failing the run
+    pub(crate) quarantine: Option<&'a Quarantine>,
+}
+
+pub(crate) struct FastqReader<'a, R> {
     reader: LineReader<R>,
+    options: ParseOptions<'a>,
+    format: Option<SeqFormat>,
+    // Lines read ahead of the current record: FASTA sequences span an unknown
+    // number of lines, so the header line of the next record is only
+    // discovered after reading it, and in lenient mode the lines following
+    // the start of a malformed record are parsed again.
+    pending: VecDeque<Bytes>,
+    // In lenient mode, the raw lines of the record being parsed: handles on
+    // the lines, which the record fields share
+    record_lines: Vec<Bytes>,
+    // Input name reported in the quarantine file
+    name: String,
+    // When the input is a range of a memory-mapped file, the content before
+    // it: its lines are only counted once a malformed record is reported.
+    preceding: &'a [u8],
+    line_base: usize,
 }
 
-impl<R: Read> FastqReader<R> {
+impl<'a, R: Read> FastqReader<'a, R> {
     #[allow(dead_code)]
     pub(crate) fn new(reader: R) -> Self {
         Self::with_capacity(8 * 1024, reader)
     }
 
     pub(crate) fn with_capacity(capacity: usize, reader: R) -> Self {
+        Self::with_options(capacity, reader, ParseOptions::default())
+    }
+
+    pub(crate) fn with_options(capacity: usize, reader: R, options: ParseOptions<'a>) -> Self {
         Self {
             reader: LineReader::with_capacity(capacity, reader),
+            options,
+            format: None,
+            pending: VecDeque::new(),
+            record_lines: Vec::new(),
+            name: String::new(),
+            preceding: &[],
+            line_base: 0,
+        }
+    }
+
+    /// Sets the input name reported in the quarantine file
+    pub(crate) fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    /// Sets the content preceding the input in its file, so that malformed
+    /// records are reported with line numbers counted from the file start
+    pub(crate) fn set_preceding(&mut self, preceding: &'a [u8]) {
+        self.preceding = preceding;
+    }
+
+    /// Number of lines preceding the input
+    fn line_base(&mut self) -> usize {
+        if !self.preceding.is_empty() {
+            self.line_base = memchr_iter(b'\n', self.preceding).count();
+            self.preceding = &[];
         }
+        self.line_base
     }
 
     pub(crate) fn offset(&self) -> usize {
-        self.reader.offset()
+        self.reader.offset() - self.pending.len()
+    }
+
+    /// The detected format, `None` until the first record has been read
+    #[allow(dead_code)]
+    pub(crate) fn format(&self) -> Option<SeqFormat> {
+        self.format
+    }
+
+    #[inline]
+    fn next_line(&mut self) -> std::io::Result<Option<Bytes>> {
+        match self.pending.pop_front() {
+            Some(line) => Ok(Some(line)),
+            None => Ok(self.reader.read_line()?.map(BytesMut::freeze)),
+        }
     }
 
     #[inline]
-    fn read_line(&mut self) -> std::io::Result<Option<BytesMut>> {
-        self.reader.read_line()
+    fn read_line(&mut self) -> std::io::Result<Option<Bytes>> {
+        let line = self.next_line()?;
+        if self.options.quarantine.is_some() && self.format != Some(SeqFormat::Fasta) {
+            if let Some(line) = &line {
+                self.record_lines.push(line.clone());
+            }
+        }
+        Ok(line)
     }
 
+    /// Reads the next record. The format (FASTQ or FASTA) is detected from the
+    /// first header line: `@` starts a FASTQ record and `>` a FASTA record.
+    /// In lenient mode, malformed records are skipped.
     #[inline]
     pub(crate) fn read_record(&mut self) -> Result<Option<FastqRecord<Bytes>>> {
+        loop {
+            match self.read_or_skip()? {
+                Some(Some(record)) => return Ok(Some(record)),
+                Some(None) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Reads the next record, returns `Some(None)` for a malformed record
+    /// skipped in lenient mode, so that callers pairing mates by position can
+    /// drop its mate as well.
+    pub(crate) fn read_or_skip(&mut self) -> Result<Option<Option<FastqRecord<Bytes>>>> {
+        let Some(quarantine) = self.options.quarantine else {
+            return match self.parse_record() {
+                Ok(record) => Ok(record.map(Some)),
+                Err(e) => match e.downcast::<FastqParseError>() {
+                    Ok(error) => Err(error.shift_lines(self.line_base()).into()),
+                    Err(e) => Err(e),
+                },
+            };
+        };
+        self.record_lines.clear();
+        let start = self.offset() + 1;
+        match self.parse_record() {
+            Ok(record) => Ok(record.map(Some)),
+            Err(e) => {
+                let error = e.downcast::<FastqParseError>()?;
+                // A record failing validation was fully read
+                let resync = match error {
+                    FastqParseError::InvalidBase { .. }
+                    | FastqParseError::InvalidQuality { .. }
+                    | FastqParseError::SepMismatch { .. } => false,
+                    // The four lines of a single-line record were read: they
+                    // make up the whole record unless the next line is not a
+                    // header, in which case its quality line was missing
+                    FastqParseError::UnequalLength { .. } if !self.options.multiline => {
+                        !self.next_is_header()?
+                    }
+                    _ => true,
+                };
+                self.quarantine_record(quarantine, start, error, resync)?;
+                Ok(Some(None))
+            }
+        }
+    }
+
+    /// Hands the lines of a malformed record to the quarantine. With `resync`,
+    /// parsing resumes at the next line starting with `@`: either one already
+    /// read as part of the bad record (e.g. the header of the next record
+    /// after a truncated one), or the next one in the input.
+    fn quarantine_record(
+        &mut self,
+        quarantine: &Quarantine,
+        start: usize,
+        error: FastqParseError,
+        resync: bool,
+    ) -> Result<()> {
+        let mut lines = std::mem::take(&mut self.record_lines);
+        let blank = lines
+            .iter()
+            .take_while(|line| line.iter().all(|b| b.is_ascii_whitespace()))
+            .count();
+        lines.drain(.. blank);
+        if resync {
+            self.resync(&mut lines)?;
+        }
+        let base = self.line_base();
+        quarantine.add(&self.name, base + start + blank, &lines, error.shift_lines(base))
+    }
+
+    /// Whether the next line starts a record, or the input ends
+    fn next_is_header(&mut self) -> std::io::Result<bool> {
+        let Some(line) = self.next_line()? else {
+            return Ok(true);
+        };
+        let header = line.first() == Some(&b'@');
+        self.pending.push_front(line);
+        Ok(header)
+    }
+
+    /// Pushes back the lines of `lines` from the first one starting with `@`
+    /// after the record header, or moves the following lines of the input
+    /// into `lines` up to the next one starting with `@`.
+    fn resync(&mut self, lines: &mut Vec<Bytes>) -> std::io::Result<()> {
+        if let Some(pos) = lines.iter().skip(1).position(|line| line.first() == Some(&b'@')) {
+            for line in lines.drain(pos + 1 ..).rev() {
+                self.pending.push_front(line);
+            }
+            return Ok(());
+        }
+        while let Some(line) = self.next_line()? {
+            if line.first() == Some(&b'@') {
+                self.pending.push_front(line);
+                break;
+            }
+            lines.push(line);
+        }
+        Ok(())
+    }
+
+    /// In validation mode, checks the record content. `seq_pos` and `sep_pos`
+    /// are the line numbers of the first sequence line and of the separator.
+    fn check_record(
+        &self,
+        record: &FastqRecord<Bytes>,
+        seq_pos: usize,
+        sep_pos: usize,
+    ) -> Result<()> {
+        if !self.options.validate {
+            return Ok(());
+        }
+        let Some(violation) = record.validate() else {
+            return Ok(());
+        };
+        let mut lines: Vec<&[u8]> = vec![&record.seq];
+        if let (Some(sep), Some(qual)) = (&record.sep, &record.qual) {
+            lines.extend([sep.as_ref(), qual.as_ref()]);
+        }
+        let text = record_text(&record.id, &record.desc, &lines);
+        let error = match violation {
+            RecordViolation::InvalidBase(base) => FastqParseError::InvalidBase {
+                record: text,
+                base,
+                pos: seq_pos,
+            },
+            RecordViolation::InvalidQuality(qual) => FastqParseError::InvalidQuality {
+                record: text,
+                qual,
+                pos: sep_pos + 1,
+            },
+            RecordViolation::SepMismatch => FastqParseError::SepMismatch {
+                record: text,
+                pos: sep_pos,
+            },
+        };
+        Err(error.into())
+    }
+
+    fn parse_record(&mut self) -> Result<Option<FastqRecord<Bytes>>> {
         let mut header;
         loop {
             if let Some(line) = self.read_line()? {
@@ -48,6 +287,27 @@ impl<R: Read> FastqReader<R> {
                 return Ok(None);
             }
         }
+        let header_pos = self.offset();
+
+        let format = match self.format {
+            Some(format) => format,
+            None => {
+                let format = match header.first() {
+                    Some(b'>') => SeqFormat::Fasta,
+                    _ => SeqFormat::Fastq,
+                };
+                self.format = Some(format);
+                format
+            }
+        };
+        if format == SeqFormat::Fasta {
+            let _ = header.split_to(1); // remove the '>' from the start of the sequence ID
+            let (id, desc) = split_header(header);
+            let seq = self.read_fasta_sequence()?;
+            let record = FastqRecord::fasta(id, desc, seq);
+            self.check_record(&record, header_pos + 1, 0)?;
+            return Ok(Some(record));
+        }
 
         // SAFETY: we must ensure line is not empty, this is ensured by the caller function
         if header.is_empty() || unsafe { *header.get_unchecked(0) } != b'@' {
@@ -57,25 +317,14 @@ impl<R: Read> FastqReader<R> {
             })?;
         }
         let _ = header.split_to(1); // remove the '@' from the start of the sequence ID
-        let id;
-        let desc;
-        if let Some(line_pos) = memchr2(b' ', b'\t', &header) {
-            id = header.split_to(line_pos).freeze();
-            let _ = header.split_to(1); // remove the blankspace
-                                        // check if description exits
-            if header.is_empty() {
-                desc = None
-            } else {
-                desc = Some(header.freeze());
-            }
-        } else {
-            id = header.freeze();
-            desc = None;
+        let (id, desc) = split_header(header);
+        if self.options.multiline {
+            return self.read_multiline_record(id, desc, header_pos).map(Some);
         }
 
         // 2nd line (sequence) must exist. Otherwise, incomplete record.
         let seq = if let Some(line) = self.read_line()? {
-            Ok(line.freeze())
+            Ok(line)
         } else {
             Err(FastqParseError::IncompleteRecord {
                 record: format!(
@@ -108,7 +357,7 @@ impl<R: Read> FastqReader<R> {
                     pos: self.offset(),
                 })
             } else {
-                Ok(line.freeze())
+                Ok(line)
             }
         } else {
             Err(FastqParseError::IncompleteRecord {
@@ -145,7 +394,7 @@ impl<R: Read> FastqReader<R> {
                     pos: self.offset(),
                 })
             } else {
-                Ok(line.freeze())
+                Ok(line)
             }
         } else {
             Err(FastqParseError::IncompleteRecord {
@@ -161,7 +410,141 @@ impl<R: Read> FastqReader<R> {
                 pos: self.offset(),
             })
         }?;
-        Ok(Some(FastqRecord::new(id, desc, seq, sep, qual)))
+        let record = FastqRecord::new(id, desc, seq, sep, qual);
+        self.check_record(&record, header_pos + 1, header_pos + 2)?;
+        Ok(Some(record))
+    }
+
+    /// Parses a wrapped FASTQ record: sequence lines are collected up to the
+    /// `+` separator, and quality lines until their length reaches the
+    /// sequence length. Quality lines may start with `@` or `+`, so only the
+    /// length tells where the record ends.
+    fn read_multiline_record(
+        &mut self,
+        id: Bytes,
+        desc: Option<Bytes>,
+        header_pos: usize,
+    ) -> Result<FastqRecord<Bytes>> {
+        let mut seq = WrappedLines::default();
+        let sep = loop {
+            let Some(line) = self.read_line()? else {
+                Err(FastqParseError::IncompleteRecord {
+                    record: record_text(&id, &desc, &[seq.as_slice()]),
+                    pos: self.offset(),
+                })?
+            };
+            if line.first() == Some(&b'+') {
+                break line;
+            }
+            seq.push(line);
+        };
+        let sep_pos = self.offset();
+        let seq = seq.freeze();
+
+        let mut qual = WrappedLines::default();
+        while qual.len() < seq.len() {
+            let Some(line) = self.read_line()? else {
+                Err(FastqParseError::IncompleteRecord {
+                    record: record_text(&id, &desc, &[&seq, &sep, qual.as_slice()]),
+                    pos: self.offset(),
+                })?
+            };
+            qual.push(line);
+        }
+        let qual = qual.freeze();
+        if qual.len() != seq.len() {
+            Err(FastqParseError::UnequalLength {
+                seq: seq.len(),
+                qual: qual.len(),
+                record: record_text(&id, &desc, &[&seq, &sep, &qual]),
+                pos: self.offset(),
+            })?;
+        }
+        let record = FastqRecord::new(id, desc, seq, sep, qual);
+        self.check_record(&record, header_pos + 1, sep_pos)?;
+        Ok(record)
+    }
+
+    /// Collects FASTA sequence lines until the next `>` header or EOF.
+    /// The header of the next record is kept in `pending`.
+    fn read_fasta_sequence(&mut self) -> Result<Bytes> {
+        let mut seq = WrappedLines::default();
+        while let Some(line) = self.read_line()? {
+            if line.first() == Some(&b'>') {
+                self.pending.push_back(line);
+                break;
+            }
+            if line.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+            seq.push(line);
+        }
+        Ok(seq.freeze())
+    }
+}
+
+/// The lines of a wrapped field, a single line stays zero-copy
+#[derive(Default)]
+struct WrappedLines {
+    line: Bytes,
+    joined: Option<BytesMut>,
+}
+
+impl WrappedLines {
+    #[inline]
+    fn push(&mut self, line: Bytes) {
+        match &mut self.joined {
+            Some(joined) => joined.extend_from_slice(&line),
+            None if self.line.is_empty() => self.line = line,
+            None => {
+                let mut joined = BytesMut::with_capacity((self.line.len() + line.len()) * 2);
+                joined.extend_from_slice(&self.line);
+                joined.extend_from_slice(&line);
+                self.joined = Some(joined);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        self.joined.as_deref().unwrap_or(&self.line)
+    }
+
+    fn freeze(self) -> Bytes {
+        self.joined.map_or(self.line, BytesMut::freeze)
+    }
+}
+
+/// Formats the lines read so far for error messages
+fn record_text(id: &[u8], desc: &Option<Bytes>, lines: &[&[u8]]) -> String {
+    let mut text = String::from_utf8_lossy(id).to_string();
+    if let Some(desc) = desc {
+        text.push(' ');
+        text.push_str(&String::from_utf8_lossy(desc));
+    }
+    for line in lines {
+        text.push('\n');
+        text.push_str(&String::from_utf8_lossy(line));
+    }
+    text
+}
+
+/// Splits a header line (without the leading marker) into ID and description
+fn split_header(mut header: Bytes) -> (Bytes, Option<Bytes>) {
+    if let Some(line_pos) = memchr2(b' ', b'\t', &header) {
+        let id = header.split_to(line_pos);
+        let _ = header.split_to(1); // remove the blankspace
+                                    // check if description exits
+        if header.is_empty() {
+            (id, None)
+        } else {
+            (id, Some(header))
+        }
+    } else {
+        (header, None)
     }
 }
 
@@ -170,12 +553,21 @@ mod tests {
     use std::io::Cursor;
 
     use super::*;
+    use crate::quarantine::Quarantine;
 
-    fn create_reader(data: &str) -> FastqReader<Cursor<&[u8]>> {
+    fn create_reader(data: &str) -> FastqReader<'_, Cursor<&[u8]>> {
         let reader = Cursor::new(data.as_bytes());
         FastqReader::new(reader)
     }
 
+    fn create_multiline_reader(data: &str) -> FastqReader<'_, Cursor<&[u8]>> {
+        let reader = Cursor::new(data.as_bytes());
+        FastqReader::with_options(8 * 1024, reader, ParseOptions {
+            multiline: true,
+            ..Default::default()
+        })
+    }
+
     #[test]
     fn test_read_valid_record() -> Result<()> {
         let fastq_data = "@seq1 description\nATGC\n+\n!!!!\n@seq2\nGCGT\n+\n$$$$\n";
@@ -188,8 +580,8 @@ mod tests {
         assert_eq!(record.id.as_ref(), b"seq1");
         assert_eq!(record.desc.unwrap().as_ref(), b"description");
         assert_eq!(record.seq.as_ref(), b"ATGC");
-        assert_eq!(record.sep.as_ref(), b"+");
-        assert_eq!(record.qual.as_ref(), b"!!!!");
+        assert_eq!(record.sep.unwrap().as_ref(), b"+");
+        assert_eq!(record.qual.unwrap().as_ref(), b"!!!!");
 
         Ok(())
     }
@@ -242,4 +634,200 @@ mod tests {
         assert!(result.unwrap().is_none());
         Ok(())
     }
+
+    #[test]
+    fn test_read_fasta_records() -> Result<()> {
+        let fasta_data = ">seq1 description\nATGC\nGGTA\n\n>seq2\nGCGT\n>seq3\n";
+
+        let mut reader = create_reader(fasta_data);
+
+        let record = reader.read_record()?.expect("Should have a record");
+        assert_eq!(reader.format(), Some(SeqFormat::Fasta));
+        assert_eq!(record.id.as_ref(), b"seq1");
+        assert_eq!(record.desc.unwrap().as_ref(), b"description");
+        assert_eq!(record.seq.as_ref(), b"ATGCGGTA");
+        assert!(record.sep.is_none());
+        assert!(record.qual.is_none());
+
+        let record = reader.read_record()?.expect("Should have a record");
+        assert_eq!(record.id.as_ref(), b"seq2");
+        assert!(record.desc.is_none());
+        assert_eq!(record.seq.as_ref(), b"GCGT");
+
+        // A header without sequence lines yields an empty sequence
+        let record = reader.read_record()?.expect("Should have a record");
+        assert_eq!(record.id.as_ref(), b"seq3");
+        assert!(record.seq.is_empty());
+
+        assert!(reader.read_record()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_multiline_records() -> Result<()> {
+        // The second quality line of seq1 starts with '@' and must not be
+        // taken for a header
+        let fastq_data = "@seq1 desc\nATGC\nGG\n+\n!!!!\n@#\n@seq2\nGCGT\n+seq2\n$$$$\n";
+
+        let mut reader = create_multiline_reader(fastq_data);
+
+        let record = reader.read_record()?.expect("Should have a record");
+        assert_eq!(record.id.as_ref(), b"seq1");
+        assert_eq!(record.seq.as_ref(), b"ATGCGG");
+        assert_eq!(record.qual.unwrap().as_ref(), b"!!!!@#");
+
+        let record = reader.read_record()?.expect("Should have a record");
+        assert_eq!(record.id.as_ref(), b"seq2");
+        assert_eq!(record.seq.as_ref(), b"GCGT");
+        assert_eq!(record.sep.unwrap().as_ref(), b"+seq2");
+
+        assert!(reader.read_record()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiline_errors() -> Result<()> {
+        // quality longer than the sequence
+        let mut reader = create_multiline_reader("@seq1\nATGC\n+\n!!\n!!!\n");
+        let err = reader.read_record().unwrap_err().to_string();
+        assert!(err.contains("(line: 5)"), "{}", err);
+        assert!(err.contains("(4 vs 5)"), "{}", err);
+
+        // missing separator
+        let mut reader = create_multiline_reader("@seq1\nATGC\nGGTT\n");
+        let err = reader.read_record().unwrap_err().to_string();
+        assert!(err.contains("incomplete record"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lenient_quarantine() -> Result<()> {
+        // seq2 lost its sequence line, seq4 has a short quality and seq6 is
+        // truncated in the middle of a line
+        let fastq_data = "@seq1\nATGC\n+\n!!!!\n@seq2\n+\n!!!!\n@seq3\nGCGT\n+\n$$$$\n\
+            @seq4\nATGC\n+\n!!\n@seq5\nTTTT\n+\n####\n@seq6\nATG";
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("bad.txt");
+        let quarantine = Quarantine::new(path.to_str(), None)?;
+        let mut reader = FastqReader::with_options(
+            8 * 1024,
+            Cursor::new(fastq_data.as_bytes()),
+            ParseOptions {
+                quarantine: Some(&quarantine),
+                ..Default::default()
+            },
+        );
+        reader.set_name("test.fq".to_string());
+        let mut ids = Vec::new();
+        while let Some(record) = reader.read_or_skip()? {
+            ids.push(record.map(|record| record.id));
+        }
+        assert_eq!(ids, vec![
+            Some(Bytes::from_static(b"seq1")),
+            None,
+            Some(Bytes::from_static(b"seq3")),
+            None,
+            Some(Bytes::from_static(b"seq5")),
+            None,
+        ]);
+        // invalid_head, invalid_sep, unequal_length, incomplete_record
+        assert_eq!(quarantine.counts(), [0, 1, 1, 1, 0, 0, 0]);
+        quarantine.flush()?;
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            "#test.fq:5-7\tinvalid_sep\n@seq2\n+\n!!!!\n\
+             #test.fq:12-15\tunequal_length\n@seq4\nATGC\n+\n!!\n\
+             #test.fq:20-21\tincomplete_record\n@seq6\nATG\n"
+        );
+
+        // Aborts once more than `max_bad` records were skipped
+        let quarantine = Quarantine::new(None, Some(1))?;
+        let mut reader = FastqReader::with_options(
+            8 * 1024,
+            Cursor::new(fastq_data.as_bytes()),
+            ParseOptions {
+                quarantine: Some(&quarantine),
+                ..Default::default()
+            },
+        );
+        let err = std::iter::from_fn(|| reader.read_record().transpose())
+            .collect::<Result<Vec<_>>>()
+            .unwrap_err();
+        assert!(err.to_string().contains("Too many malformed records"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lenient_unequal_length() -> Result<()> {
+        // The quality of seq2 starts with `@`, seq4 lost its quality line
+        let fastq_data = "@seq1\nATGC\n+\n!!!!\n@seq2\nATGC\n+\n@@@\n@seq3\nGCGT\n+\n$$$$\n\
+            @seq4\nATGC\n+\n@seq5\nTTTT\n+\n####\n";
+        let quarantine = Quarantine::new(None, None)?;
+        let mut reader = FastqReader::with_options(
+            8 * 1024,
+            Cursor::new(fastq_data.as_bytes()),
+            ParseOptions {
+                quarantine: Some(&quarantine),
+                ..Default::default()
+            },
+        );
+        let mut ids = Vec::new();
+        while let Some(record) = reader.read_or_skip()? {
+            ids.push(record.map(|record| record.id));
+        }
+        assert_eq!(ids, vec![
+            Some(Bytes::from_static(b"seq1")),
+            None,
+            Some(Bytes::from_static(b"seq3")),
+            None,
+            Some(Bytes::from_static(b"seq5")),
+        ]);
+        // Each bad record is counted once, as unequal_length
+        assert_eq!(quarantine.counts(), [0, 0, 2, 0, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_records() -> Result<()> {
+        let validate = |data: &'static str, multiline: bool| {
+            let mut reader = FastqReader::with_options(8 * 1024, Cursor::new(data.as_bytes()), ParseOptions {
+                multiline,
+                validate: true,
+                ..Default::default()
+            });
+            std::iter::from_fn(|| reader.read_record().transpose())
+                .collect::<Result<Vec<_>>>()
+                .map_err(|e| e.to_string())
+        };
+        assert!(validate("@seq1\nACGT\n+seq1\n!!!!\n", false).is_ok());
+
+        let err = validate("@seq1\nACGT\n+\n!!!!\n@seq2\nAXGT\n+\n!!!!\n", false).unwrap_err();
+        assert!(err.contains("(line: 6): invalid base 'X'"), "{}", err);
+        let err = validate("@seq1\nACGT\n+\n!! !\n", false).unwrap_err();
+        assert!(err.contains("(line: 4): quality character ' '"), "{}", err);
+        let err = validate("@seq1\nACGT\n+seq2\n!!!!\n", false).unwrap_err();
+        assert!(err.contains("(line: 3): separator line"), "{}", err);
+
+        // Wrapped records report the line where the field starts
+        let err = validate("@seq1\nAC\nGT\n+\n!!\n!\u{7f}\n", true).unwrap_err();
+        assert!(err.contains("(line: 5): quality character '\\x7f'"), "{}", err);
+
+        // FASTA records only have their bases checked
+        let err = validate(">seq1\nACGT\n>seq2\nAC\nG*\n", false).unwrap_err();
+        assert!(err.contains("(line: 4): invalid base '*'"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_fastq_format() -> Result<()> {
+        let fastq_data = "\n@seq1\nATGC\n+\n!!!!\n";
+
+        let mut reader = create_reader(fastq_data);
+        assert_eq!(reader.format(), None);
+
+        let record = reader.read_record()?.expect("Should have a record");
+        assert_eq!(reader.format(), Some(SeqFormat::Fastq));
+        assert!(!record.is_fasta());
+        Ok(())
+    }
 }
@@ -1,12 +1,14 @@
 use std::io::Write;
 
+/// A sequence record. FASTA records carry no separator and quality lines,
+/// and are written back as FASTA.
 #[derive(Debug)]
 pub(crate) struct FastqRecord<T> {
     pub(crate) id: T,
     pub(crate) desc: Option<T>,
     pub(crate) seq: T,
-    pub(crate) sep: T,
-    pub(crate) qual: T,
+    pub(crate) sep: Option<T>,
+    pub(crate) qual: Option<T>,
 }
 
 impl<T> FastqRecord<T> {
@@ -16,10 +18,55 @@ impl<T> FastqRecord<T> {
             id,
             desc,
             seq,
-            sep,
-            qual,
+            sep: Some(sep),
+            qual: Some(qual),
         }
     }
+
+    pub(crate) fn fasta(id: T, desc: Option<T>, seq: T) -> Self {
+        Self {
+            id,
+            desc,
+            seq,
+            sep: None,
+            qual: None,
+        }
+    }
+
+    /// Whether this record has no quality, i.e. it came from a FASTA file
+    pub(crate) fn is_fasta(&self) -> bool {
+        self.qual.is_none()
+    }
+}
+
+/// A content violation found by [`FastqRecord::validate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RecordViolation {
+    InvalidBase(u8),
+    InvalidQuality(u8),
+    SepMismatch,
+}
+
+#[inline]
+fn is_iupac(base: u8) -> bool {
+    matches!(
+        base.to_ascii_uppercase(),
+        b'A' | b'C'
+            | b'G'
+            | b'T'
+            | b'U'
+            | b'R'
+            | b'Y'
+            | b'S'
+            | b'W'
+            | b'K'
+            | b'M'
+            | b'B'
+            | b'D'
+            | b'H'
+            | b'V'
+            | b'N'
+    )
 }
 
 impl<T: AsRef<[u8]>> FastqRecord<T> {
@@ -29,18 +76,22 @@ impl<T: AsRef<[u8]>> FastqRecord<T> {
     }
 
     pub(crate) fn bytes_size(&self) -> usize {
-        self.id.as_ref().len()
+        let size = self.id.as_ref().len()
             // extra one for space between id and description
             + self.desc.as_ref().map(|d| d.as_ref().len() + 1).unwrap_or(0) // ' '
-            + self.seq.as_ref().len()
-            + self.sep.as_ref().len()
-            + self.qual.as_ref().len()
-            + 5 // '@' and 4 * '\n'
+            + self.seq.as_ref().len();
+        match (&self.sep, &self.qual) {
+            (Some(sep), Some(qual)) => {
+                size + sep.as_ref().len() + qual.as_ref().len() + 5 // '@' and 4 * '\n'
+            }
+            _ => size + 3, // '>' and 2 * '\n'
+        }
     }
 
-    /// Efficiently appends the FASTQ record to the provided Vec<u8>
+    /// Efficiently appends the FASTQ (or FASTA) record to the provided Vec<u8>
     pub(crate) fn extend(&self, buf: &mut Vec<u8>) {
-        buf.push(b'@');
+        let fasta = self.is_fasta();
+        buf.push(if fasta { b'>' } else { b'@' });
         buf.extend_from_slice(self.id.as_ref());
 
         if let Some(desc) = &self.desc {
@@ -51,34 +102,17 @@ impl<T: AsRef<[u8]>> FastqRecord<T> {
         buf.push(b'\n');
         buf.extend_from_slice(self.seq.as_ref());
         buf.push(b'\n');
-        buf.extend_from_slice(self.sep.as_ref());
-        buf.push(b'\n');
-        buf.extend_from_slice(self.qual.as_ref());
-        buf.push(b'\n');
+        if let (Some(sep), Some(qual)) = (&self.sep, &self.qual) {
+            buf.extend_from_slice(sep.as_ref());
+            buf.push(b'\n');
+            buf.extend_from_slice(qual.as_ref());
+            buf.push(b'\n');
+        }
     }
 
     pub(crate) fn as_vec(&self) -> Vec<u8> {
-        let id = self.id.as_ref();
-        let desc = self.desc.as_ref().map(|d| d.as_ref());
-        let seq = self.seq.as_ref();
-        let sep = self.sep.as_ref();
-        let qual = self.qual.as_ref();
         let mut buffer = Vec::with_capacity(self.bytes_size());
-        buffer.push(b'@');
-        buffer.extend_from_slice(id);
-
-        if let Some(desc) = &desc {
-            buffer.push(b' ');
-            buffer.extend_from_slice(desc);
-        }
-
-        buffer.push(b'\n');
-        buffer.extend_from_slice(seq);
-        buffer.push(b'\n');
-        buffer.extend_from_slice(sep);
-        buffer.push(b'\n');
-        buffer.extend_from_slice(qual);
-        buffer.push(b'\n');
+        self.extend(&mut buffer);
         buffer
     }
 
@@ -86,8 +120,8 @@ impl<T: AsRef<[u8]>> FastqRecord<T> {
     pub(crate) fn write_buf(&self, buf: &mut [u8]) -> std::io::Result<usize> {
         let mut pos = 0;
 
-        // Write '@' and ID
-        buf[pos] = b'@';
+        // Write '@' (or '>' for FASTA) and ID
+        buf[pos] = if self.is_fasta() { b'>' } else { b'@' };
         pos += 1;
 
         let id = self.id.as_ref();
@@ -130,8 +164,13 @@ impl<T: AsRef<[u8]>> FastqRecord<T> {
         buf[pos] = b'\n';
         pos += 1;
 
+        // FASTA records stop after the sequence
+        let (Some(sep), Some(qual)) = (&self.sep, &self.qual) else {
+            return Ok(pos);
+        };
+
         // Separator
-        let sep = self.sep.as_ref();
+        let sep = sep.as_ref();
         if pos + sep.len() + 1 > buf.len() {
             return Err(std::io::ErrorKind::WriteZero.into());
         }
@@ -141,7 +180,7 @@ impl<T: AsRef<[u8]>> FastqRecord<T> {
         pos += 1;
 
         // Quality
-        let qual = self.qual.as_ref();
+        let qual = qual.as_ref();
         if pos + qual.len() + 1 > buf.len() {
             return Err(std::io::ErrorKind::WriteZero.into());
         }
@@ -153,14 +192,48 @@ impl<T: AsRef<[u8]>> FastqRecord<T> {
         Ok(pos)
     }
 
+    /// Checks the record content: sequence characters must be IUPAC
+    /// nucleotide codes, quality characters within the Phred+33 range (`!` to
+    /// `~`), and a separator line with text after `+` must repeat the header.
+    pub(crate) fn validate(&self) -> Option<RecordViolation> {
+        if let Some(&base) = self.seq.as_ref().iter().find(|&&b| !is_iupac(b)) {
+            return Some(RecordViolation::InvalidBase(base));
+        }
+        if let Some(qual) = &self.qual {
+            if let Some(&qual) = qual.as_ref().iter().find(|&&q| !(b'!' ..= b'~').contains(&q)) {
+                return Some(RecordViolation::InvalidQuality(qual));
+            }
+        }
+        if let Some(sep) = &self.sep {
+            if let Some(header) = sep.as_ref().get(1 ..).filter(|h| !h.is_empty()) {
+                if !self.is_header(header) {
+                    return Some(RecordViolation::SepMismatch);
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether `header` is the record ID, alone or followed by the description
+    fn is_header(&self, header: &[u8]) -> bool {
+        let id = self.id.as_ref();
+        if header == id {
+            return true;
+        }
+        match (&self.desc, header.strip_prefix(id)) {
+            (Some(desc), Some(rest)) => rest.len() > 1 && &rest[1 ..] == desc.as_ref(),
+            _ => false,
+        }
+    }
+
     #[allow(dead_code)]
     pub(crate) fn as_ref(&self) -> FastqRecord<&[u8]> {
         FastqRecord {
             id: self.id.as_ref(),
             desc: self.desc.as_ref().map(|d| d.as_ref()),
             seq: self.seq.as_ref(),
-            sep: self.sep.as_ref(),
-            qual: self.qual.as_ref(),
+            sep: self.sep.as_ref().map(|s| s.as_ref()),
+            qual: self.qual.as_ref().map(|q| q.as_ref()),
         }
     }
 }
@@ -188,6 +261,20 @@ pub enum FastqParseError {
         record: String,
         pos: usize,
     },
+    InvalidBase {
+        record: String,
+        base: u8,
+        pos: usize,
+    },
+    InvalidQuality {
+        record: String,
+        qual: u8,
+        pos: usize,
+    },
+    SepMismatch {
+        record: String,
+        pos: usize,
+    },
     FastqPairError {
         read1_id: String,
         read2_id: String,
@@ -234,6 +321,31 @@ impl fmt::Display for FastqParseError {
                     pos, record
                 )
             }
+            FastqParseError::InvalidBase { record, base, pos } => {
+                write!(
+                    f,
+                    "FASTQ parse error (line: {}): invalid base '{}' in sequence\n{}",
+                    pos,
+                    std::ascii::escape_default(*base),
+                    record
+                )
+            }
+            FastqParseError::InvalidQuality { record, qual, pos } => {
+                write!(
+                    f,
+                    "FASTQ parse error (line: {}): quality character '{}' outside the Phred+33 range\n{}",
+                    pos,
+                    std::ascii::escape_default(*qual),
+                    record
+                )
+            }
+            FastqParseError::SepMismatch { record, pos } => {
+                write!(
+                    f,
+                    "FASTQ parse error (line: {}): separator line does not repeat the header\n{}",
+                    pos, record
+                )
+            }
             FastqParseError::FastqPairError {
                 read1_id,
                 read2_id,
@@ -261,6 +373,32 @@ impl fmt::Display for FastqParseError {
 
 impl Error for FastqParseError {}
 
+impl FastqParseError {
+    /// Moves the reported line numbers by `lines`, the number of lines
+    /// preceding the parsed part of the file
+    pub(crate) fn shift_lines(mut self, lines: usize) -> Self {
+        match &mut self {
+            FastqParseError::InvalidHead { pos, .. }
+            | FastqParseError::UnequalLength { pos, .. }
+            | FastqParseError::InvalidSep { pos, .. }
+            | FastqParseError::IncompleteRecord { pos, .. }
+            | FastqParseError::InvalidBase { pos, .. }
+            | FastqParseError::InvalidQuality { pos, .. }
+            | FastqParseError::SepMismatch { pos, .. } => *pos += lines,
+            FastqParseError::FastqPairError {
+                read1_pos,
+                read2_pos,
+                ..
+            } => {
+                for pos in [read1_pos, read2_pos].into_iter().flatten() {
+                    *pos += lines;
+                }
+            }
+        }
+        self
+    }
+}
+
 #[cfg(test)]
 mod test_record {
     use std::io::Cursor;
@@ -300,6 +438,55 @@ mod test_record {
         let expected = b"@SEQ_ID\nACGTACGT\n+\nIIIIIIII\n";
         assert_eq!(output.into_inner(), expected);
     }
+
+    #[test]
+    fn test_fasta_record_write() {
+        let record = FastqRecord::fasta(
+            b"SEQ_ID".as_ref(),
+            Some(b"desc".as_ref()),
+            b"ACGTACGT".as_ref(),
+        );
+        assert!(record.is_fasta());
+
+        let mut output = Cursor::new(Vec::new());
+        record.write(&mut output).expect("Write failed");
+
+        let expected = b">SEQ_ID desc\nACGTACGT\n";
+        assert_eq!(record.bytes_size(), expected.len());
+        assert_eq!(output.into_inner(), expected);
+    }
+
+    #[test]
+    fn test_fastq_record_validate() {
+        let record = |seq: &'static str, sep: &'static str, qual: &'static str| {
+            FastqRecord::new(
+                b"SEQ_ID".as_ref(),
+                Some(b"desc".as_ref()),
+                seq.as_bytes(),
+                sep.as_bytes(),
+                qual.as_bytes(),
+            )
+        };
+        assert_eq!(record("ACGTNryk", "+", "II!~IIII").validate(), None);
+        assert_eq!(record("ACGT", "+SEQ_ID", "IIII").validate(), None);
+        assert_eq!(record("ACGT", "+SEQ_ID desc", "IIII").validate(), None);
+        assert_eq!(
+            record("AC.T", "+", "IIII").validate(),
+            Some(RecordViolation::InvalidBase(b'.'))
+        );
+        assert_eq!(
+            record("ACGT", "+", "II I").validate(),
+            Some(RecordViolation::InvalidQuality(b' '))
+        );
+        assert_eq!(
+            record("ACGT", "+OTHER", "IIII").validate(),
+            Some(RecordViolation::SepMismatch)
+        );
+        assert_eq!(
+            record("ACGT", "+SEQ_ID other", "IIII").validate(),
+            Some(RecordViolation::SepMismatch)
+        );
+    }
 }
 
 #[cfg(test)]
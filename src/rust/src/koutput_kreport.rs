@@ -0,0 +1,540 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::BytesMut;
+use crossbeam_channel::{Receiver, Sender};
+use extendr_api::prelude::*;
+use libdeflater::CompressionLvl;
+use memchr::memchr;
+use rustc_hash::FxHashMap as HashMap;
+
+use crate::batchsender::BatchSender;
+use crate::kreport::parse_kreport;
+use crate::reader::LineReader;
+use crate::taxonomy::{read_taxonomy, TaxonomyTree};
+use crate::utils::*;
+
+/// Bits of the hash indexing the registers of a [`HyperLogLog`]: 1024
+/// registers of one byte, for a standard error of about 3%.
+const HLL_PRECISION: u32 = 10;
+
+/// A HyperLogLog sketch estimating the number of distinct hashes inserted. The
+/// registers are only allocated with the first hash, as most taxa hit by the
+/// k-mers of the LCA mappings are never inserted into.
+#[derive(Debug, Default, Clone)]
+struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    fn insert(&mut self, hash: u64) {
+        if self.registers.is_empty() {
+            self.registers = vec![0; 1 << HLL_PRECISION];
+        }
+        let index = (hash >> (64 - HLL_PRECISION)) as usize;
+        let rank = ((hash << HLL_PRECISION).leading_zeros() + 1).min(64 - HLL_PRECISION + 1) as u8;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    /// Makes this sketch the union of both sketches
+    fn merge(&mut self, other: &Self) {
+        if other.registers.is_empty() {
+            return;
+        }
+        if self.registers.is_empty() {
+            self.registers = other.registers.clone();
+            return;
+        }
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    /// The estimated number of distinct hashes, with the linear counting of
+    /// the empty registers for small cardinalities
+    fn estimate(&self) -> f64 {
+        if self.registers.is_empty() {
+            return 0.0;
+        }
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|&register| (-(register as i32) as f64).exp2())
+            .sum::<f64>();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&register| register == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+/// The SplitMix64 finalizer, spreading the bits of `x` over the whole hash
+fn mix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Reads, estimated minimizers and distinct minimizers assigned to a taxon
+#[derive(Debug, Default, Clone)]
+struct TaxonCounts {
+    reads: usize,
+    minimizers: f64,
+    distinct: HyperLogLog,
+}
+
+/// Counts of a koutput file, keyed by taxid
+#[derive(Debug, Default)]
+struct KoutputCounts {
+    unclassified: usize,
+    taxa: HashMap<Vec<u8>, TaxonCounts>,
+}
+
+impl KoutputCounts {
+    fn merge(&mut self, other: Self) {
+        self.unclassified += other.unclassified;
+        for (taxid, counts) in other.taxa {
+            let entry = self.taxa.entry(taxid).or_default();
+            entry.reads += counts.reads;
+            entry.minimizers += counts.minimizers;
+            entry.distinct.merge(&counts.distinct);
+        }
+    }
+
+    /// Adds a line of the koutput: the read goes to its taxon, and the k-mers
+    /// of the LCA mapping to the taxa they hit. The sequences are not in the
+    /// koutput, so the minimizers are identified by the LCA mapping and their
+    /// position in it: duplicated reads share their minimizers, as in kraken2.
+    fn add_line(&mut self, line: &[u8], density: f64) -> Result<()> {
+        let mut fields = line.split(|b| *b == b'\t');
+        let (Some(status), Some(_), Some(taxid), Some(_), Some(lca)) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) else {
+            return Err(anyhow!(
+                "Invalid koutput line: {:?}",
+                String::from_utf8_lossy(line)
+            ));
+        };
+        match status {
+            b"U" => {
+                self.unclassified += 1;
+                return Ok(());
+            }
+            b"C" => {}
+            _ => {
+                return Err(anyhow!(
+                    "Invalid classification status: {:?}",
+                    String::from_utf8_lossy(status)
+                ))
+            }
+        }
+        // With `kraken2 --use-names`, the taxid column holds the scientific
+        // name followed by the taxid, e.g. "Bacteria (taxid 2)"
+        let taxid = match KOUTPUT_TAXID_PREFIX_FINDER.find(taxid) {
+            Some(start) => {
+                let start = start + KOUTPUT_TAXID_PREFIX.len();
+                let end = memchr(KOUTPUT_TAXID_SUFFIX, &taxid[start ..])
+                    .ok_or_else(|| anyhow!("Invalid taxid: {:?}", String::from_utf8_lossy(taxid)))?;
+                &taxid[start .. start + end]
+            }
+            None => taxid,
+        };
+        self.taxa.entry(taxid.to_vec()).or_default().reads += 1;
+
+        let mut hasher = DefaultHasher::new();
+        lca.hash(&mut hasher);
+        let lca_hash = hasher.finish();
+        // Minimizers of the read preceding the run
+        let mut position = 0.0;
+
+        // The LCA mapping is a space-delimited list of "taxid:count" runs of
+        // consecutive k-mers, "A" marks ambiguous k-mers, "0" k-mers missing
+        // from the database and "|:|" separates the mates of paired reads.
+        for run in lca.split(|b| *b == b' ') {
+            if run == b"|:|" {
+                continue;
+            }
+            let Some(sep) = memchr::memrchr(b':', run) else {
+                continue;
+            };
+            let (taxid, n_kmers) = (&run[.. sep], &run[sep + 1 ..]);
+            if taxid == b"0" || taxid == b"A" {
+                continue;
+            }
+            let n_kmers = parse_usize(n_kmers)?;
+            if n_kmers > 0 {
+                let minimizers = 1.0 + (n_kmers - 1) as f64 * density;
+                let counts = self.taxa.entry(taxid.to_vec()).or_default();
+                counts.minimizers += minimizers;
+                let (start, end) = (f64::ceil(position), f64::ceil(position + minimizers));
+                for i in start as u64 .. end as u64 {
+                    counts.distinct.insert(mix64(lca_hash ^ mix64(i)));
+                }
+                position += minimizers;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The expected fraction of k-mers starting a new minimizer: for random
+/// sequences, the minimizer of a window of `k - l + 1` l-mers changes with a
+/// probability of `2 / (k - l + 2)`.
+fn minimizer_density(kmer_len: usize, minimizer_len: usize) -> Result<f64> {
+    if minimizer_len == 0 || minimizer_len > kmer_len {
+        return Err(anyhow!(
+            "'minimizer_len' ({}) must be positive and no larger than 'kmer_len' ({})",
+            minimizer_len,
+            kmer_len
+        ));
+    }
+    let window = kmer_len - minimizer_len + 1;
+    Ok(if window == 1 {
+        1.0
+    } else {
+        2.0 / (window + 1) as f64
+    })
+}
+
+fn count_koutput<P: AsRef<Path> + ?Sized>(
+    koutput: &P,
+    density: f64,
+    batch_size: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<KoutputCounts> {
+    let input: &Path = koutput.as_ref();
+    let pb = input_progress_bar(input)?;
+    pb.set_prefix("Parsing koutput");
+
+    std::thread::scope(|scope| {
+        let (reader_tx, reader_rx): (Sender<(usize, Vec<BytesMut>)>, Receiver<(usize, Vec<BytesMut>)>) =
+            new_channel(nqueue);
+
+        // ─── Parser Thread ─────────────────────────────────────
+        // Each thread counts its own lines, merged once all lines are read
+        let mut parser_handles = Vec::with_capacity(threads);
+        for _ in 0 .. threads {
+            let rx = reader_rx.clone();
+            let handle = scope.spawn(move || -> Result<KoutputCounts> {
+                let mut counts = KoutputCounts::default();
+                while let Ok((_, lines)) = rx.recv() {
+                    for line in lines {
+                        if line.is_empty() {
+                            continue;
+                        }
+                        counts.add_line(&line, density).with_context(|| {
+                            format!("Failed to parse koutput file: '{}'", input.display())
+                        })?;
+                    }
+                }
+                Ok(counts)
+            });
+            parser_handles.push(handle);
+        }
+        drop(reader_rx);
+
+        // ─── reader Thread ─────────────────────────────────────
+        let reader_handle = scope.spawn(move || -> Result<()> {
+            let mut reader = LineReader::with_capacity(
+                BUFFER_SIZE,
+                new_reader(input, BUFFER_SIZE, Some(pb), decode_threads(threads, 1))?,
+            );
+            let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
+            while let Some(line) = reader
+                .read_line()
+                .with_context(|| format!("(Reader) Failed to read line"))?
+            {
+                // The parser threads stop once failed, and drop the receiver
+                if reader_tx.send(line).is_err() {
+                    break;
+                }
+            }
+            let _ = reader_tx.flush();
+            Ok(())
+        });
+
+        // ─── Join Threads and Propagate Errors ────────────────
+        let mut counts = KoutputCounts::default();
+        for handler in parser_handles {
+            counts.merge(
+                handler
+                    .join()
+                    .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??,
+            );
+        }
+        reader_handle
+            .join()
+            .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
+        Ok(counts)
+    })
+}
+
+/// Formats the counts as a kraken2 report with minimizer data: the percent of
+/// reads in the clade, the reads in the clade, the reads assigned directly,
+/// the minimizers and distinct minimizers of the clade, the rank code, the
+/// taxid and the name indented by two spaces per depth. As kraken2 does, only
+/// taxa with reads are reported, the children sorted by decreasing clade
+/// reads.
+fn format_kreport(tree: &TaxonomyTree, counts: &KoutputCounts) -> Result<Vec<u8>> {
+    // Reads assigned directly, then reads and minimizers of the clade
+    let mut direct = vec![0; tree.len()];
+    let mut clade = vec![(0, 0.0); tree.len()];
+    // Only the clades of taxa hit by k-mers hold a sketch
+    let mut clade_distinct: HashMap<usize, HyperLogLog> = HashMap::default();
+    for (taxid, taxon_counts) in &counts.taxa {
+        let Some(i) = tree.get(taxid) else {
+            // k-mers of the LCA mapping may hit taxa of the database left out
+            // of the taxonomy, reads may not
+            if taxon_counts.reads > 0 {
+                return Err(anyhow!(
+                    "Taxid {} of the koutput is missing from the taxonomy",
+                    String::from_utf8_lossy(taxid)
+                ));
+            }
+            continue;
+        };
+        direct[i] = taxon_counts.reads;
+        for ancestor in tree.ancestors(i) {
+            clade[ancestor].0 += taxon_counts.reads;
+            clade[ancestor].1 += taxon_counts.minimizers;
+            if !taxon_counts.distinct.registers.is_empty() {
+                clade_distinct
+                    .entry(ancestor)
+                    .or_default()
+                    .merge(&taxon_counts.distinct);
+            }
+        }
+    }
+
+    let total = counts.unclassified + direct.iter().sum::<usize>();
+    let percent = |reads: usize| {
+        if total == 0 {
+            0.0
+        } else {
+            reads as f64 * 100.0 / total as f64
+        }
+    };
+    let mut out = Vec::new();
+    if counts.unclassified > 0 {
+        writeln!(
+            out,
+            "{:6.2}\t{}\t{}\t0\t0\tU\t0\tunclassified",
+            percent(counts.unclassified),
+            counts.unclassified,
+            counts.unclassified
+        )?;
+    }
+    let by_reads = |taxa: &mut Vec<usize>| {
+        // Stable, so that ties keep the order of the taxonomy
+        taxa.sort_by(|a, b| clade[*b].0.cmp(&clade[*a].0));
+    };
+    let mut roots = (0 .. tree.len())
+        .filter(|&i| tree.taxon(i).parent.is_none() && clade[i].0 > 0)
+        .collect::<Vec<_>>();
+    by_reads(&mut roots);
+    let mut stack = roots.into_iter().rev().collect::<Vec<_>>();
+    while let Some(i) = stack.pop() {
+        let taxon = tree.taxon(i);
+        let (reads, minimizers) = clade[i];
+        let distinct = clade_distinct.get(&i).map_or(0.0, |sketch| sketch.estimate());
+        // The sketch errs either way, but distinct minimizers are never more
+        // than the minimizers
+        let minimizers = minimizers.round() as u64;
+        write!(
+            out,
+            "{:6.2}\t{}\t{}\t{}\t{}\t",
+            percent(reads),
+            reads,
+            direct[i],
+            minimizers,
+            (distinct.round() as u64).min(minimizers)
+        )?;
+        out.extend_from_slice(&taxon.rank);
+        out.push(b'\t');
+        out.extend_from_slice(&taxon.taxid);
+        out.push(b'\t');
+        out.resize(out.len() + taxon.depth * 2, b' ');
+        out.extend_from_slice(&taxon.name);
+        out.push(b'\n');
+        let mut children = tree
+            .children(i)
+            .iter()
+            .copied()
+            .filter(|&child| clade[child].0 > 0)
+            .collect::<Vec<_>>();
+        by_reads(&mut children);
+        stack.extend(children.into_iter().rev());
+    }
+    Ok(out)
+}
+
+fn koutput_kreport_internal(
+    koutput: &str,
+    ofile: &str,
+    kreport: Option<&str>,
+    taxdump: Option<&str>,
+    kmer_len: usize,
+    minimizer_len: usize,
+    compression_level: i32,
+    batch_size: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> Result<()> {
+    let density = minimizer_density(kmer_len, minimizer_len)?;
+    let compression_level = CompressionLvl::new(compression_level)
+        .map_err(|e| anyhow!("Invalid 'compression_level': {:?}", e))?;
+    let tree = match (taxdump, kreport) {
+        (Some(taxdump), _) => read_taxonomy(taxdump)?,
+        (None, Some(kreport)) => TaxonomyTree::from_kreports(&parse_kreport(kreport)?.0),
+        (None, None) => return Err(anyhow!("One of 'kreport' or 'taxdump' must be provided")),
+    };
+    let counts = count_koutput(koutput, density, batch_size, nqueue, threads)?;
+    let report = format_kreport(&tree, &counts)?;
+
+    let options = OutputOptions::default();
+    let mut compressor =
+        ChunkCompressor::new(output_compression(Path::new(ofile), options), compression_level)?;
+    let mut writer = OutputWriter::new(ofile, None, BUFFER_SIZE, options)?;
+    writer
+        .write_chunk(&compressor.pack(report)?)
+        .with_context(|| format!("Failed to write kreport to {}", ofile))?;
+    commit_outputs([writer.finish()?])
+}
+
+#[extendr]
+fn koutput_to_kreport(
+    koutput: &str,
+    ofile: &str,
+    kreport: Option<&str>,
+    taxdump: Option<&str>,
+    kmer_len: usize,
+    minimizer_len: usize,
+    compression_level: i32,
+    batch_size: usize,
+    nqueue: Option<usize>,
+    threads: usize,
+) -> std::result::Result<(), String> {
+    koutput_kreport_internal(
+        koutput,
+        ofile,
+        kreport,
+        taxdump,
+        kmer_len,
+        minimizer_len,
+        compression_level,
+        batch_size,
+        nqueue,
+        threads,
+    )
+    .map_err(|e| format!("{:?}", e))
+}
+
+extendr_module! {
+    mod koutput_kreport;
+    fn koutput_to_kreport;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taxonomy::Taxon;
+
+    fn taxon(taxid: &str, rank: &str, name: &str, parent: Option<usize>) -> Taxon {
+        Taxon {
+            taxid: taxid.as_bytes().to_vec(),
+            rank: rank.as_bytes().to_vec(),
+            name: name.as_bytes().to_vec(),
+            parent,
+            depth: 0,
+        }
+    }
+
+    #[test]
+    fn test_koutput_kreport() -> Result<()> {
+        let tree = TaxonomyTree::new([
+            taxon("1", "R", "root", None),
+            taxon("2", "D", "Bacteria", Some(0)),
+            taxon("561", "G", "Escherichia", Some(1)),
+            taxon("562", "S", "Escherichia coli", Some(2)),
+            taxon("1280", "S", "Staphylococcus aureus", Some(1)),
+        ]);
+        let mut counts = KoutputCounts::default();
+        for line in [
+            "C\tr1\t562\t150\t562:10 561:2 0:3",
+            "C\tr2\tEscherichia coli (taxid 562)\t150\t562:1 |:| A:4 562:1",
+            "C\tr3\t1280\t150\t1280:4 9606:2",
+            "C\tr4\t561\t150\t561:1",
+            "C\tr5\t1280\t150\t1280:1",
+            "U\tr6\t0\t150\t0:116",
+        ] {
+            counts.add_line(line.as_bytes(), 1.0)?;
+        }
+        assert_eq!(counts.unclassified, 1);
+        assert_eq!(counts.taxa[&b"562"[..]].reads, 2);
+        assert_eq!(counts.taxa[&b"562"[..]].minimizers, 12.0);
+
+        let report = String::from_utf8(format_kreport(&tree, &counts)?)?;
+        assert_eq!(
+            report,
+            concat!(
+                " 16.67\t1\t1\t0\t0\tU\t0\tunclassified\n",
+                " 83.33\t5\t0\t20\t20\tR\t1\troot\n",
+                " 83.33\t5\t0\t20\t20\tD\t2\t  Bacteria\n",
+                " 50.00\t3\t1\t15\t15\tG\t561\t    Escherichia\n",
+                " 33.33\t2\t2\t12\t12\tS\t562\t      Escherichia coli\n",
+                " 33.33\t2\t2\t5\t5\tS\t1280\t    Staphylococcus aureus\n",
+            )
+        );
+
+        // A duplicated read adds its minimizers, but no distinct ones
+        counts.add_line(b"C\tr1.dup\t562\t150\t562:10 561:2 0:3", 1.0)?;
+        let report = String::from_utf8(format_kreport(&tree, &counts)?)?;
+        assert!(report.contains(" 85.71\t6\t0\t32\t20\tR\t1\troot\n"));
+        assert!(report.contains(" 42.86\t3\t3\t22\t12\tS\t562\t      Escherichia coli\n"));
+
+        // Reads of taxa missing from the taxonomy cannot be placed
+        counts.add_line(b"C\tr7\t9606\t150\t9606:3", 1.0)?;
+        assert!(format_kreport(&tree, &counts).is_err());
+        assert!(counts.add_line(b"C\tr8\t562", 1.0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hyperloglog() {
+        let mut sketch = HyperLogLog::default();
+        assert_eq!(sketch.estimate(), 0.0);
+        for i in 0 .. 100_000 {
+            sketch.insert(mix64(i));
+            sketch.insert(mix64(i));
+        }
+        assert!((sketch.estimate() - 100_000.0).abs() < 10_000.0);
+        let mut other = HyperLogLog::default();
+        for i in 50_000 .. 150_000 {
+            other.insert(mix64(i));
+        }
+        sketch.merge(&other);
+        assert!((sketch.estimate() - 150_000.0).abs() < 15_000.0);
+    }
+
+    #[test]
+    fn test_minimizer_density() -> Result<()> {
+        assert_eq!(minimizer_density(35, 35)?, 1.0);
+        assert_eq!(minimizer_density(35, 31)?, 2.0 / 6.0);
+        assert!(minimizer_density(31, 35).is_err());
+        Ok(())
+    }
+}
@@ -4,12 +4,12 @@ use aho_corasick::AhoCorasick;
 use anyhow::{anyhow, Context, Result};
 use bytes::{Bytes, BytesMut};
 use crossbeam_channel::{Receiver, Sender};
-use indicatif::{ProgressBar, ProgressFinish};
 use memchr::memchr;
 use rustc_hash::FxHashMap as HashMap;
 use rustc_hash::FxHashSet as HashSet;
 
 use crate::batchsender::BatchSender;
+use crate::read_id::ReadIdNormalizer;
 use crate::reader::LineReader;
 use crate::utils::*;
 
@@ -17,15 +17,14 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
     input_path: &P,
     include_sets: HashSet<&[u8]>,
     exclude_aho: Option<AhoCorasick>,
+    id_normalizer: ReadIdNormalizer,
     batch_size: usize,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<HashMap<Bytes, (Bytes, Bytes, Bytes)>> {
     let input: &Path = input_path.as_ref();
-    let style = progress_reader_style()?;
-    let pb = ProgressBar::new(input.metadata()?.len() as u64).with_finish(ProgressFinish::Abandon);
+    let pb = input_progress_bar(input)?;
     pb.set_prefix("Parsing koutput");
-    pb.set_style(style);
 
     // for kmer, we counts total and unique k-mers per taxon across cell barcodes,
     // using both the cell barcode and unique molecular identifier (UMI) to resolve
@@ -36,10 +35,10 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
         // Create a channel between the parser and writer threads
         // The channel transmits batches
         let (koutput_tx, koutput_rx): (
-            Sender<Vec<(Bytes, (Bytes, Bytes, Bytes))>>,
-            Receiver<Vec<(Bytes, (Bytes, Bytes, Bytes))>>,
+            Sender<(usize, Vec<(Bytes, (Bytes, Bytes, Bytes))>)>,
+            Receiver<(usize, Vec<(Bytes, (Bytes, Bytes, Bytes))>)>,
         ) = new_channel(None);
-        let (reader_tx, reader_rx): (Sender<Vec<BytesMut>>, Receiver<Vec<BytesMut>>) =
+        let (reader_tx, reader_rx): (Sender<(usize, Vec<BytesMut>)>, Receiver<(usize, Vec<BytesMut>)>) =
             new_channel(nqueue);
 
         // ─── Parser Thread ─────────────────────────────────────
@@ -53,7 +52,7 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
             let handle = scope.spawn(move || -> Result<()> {
                 let mut thread_tx = BatchSender::with_capacity(batch_size, tx);
                 // let mut compressor = Compressor::new(compression_level);
-                while let Ok(lines) = rx.recv() {
+                while let Ok((_, lines)) = rx.recv() {
                     'chunk_loop: for line in lines {
                         let line = line.freeze();
                         let mut field_start = 0usize;
@@ -70,7 +69,7 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
                                 }
                             } else if field_index == 1 {
                                 // Save sequence_id field (field 2)
-                                sequence_id = Some(field);
+                                sequence_id = Some(id_normalizer.normalize(field));
                             } else if field_index == 2 {
                                 // Save taxid field (field 3) if it passes filtering
                                 // Note: Through the use of `kraken2 --use-names`, 
@@ -145,8 +144,10 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
 
         // ─── reader Thread ─────────────────────────────────────
         let reader_handle = scope.spawn(move || -> Result<()> {
-            let mut reader =
-                LineReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, Some(pb))?);
+            let mut reader = LineReader::with_capacity(
+                BUFFER_SIZE,
+                new_reader(input, BUFFER_SIZE, Some(pb), decode_threads(threads, 1))?,
+            );
             let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
             while let Some(record) = reader
                 .read_line()
@@ -173,7 +174,7 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
             .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
         Ok(koutput_rx
             .into_iter()
-            .flatten()
+            .flat_map(|(_, pairs)| pairs)
             .collect::<HashMap<Bytes, (Bytes, Bytes, Bytes)>>())
     })
 }
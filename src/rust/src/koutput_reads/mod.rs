@@ -1,52 +1,73 @@
+use std::path::PathBuf;
+
 use aho_corasick::{AhoCorasick, AhoCorasickKind};
 use anyhow::{anyhow, Context, Result};
 use extendr_api::prelude::*;
 use libdeflater::CompressionLvl;
-use rustc_hash::FxHashMap as HashMap;
-use rustc_hash::FxHashSet as HashSet;
 
 mod koutput;
 mod reads;
 
+use crate::fastq_reader::ParseOptions;
 use crate::kreport::taxonomy_kreport;
+use crate::read_id::ReadIdNormalizer;
 use crate::seq_tag::robj_to_tag_ranges;
+use crate::taxonomy::{read_taxonomy, TaxonomyTree};
 use crate::utils::*;
 
 #[extendr]
 fn koutput_reads(
     kreport: &str,
     koutput: &str,
-    fq1: &str,
-    fq2: Option<&str>,
+    fq1: Vec<String>,
+    fq2: Option<Vec<String>>,
     ofile: &str,
     taxonomy: Robj,
+    taxdump: Option<&str>,
     // lca: Option<Vec<&str>>, // Only build for the specific LCA
     exclude: Robj,
     ranges1: Robj,
     ranges2: Robj,
+    multiline: bool,
+    interleaved: bool,
+    id_normalize: Option<&str>,
     // polyn_threshold: usize,
     // phred_threshould: usize,
     koutput_batch: usize,
     fastq_batch: usize,
     chunk_bytes: usize,
     compression_level: i32,
+    bgzf: bool,
+    bgzf_index: bool,
+    ordered: bool,
     nqueue: Option<usize>,
     threads: usize,
 ) -> std::result::Result<(), String> {
+    let output_options = OutputOptions {
+        bgzf,
+        bgzf_index,
+        ordered,
+        sharding: None,
+    };
     koutput_reads_internal(
         kreport,
         koutput,
-        fq1,
-        fq2,
+        &fq1,
+        fq2.as_deref(),
         ofile,
         taxonomy,
+        taxdump,
         exclude,
         ranges1,
         ranges2,
+        multiline,
+        interleaved,
+        id_normalize,
         koutput_batch,
         fastq_batch,
         chunk_bytes,
         compression_level,
+        output_options,
         nqueue,
         threads,
     )
@@ -58,17 +79,24 @@ fn koutput_reads(
 fn pprof_koutput_reads(
     kreport: &str,
     koutput: &str,
-    fq1: &str,
-    fq2: Option<&str>,
+    fq1: Vec<String>,
+    fq2: Option<Vec<String>>,
     ofile: &str,
     taxonomy: Robj,
+    taxdump: Option<&str>,
     exclude: Robj,
     ranges1: Robj,
     ranges2: Robj,
+    multiline: bool,
+    interleaved: bool,
+    id_normalize: Option<&str>,
     koutput_batch: usize,
     fastq_batch: usize,
     chunk_bytes: usize,
     compression_level: i32,
+    bgzf: bool,
+    bgzf_index: bool,
+    ordered: bool,
     nqueue: Option<usize>,
     threads: usize,
     pprof_file: &str,
@@ -85,13 +113,20 @@ fn pprof_koutput_reads(
         fq2,
         ofile,
         taxonomy,
+        taxdump,
         exclude,
         ranges1,
         ranges2,
+        multiline,
+        interleaved,
+        id_normalize,
         koutput_batch,
         fastq_batch,
         chunk_bytes,
         compression_level,
+        bgzf,
+        bgzf_index,
+        ordered,
         nqueue,
         threads,
     );
@@ -112,73 +147,53 @@ fn pprof_koutput_reads(
 fn koutput_reads_internal(
     kreport: &str,
     koutput: &str,
-    fq1: &str,
-    fq2: Option<&str>,
+    fq1: &[String],
+    fq2: Option<&[String]>,
     ofile: &str,
     taxonomy: Robj,
+    taxdump: Option<&str>,
     exclude: Robj,
     ranges1: Robj,
     ranges2: Robj,
+    multiline: bool,
+    interleaved: bool,
+    id_normalize: Option<&str>,
     koutput_batch: usize,
     fastq_batch: usize,
     chunk_bytes: usize,
     compression_level: i32,
+    output_options: OutputOptions,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
+    check_stdio(
+        std::iter::once(koutput)
+            .chain(fq1.iter().chain(fq2.into_iter().flatten()).map(|fq| fq.as_str())),
+    )?;
+    check_mate_files(fq1, fq2)?;
+    let fq1 = fq1.iter().map(PathBuf::from).collect::<Vec<_>>();
+    let fq2 = fq2.map(|fq2| fq2.iter().map(PathBuf::from).collect::<Vec<_>>());
     let tag_ranges1 = robj_to_tag_ranges(&ranges1)?;
     let tag_ranges2 = robj_to_tag_ranges(&ranges2)?;
     let compression_level = CompressionLvl::new(compression_level)
         .map_err(|e| anyhow!("Invalid 'compression_level': {:?}", e))?;
     let exclude =
         robj_to_option_str(&exclude).with_context(|| format!("Failed to parse 'exclude'"))?;
-    let kreports = taxonomy_kreport(kreport, taxonomy)?;
-
-    // Build a map: taxid → set of its ancestor taxids
-    let taxid_to_ancestors = kreports
-        .iter()
-        .map(|report| {
-            // Each report's `taxids` field holds the lineage (ancestors) of this taxon
-            let ancestors = report
-                .taxids
-                .iter()
-                .map(|x| x.as_slice())
-                .collect::<HashSet<&[u8]>>();
-            // Map: current taxid → set of its ancestors
-            (report.taxid.as_slice(), ancestors)
-        })
-        .collect::<HashMap<&[u8], HashSet<&[u8]>>>();
+    let id_normalizer = ReadIdNormalizer::parse(id_normalize)?;
+    let taxdump = taxdump.map(read_taxonomy).transpose()?;
+    let (kreports, _) = taxonomy_kreport(Some(kreport), taxonomy, taxdump.as_ref())?;
 
-    // For each taxid, find all other taxids that consider it an ancestor
-    // i.e., build a reverse lookup: taxid → set of descendant taxids
-    let taxid_to_descendants = kreports
-        .iter()
-        .map(|report| {
-            let taxid = report.taxid.as_slice();
-            // Iterate over all taxids and check whose ancestor set includes the current taxid
-            let descendants = taxid_to_ancestors
-                .iter()
-                .filter_map(|(child, parents)| {
-                    if parents.contains(taxid) {
-                        Some(*child)
-                    } else {
-                        None
-                    }
-                })
-                .collect::<HashSet<&[u8]>>();
-
-            // Map: current taxid → set of its descendant taxids
-            (taxid, descendants)
-        })
-        .collect::<HashMap<&[u8], HashSet<&[u8]>>>();
-
-    let include_sets = kreports
-        .iter()
-        // Always include the descendants
-        .filter_map(|kr| taxid_to_descendants.get(kr.taxid.as_slice()))
-        .flatten()
-        .copied()
-        .collect::<HashSet<&[u8]>>();
+    // Always include the descendants, taxa missing from the kreport are only
+    // known from the taxdump
+    let kreport_tree;
+    let tree = match &taxdump {
+        Some(tree) => tree,
+        None => {
+            kreport_tree = TaxonomyTree::from_kreports(&kreports);
+            &kreport_tree
+        }
+    };
+    let include_sets = tree.clade_taxids(kreports.iter().filter_map(|kr| tree.get(&kr.taxid)));
 
     // A space-delimited list indicating the LCA mapping of each
     // k-mer in the sequence(s). For example, "562:13 561:4 A:31 0:1 562:3" would indicate that:
@@ -210,6 +225,7 @@ fn koutput_reads_internal(
         koutput,
         include_sets,
         exclude_aho,
+        id_normalizer,
         koutput_batch,
         nqueue,
         threads,
@@ -223,14 +239,23 @@ fn koutput_reads_internal(
     // For each koutput row, we calculate kmer information
     reads::parse_reads(
         &koutmap,
-        fq1,
-        fq2,
+        &fq1,
+        fq2.as_deref(),
+        interleaved,
         ofile,
         tag_ranges1,
         tag_ranges2,
+        ParseOptions {
+            multiline,
+            id_normalizer,
+            decode_threads: decode_threads(threads, if fq2.is_some() { 2 } else { 1 }),
+            validate: false,
+            quarantine: None,
+        },
         fastq_batch,
         chunk_bytes,
         compression_level,
+        output_options,
         nqueue,
         threads,
     )?;
@@ -1,9 +1,13 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
 use bytes::Bytes;
 use indicatif::{MultiProgress, ProgressBar, ProgressFinish, ProgressStyle};
 use libdeflater::CompressionLvl;
 use rustc_hash::FxHashMap as HashMap;
 
+use crate::fastq_reader::ParseOptions;
+use crate::paired_reader::PairedInput;
 use crate::seq_tag::*;
 use crate::utils::*;
 
@@ -13,24 +17,23 @@ mod stream;
 
 pub(super) fn parse_reads(
     koutmap: &HashMap<Bytes, (Bytes, Bytes, Bytes)>,
-    fq1: &str,
-    fq2: Option<&str>,
+    fq1: &[PathBuf],
+    fq2: Option<&[PathBuf]>,
+    interleaved: bool,
     ofile: &str,
     tag_ranges1: Option<TagRanges>,
     tag_ranges2: Option<TagRanges>,
+    parse_options: ParseOptions,
     batch_size: usize,
     chunk_bytes: usize,
     compression_level: CompressionLvl,
+    output_options: OutputOptions,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
-    let reader_style = progress_reader_style()?;
     let progress = MultiProgress::new();
-    let reader_pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq1)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
+    let reader_pb1 = progress.add(inputs_progress_bar(fq1)?);
     reader_pb1.set_prefix("Reading fq1");
-    reader_pb1.set_style(reader_style.clone());
 
     let matching_pb = ProgressBar::no_length().with_finish(ProgressFinish::Abandon);
     matching_pb.set_style(ProgressStyle::with_template(
@@ -38,20 +41,28 @@ pub(super) fn parse_reads(
     )?);
 
     let threads = threads.max(1); // always use at least one thread
-    if let Some(fq2) = fq2 {
-        let reader_pb2 = progress.add(
-            ProgressBar::new(std::fs::metadata(fq2)?.len() as u64)
-                .with_finish(ProgressFinish::Abandon),
-        );
-        reader_pb2.set_prefix("Reading fq2");
-        reader_pb2.set_style(reader_style);
+    if fq2.is_some() || interleaved {
+        let input = if let Some(fq2) = fq2 {
+            let reader_pb2 = progress.add(inputs_progress_bar(fq2)?);
+            reader_pb2.set_prefix("Reading fq2");
+            PairedInput::Split {
+                input1: fq1,
+                input1_bar: Some(reader_pb1),
+                input2: fq2,
+                input2_bar: Some(reader_pb2),
+            }
+        } else {
+            reader_pb1.set_prefix("Reading fastq");
+            PairedInput::Interleaved {
+                input: fq1,
+                input_bar: Some(reader_pb1),
+            }
+        };
         let matching_pb = progress.add(matching_pb);
         paired::parse_paired_read(
             koutmap,
-            fq1,
-            Some(reader_pb1),
-            fq2,
-            Some(reader_pb2),
+            input,
+            parse_options,
             ofile,
             Some(matching_pb),
             &tag_ranges1,
@@ -59,6 +70,7 @@ pub(super) fn parse_reads(
             batch_size,
             chunk_bytes,
             compression_level,
+            output_options,
             nqueue,
             threads,
         )
@@ -68,12 +80,14 @@ pub(super) fn parse_reads(
             koutmap,
             fq1,
             Some(reader_pb1),
+            parse_options,
             ofile,
             Some(matching_pb),
             &tag_ranges1,
             batch_size,
             chunk_bytes,
             compression_level,
+            output_options,
             nqueue,
             threads,
         )
@@ -1,4 +1,3 @@
-use std::io::{BufWriter, Write};
 use std::iter::zip;
 use std::path::Path;
 
@@ -7,24 +6,22 @@ use bytes::BufMut;
 use bytes::{Bytes, BytesMut};
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
+use libdeflater::CompressionLvl;
 use rustc_hash::FxHashMap as HashMap;
 
 use super::stream::extract_tags_from_desc;
 use super::stream::RecordHandler;
-use crate::batchsender::BatchSender;
 use crate::fastq_reader::*;
-use crate::fastq_record::{FastqParseError, FastqRecord};
+use crate::paired_reader::*;
+use crate::fastq_record::FastqRecord;
 use crate::koutput_reads::reads::stream::KoutreadStream;
 use crate::seq_tag::*;
 use crate::utils::*;
 
 pub(crate) fn parse_paired_read<P: AsRef<Path> + ?Sized>(
     koutmap: &HashMap<Bytes, (Bytes, Bytes, Bytes)>,
-    input1_path: &P,
-    input1_bar: Option<ProgressBar>,
-    input2_path: &P,
-    input2_bar: Option<ProgressBar>,
+    input: PairedInput<'_>,
+    parse_options: ParseOptions,
     output_path: &P,
     matching_bar: Option<ProgressBar>,
     tag_ranges1: &Option<TagRanges>,
@@ -32,44 +29,35 @@ pub(crate) fn parse_paired_read<P: AsRef<Path> + ?Sized>(
     batch_size: usize,
     chunk_bytes: usize,
     compression_level: CompressionLvl,
+    output_options: OutputOptions,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
     let output: &Path = output_path.as_ref();
-    let gzip = gz_compressed(output);
+    let compression = output_compression(output, output_options);
     std::thread::scope(|scope| -> Result<()> {
         // Create a channel between the parser and writer threads
         // The channel transmits batches (Vec<FastqRecord>)
-        let (writer_tx, writer_rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = new_channel(nqueue);
+        let (writer_tx, writer_rx): (Sender<(usize, Vec<u8>)>, Receiver<(usize, Vec<u8>)>) = new_channel(nqueue);
 
-        let (reader_tx, reader_rx): (
-            Sender<(Vec<FastqRecord<Bytes>>, Vec<FastqRecord<Bytes>>)>,
-            Receiver<(Vec<FastqRecord<Bytes>>, Vec<FastqRecord<Bytes>>)>,
-        ) = new_channel(nqueue);
-        let (reader1_tx, reader1_rx): (
-            Sender<Vec<FastqRecord<Bytes>>>,
-            Receiver<Vec<FastqRecord<Bytes>>>,
-        ) = new_channel(nqueue);
-        let (reader2_tx, reader2_rx): (
-            Sender<Vec<FastqRecord<Bytes>>>,
-            Receiver<Vec<FastqRecord<Bytes>>>,
-        ) = new_channel(nqueue);
+        let (reader_tx, reader_rx): (Sender<(usize, RecordPairs)>, Receiver<(usize, RecordPairs)>) =
+            new_channel(nqueue);
 
         // ─── Writer Thread ─────────────────────────────────────
         // Consumes batches of records and writes them to file
-        let writer_handle = scope.spawn(move || -> Result<()> {
-            let mut writer = BufWriter::with_capacity(chunk_bytes, new_writer(output, None)?);
+        let writer_handle = scope.spawn(move || -> Result<PendingOutput> {
+            let mut writer = OutputWriter::new(output, None, chunk_bytes, output_options)?;
 
             // Iterate over each received batch of records
-            for chunk in writer_rx {
-                writer
-                    .write_all(&chunk)
+            let mut chunks = OrderedChunks::new(output_options.ordered);
+            for (index, chunk) in writer_rx {
+                chunks
+                    .push(index, chunk, |chunk| writer.write_chunk(&chunk))
                     .map_err(|e| anyhow!("(Writer) Failed to write to output: {}", e))?;
             }
-            writer
-                .flush()
-                .map_err(|e| anyhow!("(Writer) Failed to flush writer: {}", e))?;
-            Ok(())
+            chunks.finish().map_err(|e| anyhow!("(Writer) Failed to order output: {}", e))?;
+            let output = writer.finish().map_err(|e| anyhow!("(Writer) Failed to finish writer: {}", e))?;
+            Ok(output)
         });
 
         // ─── Parser Thread ─────────────────────────────────────
@@ -81,28 +69,26 @@ pub(crate) fn parse_paired_read<P: AsRef<Path> + ?Sized>(
             let handle = scope.spawn(move || -> Result<()> {
                 let record_handler = PairedRecordHandle::new(tag_ranges1, tag_ranges2);
                 let mut stream = KoutreadStream::with_capacity(chunk_bytes, tx, record_handler);
-                if gzip {
-                    let compressor = Compressor::new(compression_level);
+                if compression != OutputCompression::None {
+                    let compressor = ChunkCompressor::new(compression, compression_level)?;
                     stream.set_compressor(Some(compressor));
                 }
-                while let Ok((records1, records2)) = rx.recv() {
+                stream.set_ordered(output_options.ordered);
+                while let Ok((index, (records1, records2))) = rx.recv() {
+                    stream.start_batch(index);
                     // Initialize a thread-local batch sender for matching records
                     for (record1, record2) in zip(records1, records2) {
-                        if record1.id != record2.id {
-                            return Err(anyhow!("{}", FastqParseError::FastqPairError {
-                                read1_id: String::from_utf8_lossy(&record1.id).to_string(),
-                                read2_id: String::from_utf8_lossy(&record2.id).to_string(),
-                                read1_pos: None,
-                                read2_pos: None
-                            }));
-                        }
-                        if let Some((length, taxid, lca)) = koutmap.get(&record1.id) {
+                        parse_options
+                            .id_normalizer
+                            .check_pair(&record1, &record2, None, None)?;
+                        if let Some((length, taxid, lca)) = koutmap.get(parse_options.id_normalizer.normalize(&record1.id)) {
                             if let Some(bar) = &pb {
                                 bar.inc(1);
                             }
                             stream.process_record(taxid, lca, length, &(record1, record2))?;
                         }
                     }
+                    stream.finish_batch()?;
                 }
                 stream.flush_buffer().map_err(|e| {
                     anyhow!(
@@ -118,93 +104,11 @@ pub(crate) fn parse_paired_read<P: AsRef<Path> + ?Sized>(
         drop(writer_tx);
 
         // ─── reader Thread ─────────────────────────────────────
-        let reader_handle = scope.spawn(move || -> Result<()> {
-            loop {
-                let (records1, records2) = match (reader1_rx.recv(), reader2_rx.recv()) {
-                    (Ok(rec1), Ok(rec2)) => (rec1, rec2),
-                    (Err(_), Ok(_)) => {
-                        return Err(anyhow!(
-                            "(Reader collect) FASTQ pairing error: read1 channel closed before read2"
-                        ));
-                    }
-                    (Ok(_), Err(_)) => {
-                        return Err(anyhow!(
-                            "(Reader collect) FASTQ pairing error: read2 channel closed before read1"
-                        ));
-                    }
-                    (Err(_), Err(_)) => {
-                        break;
-                    }
-                };
-                if records1.len() != records2.len() {
-                    return Err(anyhow!("(Reader collect) FASTQ pairing error: record count mismatch (read1: {}, read2: {})", records1.len(), records2.len()));
-                }
-                reader_tx.send((records1, records2)).map_err(|e| {
-                    anyhow!(
-                        "(Reader collect) Failed to send send parsed record pair to Parser thread: {}",
-                        e
-                    )
-                })?;
-            }
-            Ok(())
-        });
-
-        let input1: &Path = input1_path.as_ref();
-        let reader1_handle = scope.spawn(move || -> Result<()> {
-            let mut reader = FastqReader::with_capacity(
-                BUFFER_SIZE,
-                new_reader(input1, BUFFER_SIZE, input1_bar)?,
-            );
-            let mut thread_tx = BatchSender::with_capacity(batch_size, reader1_tx);
-            while let Some(record) = reader
-                .read_record()
-                .map_err(|e| anyhow!("(Reader1) Error while reading FASTQ record: {}", e))?
-            {
-                thread_tx.send(record).map_err(|e| {
-                    anyhow!(
-                        "(Reader1) Failed to send FASTQ record to reader collect thread: {}",
-                        e
-                    )
-                })?;
-            }
-            thread_tx.flush().map_err(|e| {
-                anyhow!(
-                    "(Reader1) Failed to flush records to reader collect thread: {}",
-                    e
-                )
-            })?;
-            Ok(())
-        });
-
-        let input2: &Path = input2_path.as_ref();
-        let reader2_handle = scope.spawn(move || -> Result<()> {
-            let mut reader = FastqReader::with_capacity(
-                BUFFER_SIZE,
-                new_reader(input2, BUFFER_SIZE, input2_bar)?,
-            );
-            let mut thread_tx = BatchSender::with_capacity(batch_size, reader2_tx);
-            while let Some(record) = reader
-                .read_record()
-                .map_err(|e| anyhow!("(Reader2) Error while reading FASTQ record: {}", e))?
-            {
-                thread_tx.send(record).map_err(|e| {
-                    anyhow!(
-                        "(Reader2) Failed to send FASTQ record to reader collect thread: {}",
-                        e
-                    )
-                })?;
-            }
-            thread_tx.flush().map_err(|e| {
-                anyhow!(
-                    "(Reader2) Failed to flush records to reader collect thread: {}",
-                    e
-                )
-            })?;
-            Ok(())
-        });
+        let reader_handles =
+            spawn_paired_reader(scope, input, parse_options, None, batch_size, nqueue, reader_tx);
 
         // ─── Join Threads and Propagate Errors ────────────────
-        writer_handle
+        let output = writer_handle
             .join()
             .map_err(|e| anyhow!("(Writer dispatch) thread panicked: {:?}", e))??;
 
@@ -213,16 +117,8 @@ pub(crate) fn parse_paired_read<P: AsRef<Path> + ?Sized>(
                 .join()
                 .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??;
         }
-        reader_handle
-            .join()
-            .map_err(|e| anyhow!("(Reader collect) thread panicked: {:?}", e))??;
-        reader1_handle
-            .join()
-            .map_err(|e| anyhow!("(Reader1) thread panicked: {:?}", e))??;
-        reader2_handle
-            .join()
-            .map_err(|e| anyhow!("(Reader2) thread panicked: {:?}", e))??;
-        Ok(())
+        reader_handles.join()?;
+        commit_outputs([output])
     })
 }
 
@@ -310,10 +206,12 @@ impl<'a> RecordHandler for PairedRecordHandle<'a> {
     }
 
     fn qual_len(&self, record: &Self::Record) -> usize {
+        let qual1 = record.0.qual.as_ref().map_or(0, |qual| qual.len());
+        let qual2 = record.1.qual.as_ref().map_or(0, |qual| qual.len());
         if self.pair {
-            record.0.qual.len() + 1 + record.1.qual.len()
+            qual1 + 1 + qual2
         } else {
-            record.1.qual.len()
+            qual2
         }
     }
 
@@ -369,10 +267,15 @@ impl<'a> RecordHandler for PairedRecordHandle<'a> {
     }
 
     fn write_qual(&self, buf: &mut Vec<u8>, record: &Self::Record) {
+        // FASTA input leaves the quality empty
         if self.pair {
-            buf.extend_from_slice(&record.0.qual);
+            if let Some(qual) = &record.0.qual {
+                buf.extend_from_slice(qual);
+            }
             buf.put_u8(b' ');
         }
-        buf.extend_from_slice(&record.1.qual);
+        if let Some(qual) = &record.1.qual {
+            buf.extend_from_slice(qual);
+        }
     }
 }
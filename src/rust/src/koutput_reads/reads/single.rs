@@ -1,15 +1,15 @@
-use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
 use bytes::{Bytes, BytesMut};
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
+use libdeflater::CompressionLvl;
 use rustc_hash::FxHashMap as HashMap;
 
 use super::stream::extract_tags_from_desc;
 use super::stream::RecordHandler;
+use crate::bam_reader::SequenceReader;
 use crate::batchsender::BatchSender;
 use crate::fastq_reader::*;
 use crate::fastq_record::FastqRecord;
@@ -18,45 +18,46 @@ use crate::utils::*;
 
 pub(crate) fn parse_single_read<P: AsRef<Path> + ?Sized>(
     koutmap: &HashMap<Bytes, (Bytes, Bytes, Bytes)>,
-    input_path: &P,
+    input: &[PathBuf],
     input_bar: Option<ProgressBar>,
+    parse_options: ParseOptions,
     output_path: &P,
     matching_bar: Option<ProgressBar>,
     tag_ranges: &Option<TagRanges>,
     batch_size: usize,
     chunk_bytes: usize,
     compression_level: CompressionLvl,
+    output_options: OutputOptions,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
-    let input: &Path = input_path.as_ref();
     let output: &Path = output_path.as_ref();
-    let gzip = gz_compressed(output);
+    let compression = output_compression(output, output_options);
     std::thread::scope(|scope| -> Result<()> {
         // Create a channel between the parser and writer threads
         // The channel transmits batches (Vec<FastqRecord>)
-        let (writer_tx, writer_rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = new_channel(nqueue);
+        let (writer_tx, writer_rx): (Sender<(usize, Vec<u8>)>, Receiver<(usize, Vec<u8>)>) = new_channel(nqueue);
 
         let (reader_tx, reader_rx): (
-            Sender<Vec<FastqRecord<Bytes>>>,
-            Receiver<Vec<FastqRecord<Bytes>>>,
+            Sender<(usize, Vec<FastqRecord<Bytes>>)>,
+            Receiver<(usize, Vec<FastqRecord<Bytes>>)>,
         ) = new_channel(nqueue);
 
         // ─── Writer Thread ─────────────────────────────────────
         // Consumes batches of records and writes them to file
-        let writer_handle = scope.spawn(move || -> Result<()> {
-            let mut writer = BufWriter::with_capacity(chunk_bytes, new_writer(output, None)?);
+        let writer_handle = scope.spawn(move || -> Result<PendingOutput> {
+            let mut writer = OutputWriter::new(output, None, chunk_bytes, output_options)?;
 
             // Iterate over each received batch of records
-            for chunk in writer_rx {
-                writer
-                    .write_all(&chunk)
+            let mut chunks = OrderedChunks::new(output_options.ordered);
+            for (index, chunk) in writer_rx {
+                chunks
+                    .push(index, chunk, |chunk| writer.write_chunk(&chunk))
                     .map_err(|e| anyhow!("(Writer) Failed to write to output: {}", e))?;
             }
-            writer
-                .flush()
-                .map_err(|e| anyhow!("(Writer) Failed to flush writer: {}", e))?;
-            Ok(())
+            chunks.finish().map_err(|e| anyhow!("(Writer) Failed to order output: {}", e))?;
+            let output = writer.finish().map_err(|e| anyhow!("(Writer) Failed to finish writer: {}", e))?;
+            Ok(output)
         });
 
         // ─── Parser Thread ─────────────────────────────────────
@@ -72,19 +73,22 @@ pub(crate) fn parse_single_read<P: AsRef<Path> + ?Sized>(
                     tx,
                     record_handler,
                 );
-                if gzip {
-                    let compressor = Compressor::new(compression_level);
+                if compression != OutputCompression::None {
+                    let compressor = ChunkCompressor::new(compression, compression_level)?;
                     stream.set_compressor(Some(compressor));
                 }
-                while let Ok(records) = rx.recv() {
+                stream.set_ordered(output_options.ordered);
+                while let Ok((index, records)) = rx.recv() {
+                    stream.start_batch(index);
                     for record in records {
-                        if let Some((length, taxid, lca)) = koutmap.get(&record.id) {
+                        if let Some((length, taxid, lca)) = koutmap.get(parse_options.id_normalizer.normalize(&record.id)) {
                             if let Some(bar) = &pb {
                                 bar.inc(1);
                             }
                             stream.process_record(taxid, lca, length, &record)?;
                         }
                     }
+                    stream.finish_batch()?;
                 }
                 stream.flush_buffer().map_err(|e| {
                     anyhow!(
@@ -101,8 +105,7 @@ pub(crate) fn parse_single_read<P: AsRef<Path> + ?Sized>(
 
         // ─── reader Thread ─────────────────────────────────────
         let reader_handle = scope.spawn(move || -> Result<()> {
-            let mut reader =
-                FastqReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, input_bar)?);
+            let mut reader = SequenceReader::open(input, input_bar, parse_options)?;
             let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
             while let Some(record) = reader
                 .read_record()
@@ -122,7 +125,7 @@ pub(crate) fn parse_single_read<P: AsRef<Path> + ?Sized>(
         });
 
         // ─── Join Threads and Propagate Errors ────────────────
-        writer_handle
+        let output = writer_handle
             .join()
             .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))??;
         for handler in parser_handles {
@@ -133,7 +136,7 @@ pub(crate) fn parse_single_read<P: AsRef<Path> + ?Sized>(
         reader_handle
             .join()
             .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
-        Ok(())
+        commit_outputs([output])
     })
 }
 
@@ -176,7 +179,7 @@ impl<'a> RecordHandler for SinlgeRecordHandle<'a> {
     }
 
     fn qual_len(&self, record: &Self::Record) -> usize {
-        record.qual.len()
+        record.qual.as_ref().map_or(0, |qual| qual.len())
     }
 
     fn write_tags(&self, tags: &mut HashMap<Bytes, Bytes>, record: &Self::Record) -> Result<()> {
@@ -205,6 +208,9 @@ impl<'a> RecordHandler for SinlgeRecordHandle<'a> {
     }
 
     fn write_qual(&self, buf: &mut Vec<u8>, record: &Self::Record) {
-        buf.extend_from_slice(&record.qual);
+        // FASTA input leaves the quality field empty
+        if let Some(qual) = &record.qual {
+            buf.extend_from_slice(qual);
+        }
     }
 }
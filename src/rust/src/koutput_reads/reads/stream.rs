@@ -1,18 +1,21 @@
 use anyhow::{anyhow, Result};
 use bytes::{BufMut, Bytes};
 use crossbeam_channel::Sender;
-use libdeflater::Compressor;
 use memchr::memchr;
 use rustc_hash::FxHashMap as HashMap;
 
 use crate::utils::*;
 
 pub(in crate::koutput_reads::reads) struct KoutreadStream<H> {
-    sender: Sender<Vec<u8>>,
+    sender: Sender<(usize, Vec<u8>)>,
     buffer: Vec<u8>,
     chunk_bytes: usize,
+    // Index of the batch being processed, and whether each batch must be
+    // sent as a single chunk to keep the input order
+    batch: usize,
+    ordered: bool,
     tags: HashMap<Bytes, Bytes>,
-    compressor: Option<Compressor>,
+    compressor: Option<ChunkCompressor>,
     handler: H,
 }
 
@@ -38,19 +41,21 @@ where
     H: RecordHandler,
 {
     #[allow(dead_code)]
-    pub(in crate::koutput_reads::reads) fn new(sender: Sender<Vec<u8>>, handler: H) -> Self {
+    pub(in crate::koutput_reads::reads) fn new(sender: Sender<(usize, Vec<u8>)>, handler: H) -> Self {
         Self::with_capacity(BLOCK_SIZE, sender, handler)
     }
 
     pub(in crate::koutput_reads::reads) fn with_capacity(
         capacity: usize,
-        sender: Sender<Vec<u8>>,
+        sender: Sender<(usize, Vec<u8>)>,
         handler: H,
     ) -> Self {
         Self {
             sender,
             buffer: Vec::with_capacity(capacity),
             chunk_bytes: capacity,
+            batch: 0,
+            ordered: false,
             tags: HashMap::with_capacity_and_hasher(2, rustc_hash::FxBuildHasher),
             compressor: None,
             handler,
@@ -59,11 +64,29 @@ where
 
     pub(in crate::koutput_reads::reads) fn set_compressor(
         &mut self,
-        compressor: Option<Compressor>,
+        compressor: Option<ChunkCompressor>,
     ) {
         self.compressor = compressor;
     }
 
+    pub(in crate::koutput_reads::reads) fn set_ordered(&mut self, ordered: bool) {
+        self.ordered = ordered;
+    }
+
+    pub(in crate::koutput_reads::reads) fn start_batch(&mut self, index: usize) {
+        self.batch = index;
+    }
+
+    /// In ordered mode, sends the records of the current batch as a single
+    /// chunk.
+    pub(in crate::koutput_reads::reads) fn finish_batch(&mut self) -> Result<()> {
+        if self.ordered {
+            let pack = std::mem::take(&mut self.buffer);
+            self.send(pack)?;
+        }
+        Ok(())
+    }
+
     pub(in crate::koutput_reads::reads) fn process_record(
         &mut self,
         taxid: &Bytes,
@@ -91,7 +114,7 @@ where
             + 5;
 
         // If not enough buffer space, flush
-        if self.buffer.capacity() - self.buffer.len() < len {
+        if !self.ordered && self.buffer.capacity() - self.buffer.len() < len {
             let mut pack = Vec::with_capacity(self.chunk_bytes);
             std::mem::swap(&mut self.buffer, &mut pack);
             self.send(pack)?;
@@ -136,13 +159,13 @@ where
     }
 
     pub(in crate::koutput_reads::reads) fn send(&mut self, mut pack: Vec<u8>) -> Result<()> {
-        // Compress if gzip file
+        // Compress if gzip or zstd file
         if let Some(compressor) = &mut self.compressor {
-            pack = gzip_pack(&pack, compressor)?
+            pack = compressor.pack(pack)?
         }
 
         // Send compressed or raw bytes to writer
-        self.sender.send(pack).map_err(|e| {
+        self.sender.send((self.batch, pack)).map_err(|e| {
             anyhow!(
                 "(Parser) Failed to send parsed lines to Writer thread: {}",
                 e
@@ -152,6 +175,10 @@ where
     }
 
     pub(in crate::koutput_reads::reads) fn flush_buffer(mut self) -> Result<()> {
+        // Always empty in ordered mode, each batch has been sent already
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
         let pack = std::mem::take(&mut self.buffer);
         self.send(pack)
     }
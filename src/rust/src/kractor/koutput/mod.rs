@@ -2,25 +2,29 @@ use aho_corasick::{AhoCorasick, AhoCorasickKind};
 use anyhow::{anyhow, Context, Result};
 use extendr_api::prelude::*;
 use indicatif::{MultiProgress, ProgressBar, ProgressFinish};
-use rustc_hash::FxHashMap as HashMap;
 use rustc_hash::FxHashSet as HashSet;
 
 use crate::kreport::taxonomy_kreport;
+use crate::selector::{Selection, Selector};
+use crate::taxonomy::{read_taxonomy, TaxonomyTree};
 use crate::utils::*;
 
 mod parse;
 
 pub(crate) fn kractor_koutput(
-    kreport: &str,
+    kreport: Option<&str>,
     koutput: &str,
     ofile: &str,
     taxonomy: Robj,
+    taxdump: Option<&str>,
     ranks: Robj,
     taxa: Robj,
     taxids: Robj,
     exclude: Robj,
     descendants: bool,
     compression_level: i32,
+    output_options: OutputOptions,
+    mmap: Option<bool>,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
@@ -44,89 +48,54 @@ pub(crate) fn kractor_koutput(
         ));
     }
 
-    let kreports = taxonomy_kreport(kreport, taxonomy)?;
+    let taxdump = taxdump.map(read_taxonomy).transpose()?;
+    let (kreports, _) = taxonomy_kreport(kreport, taxonomy, taxdump.as_ref())?;
     let mut targeted_taxids: Vec<&[u8]>;
     if ranks.is_some() || taxa.is_some() || taxids.is_some() {
-        // Parse set of desired taxonomic ranks
-        let mut reports = kreports.iter().collect::<Vec<_>>();
+        // Each of `ranks`, `taxa` and `taxids` is a selection of the taxa
+        // themselves, like the "S", "=*__name" and "=taxid" selectors
+        let mut selections = Vec::with_capacity(3);
         if let Some(ranks) = ranks {
-            let ranks_sets = ranks
-                .iter()
-                .map(|x| x.as_bytes())
-                .collect::<HashSet<&[u8]>>();
-            reports = reports
-                .into_iter()
-                .filter(|kr| ranks_sets.contains(kr.rank.as_slice()))
-                .collect();
+            selections.push(Selection::new(vec![
+                Selector::ranks(&ranks).with_context(|| format!("Failed to parse 'ranks'"))?
+            ])?);
         }
         if let Some(taxa) = taxa {
-            let taxa_sets = taxa
-                .iter()
-                .map(|x| x.as_bytes())
-                .collect::<HashSet<&[u8]>>();
-            reports = reports
-                .into_iter()
-                .filter(|kr| taxa_sets.contains(kr.taxon.as_slice()))
-                .collect();
+            selections.push(Selection::new(
+                taxa.iter().map(|name| Selector::name(name)).collect(),
+            )?);
         }
         if let Some(taxids) = taxids {
-            let taxids_sets = taxids
-                .iter()
-                .map(|x| x.as_bytes())
-                .collect::<HashSet<&[u8]>>();
-            reports = reports
-                .into_iter()
-                .filter(|kr| taxids_sets.contains(kr.taxid.as_slice()))
-                .collect();
+            selections.push(Selection::new(
+                taxids.iter().map(|taxid| Selector::taxid(taxid)).collect(),
+            )?);
         }
-        targeted_taxids = reports.into_iter().map(|kr| kr.taxid.as_slice()).collect();
+        targeted_taxids = kreports
+            .iter()
+            .filter(|kr| selections.iter().all(|selection| selection.matches(kr)))
+            .map(|kr| kr.taxid.as_slice())
+            .collect();
     } else {
         targeted_taxids = kreports.iter().map(|kr| kr.taxid.as_slice()).collect();
     }
 
+    let kreport_tree;
     if descendants {
-        // Build a map: taxid → set of its ancestor taxids
-        let taxid_to_ancestors = kreports
-            .iter()
-            .map(|report| {
-                // Each report's `taxids` field holds the lineage (ancestors) of this taxon
-                let ancestors = report
-                    .taxids
-                    .iter()
-                    .map(|x| x.as_slice())
-                    .collect::<HashSet<&[u8]>>();
-                // Map: current taxid → set of its ancestors
-                (report.taxid.as_slice(), ancestors)
-            })
-            .collect::<HashMap<&[u8], HashSet<&[u8]>>>();
-
-        // For each taxid, find all other taxids that consider it an ancestor
-        // i.e., build a reverse lookup: taxid → set of descendant taxids
-        let taxid_to_descendants = kreports
-            .iter()
-            .map(|report| {
-                let taxid = report.taxid.as_slice();
-                // Iterate over all taxids and check whose ancestor set includes the current taxid
-                let descendants = taxid_to_ancestors
-                    .iter()
-                    .filter_map(|(child, parents)| {
-                        if parents.contains(taxid) {
-                            Some(*child)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<HashSet<&[u8]>>();
-
-                // Map: current taxid → set of its descendant taxids
-                (report.taxid.as_slice(), descendants)
-            })
-            .collect::<HashMap<&[u8], HashSet<&[u8]>>>();
-        targeted_taxids = targeted_taxids
+        // Taxa missing from the kreport are only known from the taxdump
+        let tree = match &taxdump {
+            Some(tree) => tree,
+            None => {
+                kreport_tree = TaxonomyTree::from_kreports(&kreports);
+                &kreport_tree
+            }
+        };
+        targeted_taxids = tree
+            .clade_taxids(
+                targeted_taxids
+                    .into_iter()
+                    .filter_map(|taxid| tree.get(taxid)),
+            )
             .into_iter()
-            .filter_map(|taxid| taxid_to_descendants.get(taxid))
-            .flatten()
-            .copied()
             .collect()
     }
 
@@ -156,15 +125,10 @@ pub(crate) fn kractor_koutput(
                 .build(patterns)
         })
         .transpose()?;
-    let reader_style = progress_reader_style()?;
     let writer_style = progress_writer_style()?;
     let progress = MultiProgress::new();
-    let pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(koutput)?.len() as u64)
-            .with_finish(ProgressFinish::Abandon),
-    );
+    let pb1 = progress.add(input_progress_bar(koutput)?);
     pb1.set_prefix("Reading koutput");
-    pb1.set_style(reader_style);
 
     let pb2 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
     pb2.set_prefix("Writing koutput");
@@ -173,11 +137,13 @@ pub(crate) fn kractor_koutput(
     parse::parse_koutput(
         koutput,
         Some(pb1),
+        mmap,
         ofile,
         Some(pb2),
         include_sets,
         exclude_aho,
         compression_level,
+        output_options,
         batch_size,
         chunk_bytes,
         nqueue,
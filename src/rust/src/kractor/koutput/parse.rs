@@ -1,4 +1,3 @@
-use std::io::{BufWriter, Write};
 use std::path::Path;
 
 use aho_corasick::AhoCorasick;
@@ -6,22 +5,25 @@ use anyhow::{anyhow, Context, Result};
 use bytes::{BufMut, BytesMut};
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
+use libdeflater::CompressionLvl;
 use memchr::memchr;
 use rustc_hash::FxHashSet as HashSet;
 
 use crate::batchsender::BatchSender;
+use crate::mmap_reader::{Boundary, MmapInput};
 use crate::reader::LineReader;
 use crate::utils::*;
 
 pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
     input_path: &P,
     input_bar: Option<ProgressBar>,
+    mmap: Option<bool>,
     output_path: &P,
     output_bar: Option<ProgressBar>,
     include_sets: HashSet<&[u8]>,
     exclude_aho: Option<AhoCorasick>,
     compression_level: i32,
+    output_options: OutputOptions,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
@@ -34,37 +36,39 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
     // Doing this outside avoids redundant validation across parser threads.
     let compression_level = CompressionLvl::new(compression_level)
         .map_err(|e| anyhow!("Invalid 'compression_level': {:?}", e))?;
+    let mapped = MmapInput::open(input, mmap, Boundary::Line, BUFFER_SIZE)?;
 
     std::thread::scope(|scope| -> Result<()> {
         // Two communication pipelines are set up to decouple IO and CPU-intensive work:
         // - reader_tx: transfers raw FASTQ records to parser threads
         // - writer_tx: receives compressed byte chunks from parser threads
-        let (writer_tx, writer_rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = new_channel(nqueue);
-        let (reader_tx, reader_rx): (Sender<Vec<BytesMut>>, Receiver<Vec<BytesMut>>) =
+        let (writer_tx, writer_rx): (Sender<(usize, Chunk)>, Receiver<(usize, Chunk)>) = new_channel(nqueue);
+        let (reader_tx, reader_rx): (Sender<(usize, Vec<BytesMut>)>, Receiver<(usize, Vec<BytesMut>)>) =
             new_channel(nqueue);
 
         // ─── Writer Thread ─────────────────────────────────────
         // A single thread handles file output to ensure atomic write order and leverage buffered IO.
         // This thread consumes compressed chunks, not raw records, for performance.
-        let writer_handle = scope.spawn(move || -> Result<()> {
-            let mut writer = BufWriter::with_capacity(chunk_bytes, new_writer(output, output_bar)?);
+        let writer_handle = scope.spawn(move || -> Result<PendingOutput> {
+            let mut writer = ShardedWriter::new(output, output_bar, chunk_bytes, output_options)?;
+            let mut shards = ShardCounter::new(output_options.sharding);
 
             // Iterate over each received batch of records
-            for chunk in writer_rx {
-                writer
-                    .write_all(&chunk)
+            let mut chunks = OrderedChunks::new(output_options.ordered);
+            for (index, chunk) in writer_rx {
+                chunks
+                    .push(index, chunk, |chunk| writer.write_chunk(shards.next([&chunk]), &chunk.bytes))
                     .with_context(|| format!("(Writer) Failed to write Fastq records to output"))?;
             }
-            writer
-                .flush()
-                .with_context(|| format!("(Writer) Failed to flush writer"))?;
-            Ok(())
+            chunks.finish().with_context(|| format!("(Writer) Failed to order output"))?;
+            let output = writer.finish().with_context(|| format!("(Writer) Failed to finish writer"))?;
+            Ok(output)
         });
 
         // ─── Parser Thread ─────────────────────────────────────
         // Streams Kraken2 output data, filters by ID set
         let mut parser_handles = Vec::with_capacity(threads);
-        let gzip = gz_compressed(output);
+        let compression = output_compression(output, output_options);
         for _ in 0 .. threads {
             let rx = reader_rx.clone();
             let tx = writer_tx.clone();
@@ -72,39 +76,55 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
             let exclude_aho = &exclude_aho;
             let handle = scope.spawn(move || -> Result<()> {
                 let mut pool: Vec<u8> = Vec::with_capacity(chunk_bytes);
-                let mut compressor = Compressor::new(compression_level);
-                while let Ok(lines) = rx.recv() {
+                let mut pool_lines = 0;
+                let mut compressor = ChunkCompressor::new(compression, compression_level)?;
+                while let Ok((index, lines)) = rx.recv() {
                     for line in lines {
                         if kractor_match_aho(&include_sets, &exclude_aho, &line) {
                             // Flush when pool is too full to accept the next record.
                             // This ensures output chunks remain near the target block size.
-                            if pool.capacity() - pool.len() < (line.len() + 1) {
+                            if !output_options.ordered &&
+                                (pool.capacity() - pool.len() < (line.len() + 1) ||
+                                    output_options.chunk_full(pool_lines))
+                            {
                                 let mut pack = Vec::with_capacity(chunk_bytes);
                                 std::mem::swap(&mut pool, &mut pack);
-                                // Compress if gzip file
-                                if gzip {
-                                    pack = gzip_pack(&pack, &mut compressor)?
-                                }
+                                // Compress if gzip or zstd file
+                                let pack = Chunk {
+                                    bytes: compressor.pack(pack)?,
+                                    records: std::mem::take(&mut pool_lines),
+                                };
 
                                 // Send compressed or raw bytes to writer
-                                tx.send(pack).with_context(|| {
+                                tx.send((index, pack)).with_context(|| {
                                     format!("(Parser) Failed to send parsed lines to Writer thread")
                                 })?;
                             }
                             // Append encoded lines to buffer
                             pool.extend_from_slice(&line);
                             pool.put_u8(b'\n');
+                            pool_lines += 1;
                         };
                     }
+                    // In ordered mode, each batch is sent as a single chunk
+                    if output_options.ordered {
+                        let pack = Chunk {
+                            bytes: compressor.pack(std::mem::take(&mut pool))?,
+                            records: std::mem::take(&mut pool_lines),
+                        };
+                        tx.send((index, pack)).with_context(|| {
+                            format!("(Parser) Failed to send parsed lines to Writer thread")
+                        })?;
+                    }
                 }
-                // Flush remaining lines if any
+                // Flush remaining lines if any, the index only matters in
+                // ordered mode, where the pool is always empty here
                 if !pool.is_empty() {
-                    let pack = if gzip {
-                        gzip_pack(&pool, &mut compressor)?
-                    } else {
-                        pool
+                    let pack = Chunk {
+                        bytes: compressor.pack(pool)?,
+                        records: pool_lines,
                     };
-                    tx.send(pack).with_context(|| {
+                    tx.send((0, pack)).with_context(|| {
                         format!("(Parser) Failed to send parsed lines to Writer thread")
                     })?;
                 };
@@ -116,26 +136,59 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
         drop(writer_tx);
 
         // ─── reader Thread ─────────────────────────────────────
-        let reader_handle = scope.spawn(move || -> Result<()> {
-            let mut reader =
-                LineReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, input_bar)?);
-            let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
-            while let Some(record) = reader
-                .read_line()
-                .with_context(|| format!("(Reader) Failed to read line"))?
-            {
-                reader_tx
-                    .send(record)
-                    .with_context(|| format!("(Reader) Failed to send lines to Parser thread"))?;
+        let mut reader_handles = Vec::with_capacity(threads);
+        if let Some(mapped) = &mapped {
+            // A memory-mapped input is read by several threads, each sending
+            // the lines of a whole range as one batch
+            for _ in 0 .. threads {
+                let reader_tx = reader_tx.clone();
+                let input_bar = input_bar.clone();
+                reader_handles.push(scope.spawn(move || -> Result<()> {
+                    while let Some((index, range)) = mapped.next_range() {
+                        let nbytes = range.len() as u64;
+                        let mut reader = mapped.line_reader(range);
+                        let mut lines = Vec::new();
+                        while let Some(line) = reader
+                            .read_line()
+                            .with_context(|| format!("(Reader) Failed to read line"))?
+                        {
+                            lines.push(line);
+                        }
+                        reader_tx.send((index, lines)).with_context(|| {
+                            format!("(Reader) Failed to send lines to Parser thread")
+                        })?;
+                        if let Some(bar) = &input_bar {
+                            bar.inc(nbytes);
+                        }
+                    }
+                    Ok(())
+                }));
             }
-            reader_tx
-                .flush()
-                .with_context(|| format!("(Reader) Failed to flush lines to Parser thread"))?;
-            Ok(())
-        });
+            drop(reader_tx);
+        } else {
+            reader_handles.push(scope.spawn(move || -> Result<()> {
+                let mut reader = LineReader::with_capacity(
+                    BUFFER_SIZE,
+                    new_reader(input, BUFFER_SIZE, input_bar, decode_threads(threads, 1))?,
+                );
+                let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
+                while let Some(record) = reader
+                    .read_line()
+                    .with_context(|| format!("(Reader) Failed to read line"))?
+                {
+                    reader_tx
+                        .send(record)
+                        .with_context(|| format!("(Reader) Failed to send lines to Parser thread"))?;
+                }
+                reader_tx
+                    .flush()
+                    .with_context(|| format!("(Reader) Failed to flush lines to Parser thread"))?;
+                Ok(())
+            }));
+        }
 
         // ─── Join Threads and Propagate Errors ────────────────
-        writer_handle
+        let output = writer_handle
             .join()
             .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))??;
         for handler in parser_handles {
@@ -143,10 +196,12 @@ pub(super) fn parse_koutput<P: AsRef<Path> + ?Sized>(
                 .join()
                 .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??;
         }
-        reader_handle
-            .join()
-            .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
-        Ok(())
+        for handler in reader_handles {
+            handler
+                .join()
+                .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
+        }
+        commit_outputs([output])
     })
 }
 
@@ -201,6 +256,7 @@ fn kractor_match_aho(
 mod tests {
     use std::collections::HashSet;
     use std::fs;
+    use std::io::Read;
 
     use aho_corasick::AhoCorasick;
     use tempfile::tempdir;
@@ -228,11 +284,13 @@ C\tread2\t456\t456\tFungi
         parse_koutput(
             &input_path,
             None,
+            None,
             &output_path,
             None,
             include,
             exclude,
             3,          // compression level
+            OutputOptions::default(),
             10,         // batch size
             512 * 1024, // chunk_bytes
             Some(2),    // nqueue
@@ -246,6 +304,90 @@ C\tread2\t456\t456\tFungi
         Ok(())
     }
 
+    #[test]
+    fn test_parse_koutput_bgzf_index() -> Result<()> {
+        let temp = tempdir()?;
+        let input_path = temp.path().join("kout.txt");
+        let output_path = temp.path().join("out.gz");
+        let sample = "C\tread1\t123\t123\tBacteria\n".repeat(10_000);
+        fs::write(&input_path, &sample)?;
+
+        let mut include = HashSet::default();
+        include.insert(b"123".as_ref());
+        parse_koutput(
+            &input_path,
+            None,
+            None,
+            &output_path,
+            None,
+            include,
+            None,
+            3, // compression level
+            OutputOptions {
+                bgzf: true,
+                bgzf_index: true,
+                ..Default::default()
+            },
+            1000,      // batch size
+            64 * 1024, // chunk_bytes
+            Some(2),   // nqueue
+            2,         // threads
+        )?;
+
+        let out_content = fs::read(&output_path)?;
+        assert!(out_content.ends_with(&crate::bgzf::BGZF_EOF));
+        let mut decoded = String::new();
+        flate2::read::MultiGzDecoder::new(&out_content[..]).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, sample);
+        // 290 KB of records need at least 5 blocks, the first one is not indexed
+        let gzi = fs::read(temp.path().join("out.gz.gzi"))?;
+        let entries = u64::from_le_bytes(gzi[.. 8].try_into()?);
+        assert!(entries >= 4);
+        assert_eq!(gzi.len() as u64, 8 + entries * 16);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_koutput_ordered() -> Result<()> {
+        let temp = tempdir()?;
+        let input_path = temp.path().join("kout.txt");
+        let output_path = temp.path().join("out.txt");
+        let sample = (0 .. 10_000)
+            .map(|i| format!("C\tread{}\t{}\t{}\tBacteria\n", i, 123 + i % 3, 123 + i % 3))
+            .collect::<String>();
+        fs::write(&input_path, &sample)?;
+        let expected = sample
+            .lines()
+            .filter(|line| line.contains("\t123\t"))
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+
+        let mut include = HashSet::default();
+        include.insert(b"123".as_ref());
+        for mmap in [Some(false), Some(true)] {
+            parse_koutput(
+                &input_path,
+                None,
+                mmap,
+                &output_path,
+                None,
+                include.clone(),
+                None,
+                3, // compression level
+                OutputOptions {
+                    ordered: true,
+                    ..Default::default()
+                },
+                7,         // batch size
+                64 * 1024, // chunk_bytes
+                Some(2),   // nqueue
+                4,         // threads
+            )?;
+            assert_eq!(fs::read_to_string(&output_path)?, expected);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_kractor_match_aho() {
         let mut include = HashSet::default();
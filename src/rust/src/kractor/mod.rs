@@ -2,14 +2,18 @@
 use anyhow::Context;
 use extendr_api::prelude::*;
 
+use crate::quarantine::bad_record_counts;
+use crate::utils::{OutputOptions, Sharding};
+
 mod koutput;
 pub(crate) mod reads;
 
 #[extendr]
 fn kractor_koutput(
-    kreport: &str,
+    kreport: Option<&str>,
     koutput: &str,
     taxonomy: Robj,
+    taxdump: Option<&str>,
     ranks: Robj,
     taxa: Robj,
     taxids: Robj,
@@ -17,22 +21,41 @@ fn kractor_koutput(
     descendants: bool,
     ofile: &str,
     compression_level: i32,
+    bgzf: bool,
+    bgzf_index: bool,
+    ordered: bool,
+    shards: Option<usize>,
+    shard_records: Option<usize>,
+    shard_bytes: Option<usize>,
+    mmap: Option<bool>,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
     threads: usize,
 ) -> std::result::Result<(), String> {
+    let sharding =
+        Sharding::parse(shards, shard_records, shard_bytes).map_err(|e| format!("{:?}", e))?;
+    let output_options = OutputOptions {
+        bgzf,
+        bgzf_index,
+        ordered,
+        sharding,
+    };
+    let (batch_size, chunk_bytes) = output_options.chunk_sizes(batch_size, chunk_bytes);
     koutput::kractor_koutput(
         kreport,
         koutput,
         ofile,
         taxonomy,
+        taxdump,
         ranks,
         taxa,
         taxids,
         exclude,
         descendants,
         compression_level,
+        output_options,
+        mmap,
         batch_size,
         chunk_bytes,
         nqueue,
@@ -44,37 +67,75 @@ fn kractor_koutput(
 #[extendr]
 fn kractor_reads(
     koutput: &str,
-    fq1: &str,
+    fq1: Vec<String>,
     ofile1: Option<&str>,
-    fq2: Option<&str>,
+    fq2: Option<Vec<String>>,
     ofile2: Option<&str>,
+    multiline: bool,
+    interleaved: bool,
+    id_normalize: Option<&str>,
+    validate: bool,
+    lenient: bool,
+    quarantine: Option<&str>,
+    max_bad: Option<usize>,
+    subsample: Option<f64>,
+    seed: u64,
     compression_level: i32,
+    bgzf: bool,
+    bgzf_index: bool,
+    ordered: bool,
+    shards: Option<usize>,
+    shard_records: Option<usize>,
+    shard_bytes: Option<usize>,
+    mmap: Option<bool>,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
     threads: usize,
-) -> std::result::Result<(), String> {
-    reads::kractor_reads(
+) -> std::result::Result<List, String> {
+    let sharding =
+        Sharding::parse(shards, shard_records, shard_bytes).map_err(|e| format!("{:?}", e))?;
+    let output_options = OutputOptions {
+        bgzf,
+        bgzf_index,
+        ordered,
+        sharding,
+    };
+    let (batch_size, chunk_bytes) = output_options.chunk_sizes(batch_size, chunk_bytes);
+    let counts = reads::kractor_reads(
         koutput,
-        fq1,
+        &fq1,
         ofile1,
-        fq2,
+        fq2.as_deref(),
         ofile2,
+        multiline,
+        interleaved,
+        id_normalize,
+        validate,
+        lenient,
+        quarantine,
+        max_bad,
+        subsample,
+        seed,
         compression_level,
+        output_options,
+        mmap,
         batch_size,
         chunk_bytes,
         nqueue,
         threads,
     )
-    .map_err(|e| format!("{}", e))
+    .map_err(|e| format!("{}", e))?;
+    bad_record_counts(counts)
 }
 
 #[extendr]
 #[cfg(feature = "bench")]
 fn pprof_kractor_koutput(
-    kreport: &str,
+    kreport: Option<&str>,
     koutput: &str,
     taxonomy: Robj,
+    taxdump: Option<&str>,
     ranks: Robj,
     taxa: Robj,
     taxids: Robj,
@@ -82,6 +143,13 @@ fn pprof_kractor_koutput(
     descendants: bool,
     ofile: &str,
     compression_level: i32,
+    bgzf: bool,
+    bgzf_index: bool,
+    ordered: bool,
+    shards: Option<usize>,
+    shard_records: Option<usize>,
+    shard_bytes: Option<usize>,
+    mmap: Option<bool>,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
@@ -97,6 +165,7 @@ fn pprof_kractor_koutput(
         kreport,
         koutput,
         taxonomy,
+        taxdump,
         ranks,
         taxa,
         taxids,
@@ -104,6 +173,13 @@ fn pprof_kractor_koutput(
         descendants,
         ofile,
         compression_level,
+        bgzf,
+        bgzf_index,
+        ordered,
+        shards,
+        shard_records,
+        shard_bytes,
+        mmap,
         batch_size,
         chunk_bytes,
         nqueue,
@@ -128,17 +204,33 @@ fn pprof_kractor_koutput(
 #[cfg(feature = "bench")]
 fn pprof_kractor_reads(
     koutput: &str,
-    fq1: &str,
+    fq1: Vec<String>,
     ofile1: Option<&str>,
-    fq2: Option<&str>,
+    fq2: Option<Vec<String>>,
     ofile2: Option<&str>,
+    multiline: bool,
+    interleaved: bool,
+    id_normalize: Option<&str>,
+    validate: bool,
+    lenient: bool,
+    quarantine: Option<&str>,
+    max_bad: Option<usize>,
+    subsample: Option<f64>,
+    seed: u64,
     compression_level: i32,
+    bgzf: bool,
+    bgzf_index: bool,
+    ordered: bool,
+    shards: Option<usize>,
+    shard_records: Option<usize>,
+    shard_bytes: Option<usize>,
+    mmap: Option<bool>,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
     threads: usize,
     pprof_file: &str,
-) -> std::result::Result<(), String> {
+) -> std::result::Result<List, String> {
     let guard = pprof::ProfilerGuardBuilder::default()
         .frequency(2000)
         .build()
@@ -150,7 +242,23 @@ fn pprof_kractor_reads(
         ofile1,
         fq2,
         ofile2,
+        multiline,
+        interleaved,
+        id_normalize,
+        validate,
+        lenient,
+        quarantine,
+        max_bad,
+        subsample,
+        seed,
         compression_level,
+        bgzf,
+        bgzf_index,
+        ordered,
+        shards,
+        shard_records,
+        shard_bytes,
+        mmap,
         batch_size,
         chunk_bytes,
         nqueue,
@@ -1,7 +1,6 @@
 use std::fmt::Display;
-use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
 use rustc_hash::FxHashSet as HashSet;
@@ -11,73 +10,133 @@ mod single;
 
 use indicatif::{MultiProgress, ProgressBar, ProgressFinish};
 
+use crate::fastq_reader::ParseOptions;
+use crate::read_id::ReadIdNormalizer;
+use crate::subsample::Subsample;
+use crate::paired_reader::PairedInput;
+use crate::quarantine::{BadRecordCounts, Quarantine};
 use crate::utils::*;
 
 pub(super) fn kractor_reads(
     koutput: &str,
-    fq1: &str,
+    fq1: &[String],
     ofile1: Option<&str>,
-    fq2: Option<&str>,
+    fq2: Option<&[String]>,
     ofile2: Option<&str>,
+    multiline: bool,
+    interleaved: bool,
+    id_normalize: Option<&str>,
+    validate: bool,
+    lenient: bool,
+    quarantine_file: Option<&str>,
+    max_bad: Option<usize>,
+    subsample: Option<f64>,
+    seed: u64,
     compression_level: i32,
+    output_options: OutputOptions,
+    mmap: Option<bool>,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
     threads: usize,
-) -> Result<()> {
-    let ids = read_sequence_id_from_koutput(koutput, 126 * 1024)
+) -> Result<BadRecordCounts> {
+    check_stdio(
+        std::iter::once(koutput)
+            .chain(fq1.iter().chain(fq2.into_iter().flatten()).map(|fq| fq.as_str())),
+    )?;
+    check_mate_files(fq1, fq2)?;
+    check_stdio([ofile1, ofile2].into_iter().flatten())?;
+    let quarantine = lenient
+        .then(|| Quarantine::new(quarantine_file, max_bad))
+        .transpose()?;
+    let id_normalizer = ReadIdNormalizer::parse(id_normalize)?;
+    let subsample = Subsample::parse(subsample, seed)?;
+    let ids = read_sequence_id_from_koutput(koutput, 126 * 1024, threads)
         .map_err(|e| anyhow!("Failed to read sequence IDs: {}", e))?;
     let id_sets = ids
         .iter()
-        .map(|id| id.as_slice())
+        .map(|id| id_normalizer.normalize(id.as_slice()))
         .collect::<HashSet<&[u8]>>();
     let threads = threads.max(1); // always use at least one thread
-    if let Some(fq2) = fq2 {
+    // Wrapped records cannot be found from the middle of a file, mates must
+    // be read in step, and records must be subsampled in input order
+    if mmap == Some(true) &&
+        (multiline || fq2.is_some() || interleaved || fq1.len() > 1 || subsample.is_some())
+    {
+        return Err(anyhow!(
+            "'mmap' is only supported for a single file of single-end reads without 'multiline' or 'subsample'"
+        ));
+    }
+    let fq1 = fq1.iter().map(PathBuf::from).collect::<Vec<_>>();
+    let fq2 = fq2.map(|fq2| fq2.iter().map(PathBuf::from).collect::<Vec<_>>());
+    let parse_options = ParseOptions {
+        multiline,
+        id_normalizer,
+        decode_threads: decode_threads(threads, if fq2.is_some() { 2 } else { 1 }),
+        validate,
+        quarantine: quarantine.as_ref(),
+    };
+    if fq2.is_some() || interleaved {
         kractor_reads_paired(
             &id_sets,
-            fq1,
+            &fq1,
             ofile1,
-            fq2,
+            fq2.as_deref(),
             ofile2,
+            interleaved,
+            parse_options,
+            subsample,
             batch_size,
             chunk_bytes,
             compression_level,
+            output_options,
             nqueue,
             threads,
-        )
+        )?;
     } else {
         kractor_reads_single(
             &id_sets,
-            fq1,
+            &fq1,
             ofile1,
+            parse_options,
+            subsample,
+            if multiline || subsample.is_some() { Some(false) } else { mmap },
             batch_size,
             chunk_bytes,
             compression_level,
+            output_options,
             nqueue,
             threads,
-        )
+        )?;
+    }
+    match quarantine {
+        Some(quarantine) => {
+            quarantine.flush()?;
+            Ok(quarantine.counts())
+        }
+        None => Ok(Default::default()),
     }
 }
 
 fn kractor_reads_single(
     id_sets: &HashSet<&[u8]>,
-    fq1: &str,
+    fq1: &[PathBuf],
     ofile1: Option<&str>,
+    parse_options: ParseOptions,
+    subsample: Option<Subsample>,
+    mmap: Option<bool>,
     batch_size: usize,
     chunk_bytes: usize,
     compression_level: i32,
+    output_options: OutputOptions,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
     let ofile1 = ofile1.ok_or_else(|| anyhow!("No output file specified."))?;
-    let reader_style = progress_reader_style()?;
     let writer_style = progress_writer_style()?;
     let progress = MultiProgress::new();
-    let pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq1)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
+    let pb1 = progress.add(inputs_progress_bar(fq1)?);
     pb1.set_prefix("Reading fastq");
-    pb1.set_style(reader_style);
 
     let pb2 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
     pb2.set_prefix("Writing fastq");
@@ -85,11 +144,15 @@ fn kractor_reads_single(
 
     single::parse_single(
         id_sets,
-        &fq1,
+        fq1,
         Some(pb1),
+        parse_options,
+        subsample,
+        mmap,
         &ofile1,
         Some(pb2),
         compression_level,
+        output_options,
         batch_size,
         chunk_bytes,
         nqueue,
@@ -99,42 +162,59 @@ fn kractor_reads_single(
 
 fn kractor_reads_paired(
     id_sets: &HashSet<&[u8]>,
-    fq1: &str,
+    fq1: &[PathBuf],
     ofile1: Option<&str>,
-    fq2: &str,
+    fq2: Option<&[PathBuf]>,
     ofile2: Option<&str>,
+    interleaved: bool,
+    parse_options: ParseOptions,
+    subsample: Option<Subsample>,
     batch_size: usize,
     chunk_bytes: usize,
     compression_level: i32,
+    output_options: OutputOptions,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
     if ofile1.is_none() && ofile2.is_none() {
         return Err(anyhow!("No output file specified."));
     }
-
-    let reader_style = progress_reader_style()?;
+    // With interleaved mode, both mates are written into `ofile1` when no
+    // `ofile2` is given
+    let interleaved_output = interleaved && ofile2.is_none();
     let writer_style = progress_writer_style()?;
     let progress = MultiProgress::new();
-    let pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq1)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
-    pb1.set_prefix("Reading fq1");
-    pb1.set_style(reader_style.clone());
+    let pb1 = progress.add(inputs_progress_bar(fq1)?);
     let pb2 = if let Some(_) = ofile1 {
         let pb2 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
-        pb2.set_prefix("Writing fq1");
+        if interleaved_output {
+            pb2.set_prefix("Writing fastq");
+        } else {
+            pb2.set_prefix("Writing fq1");
+        }
         pb2.set_style(writer_style.clone());
         Some(pb2)
     } else {
         None
     };
 
-    let pb3 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq2)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
-    pb3.set_prefix("Reading fq2");
-    pb3.set_style(reader_style);
+    let input = if let Some(fq2) = fq2 {
+        pb1.set_prefix("Reading fq1");
+        let pb3 = progress.add(inputs_progress_bar(fq2)?);
+        pb3.set_prefix("Reading fq2");
+        PairedInput::Split {
+            input1: fq1,
+            input1_bar: Some(pb1),
+            input2: fq2,
+            input2_bar: Some(pb3),
+        }
+    } else {
+        pb1.set_prefix("Reading fastq");
+        PairedInput::Interleaved {
+            input: fq1,
+            input_bar: Some(pb1),
+        }
+    };
     let pb4 = if let Some(_) = ofile2 {
         let pb4 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
         pb4.set_prefix("Writing fq2");
@@ -145,15 +225,16 @@ fn kractor_reads_paired(
     };
     paired::parse_paired(
         id_sets,
-        fq1,
-        Some(pb1),
-        fq2,
-        Some(pb3),
+        input,
+        parse_options,
+        subsample,
         ofile1,
         pb2,
         ofile2,
         pb4,
+        interleaved_output,
         compression_level,
+        output_options,
         batch_size,
         chunk_bytes,
         nqueue,
@@ -164,11 +245,12 @@ fn kractor_reads_paired(
 fn read_sequence_id_from_koutput<P>(
     file: P,
     buffersize: usize,
+    threads: usize,
 ) -> std::result::Result<Vec<Vec<u8>>, String>
 where
     P: AsRef<Path> + Display,
 {
-    let opened = File::open(file).map_err(|e| format!("Open file failed: {}", e))?;
+    let opened = new_reader(&file, buffersize, None, threads).map_err(|e| format!("{:?}", e))?;
     let buffer = BufReader::with_capacity(buffersize, opened);
     let id_sets = buffer
         .lines()
@@ -1,63 +1,74 @@
-use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
+use libdeflater::CompressionLvl;
 use rustc_hash::FxHashSet as HashSet;
 
+use crate::bam_reader::SequenceReader;
 use crate::batchsender::BatchSender;
+use crate::mmap_reader::{Boundary, MmapInput};
 use crate::fastq_reader::*;
 use crate::fastq_record::FastqRecord;
+use crate::subsample::{Subsample, Subsampler};
 use crate::utils::*;
 
 pub(super) fn parse_single<P: AsRef<Path> + ?Sized>(
     id_sets: &HashSet<&[u8]>,
-    input_path: &P,
+    input: &[PathBuf],
     input_bar: Option<ProgressBar>,
+    parse_options: ParseOptions,
+    subsample: Option<Subsample>,
+    mmap: Option<bool>,
     output_path: &P,
     output_bar: Option<ProgressBar>,
     compression_level: i32,
+    output_options: OutputOptions,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
-    let input: &Path = input_path.as_ref();
     let output: &Path = output_path.as_ref();
 
     // Ensure compression level is validated and converted before entering thread scope.
     // Doing this outside avoids redundant validation across parser threads.
     let compression_level = CompressionLvl::new(compression_level)
         .map_err(|e| anyhow!("Invalid 'compression_level': {:?}", e))?;
+    // Several files are read one after the other by a single thread
+    let mapped = match input {
+        [input] => MmapInput::open(input, mmap, Boundary::Record, BUFFER_SIZE)?,
+        _ => None,
+    };
     std::thread::scope(|scope| -> Result<()> {
         // Two communication pipelines are set up to decouple IO and CPU-intensive work:
         // - reader_tx: transfers raw FASTQ records to parser threads
         // - writer_tx: receives compressed byte chunks from parser threads
-        let (writer_tx, writer_rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = new_channel(nqueue);
+        let (writer_tx, writer_rx): (Sender<(usize, Chunk)>, Receiver<(usize, Chunk)>) = new_channel(nqueue);
         let (reader_tx, reader_rx): (
-            Sender<Vec<FastqRecord<Bytes>>>,
-            Receiver<Vec<FastqRecord<Bytes>>>,
+            Sender<(usize, Vec<FastqRecord<Bytes>>)>,
+            Receiver<(usize, Vec<FastqRecord<Bytes>>)>,
         ) = new_channel(nqueue);
 
         // ─── Writer Thread ─────────────────────────────────────
         // A single thread handles file output to ensure atomic write order and leverage buffered IO.
         // This thread consumes compressed chunks, not raw records, for performance.
-        let writer_handle = scope.spawn(move || -> Result<()> {
-            let mut writer = BufWriter::with_capacity(chunk_bytes, new_writer(output, output_bar)?);
+        let writer_handle = scope.spawn(move || -> Result<PendingOutput> {
+            let mut writer = ShardedWriter::new(output, output_bar, chunk_bytes, output_options)?;
+            let mut shards = ShardCounter::new(output_options.sharding);
 
             // Iterate over each received batch of records
-            for chunk in writer_rx {
-                writer
-                    .write_all(&chunk)
+            let mut chunks = OrderedChunks::new(output_options.ordered);
+            for (index, chunk) in writer_rx {
+                chunks
+                    .push(index, chunk, |chunk| writer.write_chunk(shards.next([&chunk]), &chunk.bytes))
                     .with_context(|| format!("(Writer) Failed to write FastqRecord to output"))?;
             }
-            writer
-                .flush()
-                .with_context(|| format!("(Writer) Failed to flush writer"))?;
-            Ok(())
+            chunks.finish().with_context(|| format!("(Writer) Failed to order output"))?;
+            let output = writer.finish().with_context(|| format!("(Writer) Failed to finish writer"))?;
+            Ok(output)
         });
 
         // ─── Parser Thread ─────────────────────────────────────
@@ -65,29 +76,34 @@ pub(super) fn parse_single<P: AsRef<Path> + ?Sized>(
         // Each thread transforms records and buffers them into a local pool,
         // which is periodically flushed into the writer pipeline.
         let mut parser_handles = Vec::with_capacity(threads);
-        let gzip = gz_compressed(output);
+        let compression = output_compression(output, output_options);
         for _ in 0 .. threads {
             let rx = reader_rx.clone();
             let tx = writer_tx.clone();
             let handle = scope.spawn(move || -> Result<()> {
                 // Temporary buffer for current output chunk
                 let mut records_pool: Vec<u8> = Vec::with_capacity(chunk_bytes);
-                let mut compressor = Compressor::new(compression_level);
-                while let Ok(records) = rx.recv() {
+                let mut pool_records = 0;
+                let mut compressor = ChunkCompressor::new(compression, compression_level)?;
+                while let Ok((index, records)) = rx.recv() {
                     for record in records {
-                        if id_sets.contains(record.id.as_ref()) {
+                        if id_sets.contains(parse_options.id_normalizer.normalize(&record.id)) {
                             // Flush when pool is too full to accept the next record.
                             // This ensures output chunks remain near the target block size.
-                            if records_pool.capacity() - records_pool.len() < record.bytes_size() {
+                            if !output_options.ordered &&
+                                (records_pool.capacity() - records_pool.len() < record.bytes_size() ||
+                                    output_options.chunk_full(pool_records))
+                            {
                                 let mut pack = Vec::with_capacity(chunk_bytes);
                                 std::mem::swap(&mut records_pool, &mut pack);
-                                // Compress if gzip file
-                                if gzip {
-                                    pack = gzip_pack(&pack, &mut compressor)?
-                                }
+                                // Compress if gzip or zstd file
+                                let pack = Chunk {
+                                    bytes: compressor.pack(pack)?,
+                                    records: std::mem::take(&mut pool_records),
+                                };
 
                                 // Send compressed or raw bytes to writer
-                                tx.send(pack).with_context(|| {
+                                tx.send((index, pack)).with_context(|| {
                                     format!(
                                         "(Parser) Failed to send parsed record to Writer thread"
                                     )
@@ -96,17 +112,28 @@ pub(super) fn parse_single<P: AsRef<Path> + ?Sized>(
                         }
                         // Append encoded record to buffer
                         record.extend(&mut records_pool);
+                        pool_records += 1;
+                    }
+                    // In ordered mode, each batch is sent as a single chunk
+                    if output_options.ordered {
+                        let pack = Chunk {
+                            bytes: compressor.pack(std::mem::take(&mut records_pool))?,
+                            records: std::mem::take(&mut pool_records),
+                        };
+                        tx.send((index, pack)).with_context(|| {
+                            format!("(Parser) Failed to send parsed record to Writer thread")
+                        })?;
                     }
                 }
 
-                // Flush remaining records if any
+                // Flush remaining records if any, the index only matters in
+                // ordered mode, where the pool is always empty here
                 if !records_pool.is_empty() {
-                    let pack = if gzip {
-                        gzip_pack(&records_pool, &mut compressor)?
-                    } else {
-                        records_pool
+                    let pack = Chunk {
+                        bytes: compressor.pack(records_pool)?,
+                        records: pool_records,
                     };
-                    tx.send(pack).with_context(|| {
+                    tx.send((0, pack)).with_context(|| {
                         format!("(Parser) Failed to send parsed record to Writer thread")
                     })?;
                 }
@@ -118,26 +145,64 @@ pub(super) fn parse_single<P: AsRef<Path> + ?Sized>(
         drop(writer_tx);
 
         // ─── reader Thread ─────────────────────────────────────
-        let reader_handle = scope.spawn(move || -> Result<()> {
-            let mut reader =
-                FastqReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, input_bar)?);
-            let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
-            while let Some(record) = reader
-                .read_record()
-                .with_context(|| format!("(Reader) Failed to read FASTQ record"))?
-            {
-                reader_tx.send(record).with_context(|| {
-                    format!("(Reader) Failed to send FASTQ records to Parser thread")
-                })?;
+        let mut reader_handles = Vec::with_capacity(threads);
+        if let Some(mapped) = &mapped {
+            // A memory-mapped input is read by several threads, each sending
+            // the records of a whole range as one batch
+            for _ in 0 .. threads {
+                let reader_tx = reader_tx.clone();
+                let input_bar = input_bar.clone();
+                reader_handles.push(scope.spawn(move || -> Result<()> {
+                    while let Some((index, range)) = mapped.next_range() {
+                        let nbytes = range.len() as u64;
+                        let mut reader = mapped.fastq_reader(range, parse_options);
+                        let mut records = Vec::new();
+                        while let Some(record) = reader
+                            .read_record()
+                            .with_context(|| format!("(Reader) Failed to read FASTQ record"))?
+                        {
+                            records.push(record);
+                        }
+                        reader_tx.send((index, records)).with_context(|| {
+                            format!("(Reader) Failed to send FASTQ records to Parser thread")
+                        })?;
+                        if let Some(bar) = &input_bar {
+                            bar.inc(nbytes);
+                        }
+                    }
+                    Ok(())
+                }));
             }
-            reader_tx.flush().with_context(|| {
-                format!("(Reader) Failed to flush FASTQ records to Parser thread")
-            })?;
-            Ok(())
-        });
+            drop(reader_tx);
+        } else {
+            reader_handles.push(scope.spawn(move || -> Result<()> {
+                let mut reader = SequenceReader::open(input, input_bar, parse_options)?;
+                let mut sampler = Subsampler::new(subsample);
+                let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
+                while let Some(record) = reader
+                    .read_record()
+                    .with_context(|| format!("(Reader) Failed to read FASTQ record"))?
+                {
+                    if let Some(record) = sampler.offer(record) {
+                        reader_tx.send(record).with_context(|| {
+                            format!("(Reader) Failed to send FASTQ records to Parser thread")
+                        })?;
+                    }
+                }
+                for record in sampler.finish() {
+                    reader_tx.send(record).with_context(|| {
+                        format!("(Reader) Failed to send FASTQ records to Parser thread")
+                    })?;
+                }
+                reader_tx.flush().with_context(|| {
+                    format!("(Reader) Failed to flush FASTQ records to Parser thread")
+                })?;
+                Ok(())
+            }));
+        }
 
         // ─── Join Threads and Propagate Errors ────────────────
-        writer_handle
+        let output = writer_handle
             .join()
             .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))??;
         for handler in parser_handles {
@@ -145,9 +210,11 @@ pub(super) fn parse_single<P: AsRef<Path> + ?Sized>(
                 .join()
                 .map_err(|e| anyhow!("(Parser) thread panicked: {:?}", e))??;
         }
-        reader_handle
-            .join()
-            .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
-        Ok(())
+        for handler in reader_handles {
+            handler
+                .join()
+                .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
+        }
+        commit_outputs([output])
     })
 }
@@ -3,7 +3,6 @@ use std::path::Path;
 use anyhow::{anyhow, Context, Result};
 use bytes::{Bytes, BytesMut};
 use crossbeam_channel::{Receiver, Sender};
-use indicatif::{ProgressBar, ProgressFinish};
 use memchr::memchr;
 use memchr::memmem::Finder;
 use rustc_hash::FxHashMap as HashMap;
@@ -119,10 +118,8 @@ pub(super) fn count_kmers_and_reads<'taxid, P: AsRef<Path> + ?Sized>(
     nqueue: Option<usize>,
 ) -> Result<HashMap<Bytes, HashMap<&'taxid [u8], ReadsAndKmer>>> {
     let input: &Path = koutreads.as_ref();
-    let style = progress_reader_style()?;
-    let pb = ProgressBar::new(input.metadata()?.len() as u64).with_finish(ProgressFinish::Abandon);
+    let pb = input_progress_bar(input)?;
     pb.set_prefix("Parsing Koutreads");
-    pb.set_style(style);
 
     // This function processes a Koutreads-format file and collects k-mer counts
     // per (barcode, taxon). Each taxon aggregates k-mers from its descendant taxa.
@@ -130,7 +127,7 @@ pub(super) fn count_kmers_and_reads<'taxid, P: AsRef<Path> + ?Sized>(
     std::thread::scope(
         |scope| -> Result<HashMap<Bytes, HashMap<&[u8], ReadsAndKmer>>> {
             // Shared queue between reader and parser threads
-            let (reader_tx, reader_rx): (Sender<Vec<BytesMut>>, Receiver<Vec<BytesMut>>) =
+            let (reader_tx, reader_rx): (Sender<(usize, Vec<BytesMut>)>, Receiver<(usize, Vec<BytesMut>)>) =
                 new_channel(nqueue);
 
             // ─── Parser Thread ─────────────────────────────────────
@@ -143,7 +140,7 @@ pub(super) fn count_kmers_and_reads<'taxid, P: AsRef<Path> + ?Sized>(
                     let umi_finder = umi_tag.as_ref().map(|tag| Finder::new(tag));
                     let barcode_finder = barcode_tag.as_ref().map(|tag| Finder::new(tag));
 
-                    while let Ok(lines) = reader_rx.recv() {
+                    while let Ok((_, lines)) = reader_rx.recv() {
                         for line in lines {
                             let line = line.freeze();
                             let fields: Vec<&[u8]> = line.split(|b| *b == b'\t').collect();
@@ -246,7 +243,7 @@ pub(super) fn count_kmers_and_reads<'taxid, P: AsRef<Path> + ?Sized>(
             let reader_handle = scope.spawn(move || -> Result<()> {
                 let mut reader = LineReader::with_capacity(
                     BUFFER_SIZE,
-                    new_reader(input, BUFFER_SIZE, Some(pb))?,
+                    new_reader(input, BUFFER_SIZE, Some(pb), 1)?,
                 );
                 let mut reader_tx: BatchSender<BytesMut> =
                     BatchSender::with_capacity(batch_size, reader_tx);
@@ -1,5 +1,5 @@
 use anyhow::anyhow;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bytes::Bytes;
 use extendr_api::prelude::*;
 use rustc_hash::FxHashMap as HashMap;
@@ -7,16 +7,18 @@ use rustc_hash::FxHashSet as HashSet;
 
 mod count;
 
-use crate::kreport::taxonomy_kreport;
+use crate::kreport::{select_tree_taxa, taxonomy_kreport, tree_kreport, Kreport, KreportSummary};
+use crate::taxonomy::{rank_order_key, read_taxonomy, TaxonomyTree};
 use crate::utils::*;
 
 #[extendr]
 fn krcount(
     koutreads: &str,
-    kreport: &str,
+    kreport: Option<&str>,
     umi_tag: Option<&str>,
     barcode_tag: Option<&str>,
     taxonomy: Robj,
+    taxdump: Option<&str>,
     batch_size: usize,
     nqueue: Option<usize>,
 ) -> std::result::Result<List, String> {
@@ -26,6 +28,7 @@ fn krcount(
         umi_tag,
         barcode_tag,
         taxonomy,
+        taxdump,
         batch_size,
         nqueue,
     )
@@ -34,30 +37,47 @@ fn krcount(
 
 fn krcount_internal(
     koutreads: &str,
-    kreport: &str,
+    kreport: Option<&str>,
     umi_tag: Option<&str>,
     barcode_tag: Option<&str>,
     taxonomy: Robj,
+    taxdump: Option<&str>,
     batch_size: usize,
     nqueue: Option<usize>,
 ) -> Result<List> {
-    let kreports = taxonomy_kreport(kreport, taxonomy)?;
+    let taxdump = taxdump.map(read_taxonomy).transpose()?;
+    // Without a kreport, the taxa are selected in the taxonomy, and their rows
+    // are only built once counted
+    let (mut kreports, summary, selected) = match (kreport, &taxdump) {
+        (None, Some(tree)) => {
+            let taxonomy = robj_to_option_str(&taxonomy)
+                .with_context(|| format!("Failed to parse 'taxonomy'"))?;
+            let selected = select_tree_taxa(tree, taxonomy.as_deref())?;
+            (Vec::new(), KreportSummary::default(), Some(selected))
+        }
+        _ => {
+            let (kreports, summary) = taxonomy_kreport(kreport, taxonomy, taxdump.as_ref())?;
+            (kreports, summary, None)
+        }
+    };
 
     // ─── Build taxonomic ancestry map ───────────────────
-    // Each taxid maps to a set of its ancestor taxids (inclusive)
-    let taxid_to_ancestors = kreports
-        .iter()
-        .map(|report| {
-            // Each report's `taxids` field holds the lineage (ancestors) of this taxon
-            let ancestors = report
-                .taxids
-                .iter()
-                .map(|x| x.as_slice())
-                .collect::<HashSet<&[u8]>>();
-            // Map: current taxid → set of its ancestors
-            (report.taxid.as_slice(), ancestors)
-        })
-        .collect::<HashMap<&[u8], HashSet<&[u8]>>>();
+    let kreport_tree;
+    let tree = match &taxdump {
+        Some(tree) => tree,
+        None => {
+            kreport_tree = TaxonomyTree::from_kreports(&kreports);
+            &kreport_tree
+        }
+    };
+    let from_taxonomy = selected.is_some();
+    let listed = selected.unwrap_or_else(|| {
+        kreports
+            .iter()
+            .filter_map(|report| tree.get(&report.taxid))
+            .collect()
+    });
+    let taxid_to_ancestors = taxid_to_ancestors(tree, &listed);
 
     // ─── Count reads and kmers per (barcode, taxon) ─────
     let counts_map = count::count_kmers_and_reads(
@@ -68,6 +88,17 @@ fn krcount_internal(
         batch_size,
         nqueue,
     )?;
+    if from_taxonomy {
+        let counted = counts_map
+            .values()
+            .flat_map(|barcode_map| barcode_map.keys().copied())
+            .collect::<HashSet<&[u8]>>();
+        kreports = listed
+            .iter()
+            .filter(|&&i| counted.contains(tree.taxon(i).taxid.as_slice()))
+            .map(|&i| tree_kreport(tree, i))
+            .collect();
+    }
 
     // ─── Determine all observed rank codes ───────────────
     // Examples: U, R, D, K, P, C, O, F, G, S, G2, S1, etc.
@@ -96,17 +127,10 @@ fn krcount_internal(
     let mut taxa_table: HashMap<&[u8], Vec<Rstr>> =
         HashMap::with_capacity_and_hasher(ordered_ranks.len(), rustc_hash::FxBuildHasher);
     for &rank in &ordered_ranks {
-        let mut taxa_vec = Vec::with_capacity(kreports.len());
-        for report in &kreports {
-            let mut value = Rstr::na();
-            for (r, t) in report.ranks.iter().zip(report.taxa.iter()) {
-                if r == rank {
-                    value = u8_to_rstr(t.clone());
-                    break;
-                }
-            }
-            taxa_vec.push(value);
-        }
+        let taxa_vec = rank_taxa(&kreports, rank)
+            .into_iter()
+            .map(|taxon| taxon.map_or_else(Rstr::na, |taxon| u8_to_rstr(taxon.to_vec())))
+            .collect::<Vec<_>>();
         taxa_table.insert(rank, taxa_vec);
     }
     let taxa_vec = ordered_ranks
@@ -168,42 +192,139 @@ fn krcount_internal(
             .map_err(|e| anyhow!("Failed to create list for kmer_total: {}", e))?,
         kmer_unique = List::from_names_and_values(barcode_cols, kmer_unique_vec)
             .map_err(|e| anyhow!("Failed to create list for kmer_unique: {}", e))?,
+        sample = kreport.map(|_| summary.to_list()),
     ])
 }
 
-fn rank_order_key(rank: &[u8]) -> (usize, usize) {
-    match rank {
-        b"U" => (0, 0),
-        b"R" => (1, 0),
-        b"D" => (2, 0),
-        b"K" => (3, 0),
-        b"P" => (4, 0),
-        b"C" => (5, 0),
-        b"O" => (6, 0),
-        b"F" => (7, 0),
-        b"G" => (8, 0),
-        b"S" => (9, 0),
-        _ if rank.len() > 1 => (
-            match unsafe { rank.get_unchecked(0) } {
-                b'U' => 0,
-                b'R' => 1,
-                b'D' => 2,
-                b'K' => 3,
-                b'P' => 4,
-                b'C' => 5,
-                b'O' => 6,
-                b'F' => 7,
-                b'G' => 8,
-                b'S' => 9,
-                _ => 10,
-            },
-            parse_usize(&rank[1 ..]).map_or_else(|_| usize::max_value(), |x| x),
-        ),
-        _ => (10, 0),
-    }
+/// The name of the taxon at `rank` in the lineage of each report. The lineage
+/// of the row itself still holds the ancestors filtered out of the reports.
+fn rank_taxa<'a>(kreports: &'a [Kreport], rank: &[u8]) -> Vec<Option<&'a [u8]>> {
+    kreports
+        .iter()
+        .map(|report| {
+            report
+                .ranks
+                .iter()
+                .position(|r| r == rank)
+                .map(|i| report.taxa[i].as_slice())
+        })
+        .collect()
+}
+
+/// Maps each taxid to the set of its ancestor taxids (inclusive) among the
+/// `listed` taxa of `tree`. With a taxdump, the descendants missing from the
+/// kreport are counted towards their listed ancestors. As in the lineages of
+/// kraken reports, the root ranks (root, cellular organisms) are not
+/// ancestors: they only count the reads assigned to them.
+fn taxid_to_ancestors<'a>(
+    tree: &'a TaxonomyTree,
+    listed: &[usize],
+) -> HashMap<&'a [u8], HashSet<&'a [u8]>> {
+    let listed_taxids = listed
+        .iter()
+        .map(|&i| tree.taxon(i).taxid.as_slice())
+        .collect::<HashSet<&[u8]>>();
+    tree.clades(listed.iter().copied())
+        .into_iter()
+        .map(|i| {
+            let ancestors = tree
+                .lineage(i)
+                .into_iter()
+                .map(|ancestor| tree.taxon(ancestor).taxid.as_slice())
+                .filter(|taxid| listed_taxids.contains(taxid))
+                .collect::<HashSet<&[u8]>>();
+            (tree.taxon(i).taxid.as_slice(), ancestors)
+        })
+        .collect()
 }
 
 extendr_module! {
     mod krcount;
     fn krcount;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::kreport::parse_kreport;
+    use crate::selector::Selection;
+
+    #[test]
+    fn test_krcount_root_ranks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let kreport = dir.path().join("sample.kreport");
+        std::fs::File::create(&kreport)?.write_all(
+            concat!(
+                "100.00\t3\t1\tR\t1\troot\n",
+                " 66.67\t2\t0\tR1\t131567\t  cellular organisms\n",
+                " 66.67\t2\t0\tD\t2\t    Bacteria\n",
+                " 66.67\t2\t2\tS\t562\t      Escherichia coli\n",
+            )
+            .as_bytes(),
+        )?;
+        let seq = "ACGTTGCAAGCTTCGAACGTTGCAAGCTTCGA";
+        let qual = "I".repeat(seq.len());
+        let koutreads = dir.path().join("sample.koutreads");
+        let mut file = std::fs::File::create(&koutreads)?;
+        for taxid in ["562", "562", "1"] {
+            writeln!(file, "{}\t\t{}:4\t{}\t{}", taxid, taxid, seq, qual)?;
+        }
+        drop(file);
+
+        let (kreports, _) = parse_kreport(kreport.to_str().unwrap())?;
+        let tree = TaxonomyTree::from_kreports(&kreports);
+        let listed = (0 .. tree.len()).collect::<Vec<_>>();
+        let ancestors = taxid_to_ancestors(&tree, &listed);
+        assert_eq!(
+            ancestors[&b"562"[..]],
+            [&b"2"[..], b"562"].into_iter().collect::<HashSet<_>>()
+        );
+
+        let counts = count::count_kmers_and_reads(&koutreads, ancestors, None, None, 2, None)?;
+        let counts = &counts[&Bytes::new()];
+        let reads = |taxid: &[u8]| counts.get(taxid).map(|counts| counts.reads());
+        // The root ranks only count the reads assigned to them
+        assert_eq!(reads(b"1"), Some(1));
+        assert_eq!(reads(b"131567"), None);
+        assert_eq!(reads(b"2"), Some(2));
+        assert_eq!(reads(b"562"), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_krcount_filtered_rank_taxa() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(
+            file.path(),
+            concat!(
+                "100.00\t10\t0\tR\t1\troot\n",
+                "100.00\t10\t0\tD\t2\t  Bacteria\n",
+                "100.00\t10\t0\tF\t543\t    Enterobacteriaceae\n",
+                "100.00\t10\t0\tG\t561\t      Escherichia\n",
+                "100.00\t10\t10\tS\t562\t        Escherichia coli\n",
+            ),
+        )?;
+        let (kreports, _) = parse_kreport(file.path())?;
+        // The ancestors of the species are filtered out of the reports, but
+        // still name the upper ranks
+        for selector in ["S__Escherichia coli", "S", "=562", "S < D__Bacteria"] {
+            let selection = Selection::parse(&[selector])?;
+            let filtered = kreports
+                .iter()
+                .filter(|kr| selection.matches(kr))
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(
+                rank_taxa(&filtered, b"G"),
+                vec![Some(&b"Escherichia"[..])],
+                "{}",
+                selector
+            );
+            assert_eq!(rank_taxa(&filtered, b"F"), vec![Some(&b"Enterobacteriaceae"[..])]);
+            assert_eq!(rank_taxa(&filtered, b"R"), vec![None]);
+        }
+        Ok(())
+    }
+}
@@ -2,35 +2,73 @@ use std::{fs::File, path::Path};
 
 use anyhow::{anyhow, Context, Result};
 use extendr_api::prelude::*;
-use rustc_hash::FxHashSet as HashSet;
 
+use crate::selector::Selection;
+use crate::taxonomy::{rank_code, read_taxonomy, TaxonomyTree};
 use crate::utils::*;
 use crate::{reader::LineReader, utils::BUFFER_SIZE};
 
-pub(crate) fn parse_kreport<P: AsRef<Path> + ?Sized>(kreport: &P) -> Result<Vec<Kreport>> {
+/// Column layout of a kraken report, detected from its number of fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KreportFormat {
+    /// Kraken2 and Bracken reports: percents, clade reads, direct reads, rank
+    /// code, taxid and indented name
+    Kraken2,
+    /// Kraken2 reports with `--report-minimizer-data`, the minimizer columns
+    /// follow the read counts
+    Kraken2Minimizer,
+    /// KrakenUniq reports: %, reads, taxReads, kmers, dup, cov, taxID, rank
+    /// and indented taxName
+    KrakenUniq,
+}
+
+impl KreportFormat {
+    fn from_fields(n: usize) -> Option<Self> {
+        match n {
+            6 => Some(Self::Kraken2),
+            8 => Some(Self::Kraken2Minimizer),
+            9 => Some(Self::KrakenUniq),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the taxa of a kraken report, the unclassified row only counts
+/// towards the [`KreportSummary`]
+pub(crate) fn parse_kreport<P: AsRef<Path> + ?Sized>(
+    kreport: &P,
+) -> Result<(Vec<Kreport>, KreportSummary)> {
     let path: &Path = kreport.as_ref();
     let mut reader = LineReader::with_capacity(
         BUFFER_SIZE,
         File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?,
     );
     let mut kreports: Vec<Kreport> = Vec::with_capacity(10);
+    let mut summary = KreportSummary::default();
     let mut ancestors = Vec::with_capacity(10);
+    // The rank code of the last taxon at each level, for KrakenUniq ranks
+    let mut level_ranks: Vec<Vec<u8>> = Vec::with_capacity(10);
     let mut pos = 0; // The line offset of the ancestors
     while let Some(line) = reader.read_line()? {
         if line.iter().all(|b| b.is_ascii_whitespace()) {
             continue;
         }
         let line = line.freeze();
+        // KrakenUniq reports start with comments and a header line
+        if line[0] == b'#' || line.starts_with(b"%\t") {
+            continue;
+        }
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
         let fields: Vec<&[u8]> = line.split(|b| *b == b'\t').collect();
-        if fields.len() != 6 && fields.len() != 8 {
+        let Some(format) = KreportFormat::from_fields(fields.len()) else {
             return Err(anyhow!(
                 "Invalid line with {} fields: {:?}",
                 fields.len(),
-                String::from_utf8_lossy(&line)
+                String::from_utf8_lossy(line)
             ))
             .with_context(|| format!("Failed to parse kraken report: '{}'", path.display()))
             .with_context(|| {
-                format!("Failed to parse line: '{}'", String::from_utf8_lossy(&line))
+                format!("Failed to parse line: '{}'", String::from_utf8_lossy(line))
             })?;
         };
 
@@ -38,20 +76,23 @@ pub(crate) fn parse_kreport<P: AsRef<Path> + ?Sized>(kreport: &P) -> Result<Vec<
         let percents = parse_f64(unsafe { fields.get_unchecked(0) })
             .with_context(|| format!("Failed to parse kraken report: '{}'", path.display()))
             .with_context(|| {
-                format!("Failed to parse line: '{}'", String::from_utf8_lossy(&line))
+                format!("Failed to parse line: '{}'", String::from_utf8_lossy(line))
             })?;
-        let total_reads = parse_usize(unsafe { fields.get_unchecked(1) })
+        let total_reads = parse_count(unsafe { fields.get_unchecked(1) })
             .with_context(|| format!("Failed to parse kraken report: '{}'", path.display()))
             .with_context(|| {
-                format!("Failed to parse line: '{}'", String::from_utf8_lossy(&line))
+                format!("Failed to parse line: '{}'", String::from_utf8_lossy(line))
             })?;
-        let reads = parse_usize(unsafe { fields.get_unchecked(2) })
+        let reads = parse_count(unsafe { fields.get_unchecked(2) })
             .with_context(|| format!("Failed to parse kraken report: '{}'", path.display()))
             .with_context(|| {
-                format!("Failed to parse line: '{}'", String::from_utf8_lossy(&line))
+                format!("Failed to parse line: '{}'", String::from_utf8_lossy(line))
             })?;
-        let minimizer_len;
-        let minimizer_n_unique;
+        let mut minimizer_len = None;
+        let mut minimizer_n_unique = None;
+        let mut kmers = None;
+        let mut dup = None;
+        let mut cov = None;
         let rank;
         let taxid;
         let taxon;
@@ -73,26 +114,35 @@ pub(crate) fn parse_kreport<P: AsRef<Path> + ?Sized>(kreport: &P) -> Result<Vec<
         //    taxon is at the genus rank.
         // 7. NCBI taxonomic ID number
         // 8. Indented scientific name
-        if fields.len() == 6 {
-            // 6-column format
-            rank = unsafe { fields.get_unchecked(3) };
-            if rank[0] == b'U' {
-                continue;
+        //
+        // Bracken writes its re-estimated reports in the 6-column format.
+        //
+        // https://github.com/fbreitwieser/krakenuniq/blob/master/README.md
+        // KrakenUniq reports the clade and direct reads, then the number of
+        // unique k-mers, their average duplicity and the coverage of the clade
+        // in the database, followed by the taxid, a full rank name and the
+        // indented name.
+        match format {
+            KreportFormat::Kraken2 => {
+                rank = unsafe { fields.get_unchecked(3) };
+                taxid = unsafe { fields.get_unchecked(4) };
+                taxon_field = unsafe { fields.get_unchecked(5) }.iter().peekable();
             }
-            taxid = unsafe { fields.get_unchecked(4) };
-            taxon_field = unsafe { fields.get_unchecked(5) }.into_iter().peekable();
-            minimizer_len = None;
-            minimizer_n_unique = None;
-        } else {
-            // 8-column format
-            rank = unsafe { fields.get_unchecked(5) };
-            if rank[0] == b'U' {
-                continue;
+            KreportFormat::Kraken2Minimizer => {
+                rank = unsafe { fields.get_unchecked(5) };
+                minimizer_len = Some(parse_usize(unsafe { fields.get_unchecked(3) })?);
+                minimizer_n_unique = Some(parse_usize(unsafe { fields.get_unchecked(4) })?);
+                taxid = unsafe { fields.get_unchecked(6) };
+                taxon_field = unsafe { fields.get_unchecked(7) }.iter().peekable();
+            }
+            KreportFormat::KrakenUniq => {
+                kmers = Some(parse_usize(unsafe { fields.get_unchecked(3) })?);
+                dup = Some(parse_f64(unsafe { fields.get_unchecked(4) })?);
+                cov = Some(parse_f64(unsafe { fields.get_unchecked(5) })?);
+                rank = unsafe { fields.get_unchecked(7) };
+                taxid = unsafe { fields.get_unchecked(6) };
+                taxon_field = unsafe { fields.get_unchecked(8) }.iter().peekable();
             }
-            minimizer_len = Some(parse_usize(unsafe { fields.get_unchecked(3) })?);
-            minimizer_n_unique = Some(parse_usize(unsafe { fields.get_unchecked(4) })?);
-            taxid = unsafe { fields.get_unchecked(6) };
-            taxon_field = unsafe { fields.get_unchecked(7) }.into_iter().peekable();
         };
         let mut n = 0;
         while let Some(byte) = taxon_field.peek() {
@@ -105,10 +155,28 @@ pub(crate) fn parse_kreport<P: AsRef<Path> + ?Sized>(kreport: &P) -> Result<Vec<
         }
         level = n / 2;
         taxon = taxon_field.copied().collect::<Vec<u8>>();
-        let rank: Vec<u8> = rank.into_iter().copied().collect();
-        let taxid: Vec<u8> = taxid.into_iter().copied().collect();
+        let rank = if format == KreportFormat::KrakenUniq {
+            let parent = level
+                .checked_sub(1)
+                .and_then(|parent| level_ranks.get(parent));
+            rank_code(rank, taxid, parent.map(|rank| rank.as_slice()))
+        } else {
+            rank.to_vec()
+        };
+        level_ranks.truncate(level);
+        level_ranks.push(rank.clone());
+        if rank.first() == Some(&b'U') {
+            *summary.unclassified_reads.get_or_insert(0) += total_reads;
+            continue;
+        }
+        if level == 0 {
+            summary.classified_reads += total_reads;
+        }
+        let taxid: Vec<u8> = taxid.to_vec();
+        // Pop every ancestor not above this level, so that a skipped level
+        // doesn't underflow or drop the whole lineage
         while let Some(ancestor) = ancestors.last() {
-            if unsafe { kreports.get_unchecked::<usize>(*ancestor) }.level != level - 1 {
+            if unsafe { kreports.get_unchecked::<usize>(*ancestor) }.level >= level {
                 ancestors.pop();
             } else {
                 break;
@@ -130,7 +198,7 @@ pub(crate) fn parse_kreport<P: AsRef<Path> + ?Sized>(kreport: &P) -> Result<Vec<
             .unzip();
 
         // always remove root species from ancestors
-        if rank[0] != b'R' {
+        if rank.first() != Some(&b'R') {
             ancestors.push(pos);
         }
         let report = Kreport {
@@ -139,6 +207,9 @@ pub(crate) fn parse_kreport<P: AsRef<Path> + ?Sized>(kreport: &P) -> Result<Vec<
             reads,
             minimizer_len,
             minimizer_n_unique,
+            kmers,
+            dup,
+            cov,
             rank,
             taxid,
             taxon,
@@ -150,52 +221,63 @@ pub(crate) fn parse_kreport<P: AsRef<Path> + ?Sized>(kreport: &P) -> Result<Vec<
         kreports.push(report);
         pos += 1;
     }
-    Ok(kreports)
+    Ok((kreports, summary))
+}
+
+/// Read counts, re-estimated counts written with decimals are rounded
+fn parse_count(bytes: &[u8]) -> Result<usize> {
+    parse_usize(bytes).or_else(|e| match parse_f64(bytes) {
+        Ok(count) if count >= 0.0 && count.is_finite() => Ok(count.round() as usize),
+        _ => Err(e),
+    })
 }
 
+/// Parses a kraken report and keeps the taxa in `taxonomy`. With a `tree`,
+/// such as an NCBI taxdump, the lineages of the taxa come from the tree
+/// instead of the indentation of the report.
 pub(crate) fn taxonomy_kreport<P: AsRef<Path> + ?Sized>(
-    kreport: &P,
+    kreport: Option<&P>,
     taxonomy: Robj,
-) -> Result<Vec<Kreport>> {
+    tree: Option<&TaxonomyTree>,
+) -> Result<(Vec<Kreport>, KreportSummary)> {
     let taxonomy =
         robj_to_option_str(&taxonomy).with_context(|| format!("Failed to parse 'taxonomy'"))?;
-    let path = kreport.as_ref();
-    let mut kreports = parse_kreport(path)?;
-    if kreports.is_empty() {
-        return Err(anyhow!(
-            "No entries found in kreport file: '{}'. Please ensure it is not empty or malformed.",
-            path.display()
-        ));
-    }
-    if let Some(taxonomy) = taxonomy {
-        // Parse taxon strings like "rank__name" into rank-name pairs
-        let rank_taxon_sets = taxonomy
-            .iter()
-            .filter_map(|t| {
-                let mut pair = t.splitn(2, "__");
-                if let (Some(rank), Some(taxa)) = (pair.next(), pair.next()) {
-                    Some((rank.as_bytes(), taxa.as_bytes()))
-                } else {
-                    None
+    let (mut kreports, summary) = match (kreport, tree) {
+        (Some(kreport), _) => {
+            let path = kreport.as_ref();
+            let (mut kreports, summary) = parse_kreport(path)?;
+            if kreports.is_empty() {
+                return Err(anyhow!(
+                    "No entries found in kreport file: '{}'. Please ensure it is not empty or malformed.",
+                    path.display()
+                ));
+            }
+            if let Some(tree) = tree {
+                for report in kreports.iter_mut() {
+                    if let Some(i) = tree.get(&report.taxid) {
+                        report.set_lineage(tree, i);
+                    }
                 }
-            })
-            .collect::<HashSet<(&[u8], &[u8])>>();
-
-        // Fail early if no valid taxon entries
-        if !taxonomy.is_empty() && rank_taxon_sets.is_empty() {
-            return Err(anyhow!("No valid taxonomy provided. 'taxonomy' must be in the format 'rank__name', where 'rank' and 'name' are separated by '__'."));
+            }
+            (kreports, summary)
         }
-
+        // Without a report, the selected taxa of the taxonomy are rows without
+        // reads
+        (None, Some(tree)) => {
+            let kreports = select_tree_taxa(tree, taxonomy.as_deref())?
+                .into_iter()
+                .map(|i| tree_kreport(tree, i))
+                .collect();
+            return Ok((kreports, KreportSummary::default()));
+        }
+        (None, None) => {
+            return Err(anyhow!("One of 'kreport' or 'taxdump' must be provided"));
+        }
+    };
+    if let Some(taxonomy) = taxonomy {
         // Parsing kraken2 report: only contain information specified by `taxonomy`
-        kreports = kreports
-            .into_iter()
-            .filter(|kr| {
-                kr.ranks
-                    .iter()
-                    .zip(kr.taxa.iter())
-                    .any(|(rank, taxa)| rank_taxon_sets.contains(&(rank, taxa)))
-            })
-            .collect();
+        let selection = Selection::parse(&taxonomy)?;
+        kreports.retain(|kr| selection.matches(kr));
         if kreports.is_empty() {
             return Err(anyhow!(
                 "No taxonomic matches found in the kreport file for {:?}.",
@@ -203,16 +285,56 @@ pub(crate) fn taxonomy_kreport<P: AsRef<Path> + ?Sized>(
             ));
         }
     }
-    Ok(kreports)
+    Ok((kreports, summary))
+}
+
+/// Sample-level read counts of a kraken report, the denominators of
+/// normalised abundances. They are read before any taxonomy filtering.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct KreportSummary {
+    /// Fragments of the unclassified row, `None` if the report has no such
+    /// row
+    pub(crate) unclassified_reads: Option<usize>,
+    /// Fragments of the top-level clades, usually the root alone
+    pub(crate) classified_reads: usize,
+}
+
+impl KreportSummary {
+    /// All fragments of the sample, only known with the unclassified row
+    pub(crate) fn total_reads(&self) -> Option<usize> {
+        self.unclassified_reads
+            .map(|unclassified| unclassified + self.classified_reads)
+    }
+
+    pub(crate) fn classified_fraction(&self) -> Option<f64> {
+        self.total_reads()
+            .filter(|total| *total > 0)
+            .map(|total| self.classified_reads as f64 / total as f64)
+    }
+
+    pub(crate) fn to_list(self) -> List {
+        let count = |reads: Option<usize>| Rfloat::from(reads.map(|reads| reads as f64));
+        list![
+            unclassified_reads = count(self.unclassified_reads),
+            classified_reads = self.classified_reads as f64,
+            total_reads = count(self.total_reads()),
+            classified_fraction = Rfloat::from(self.classified_fraction())
+        ]
+    }
 }
 
 #[allow(dead_code)]
+#[derive(Clone)]
 pub(crate) struct Kreport {
     pub(crate) percents: f64,
     pub(crate) total_reads: usize,
     pub(crate) reads: usize,
     pub(crate) minimizer_len: Option<usize>,
     pub(crate) minimizer_n_unique: Option<usize>,
+    // KrakenUniq columns
+    pub(crate) kmers: Option<usize>,
+    pub(crate) dup: Option<f64>,
+    pub(crate) cov: Option<f64>,
     pub(crate) rank: Vec<u8>,
     pub(crate) taxid: Vec<u8>,
     pub(crate) taxon: Vec<u8>,
@@ -222,9 +344,84 @@ pub(crate) struct Kreport {
     pub(crate) level: usize,
 }
 
+impl Kreport {
+    /// Replaces the lineage by the one of the taxon `i` of `tree`
+    pub(crate) fn set_lineage(&mut self, tree: &TaxonomyTree, i: usize) {
+        let lineage = tree.lineage(i);
+        self.ranks = lineage
+            .iter()
+            .map(|&a| tree.taxon(a).rank.clone())
+            .collect();
+        self.taxids = lineage
+            .iter()
+            .map(|&a| tree.taxon(a).taxid.clone())
+            .collect();
+        self.taxa = lineage
+            .iter()
+            .map(|&a| tree.taxon(a).name.clone())
+            .collect();
+    }
+}
+
+/// The row without reads of the taxon `i` of `tree`
+pub(crate) fn tree_kreport(tree: &TaxonomyTree, i: usize) -> Kreport {
+    let taxon = tree.taxon(i);
+    let mut report = Kreport {
+        percents: 0.0,
+        total_reads: 0,
+        reads: 0,
+        minimizer_len: None,
+        minimizer_n_unique: None,
+        kmers: None,
+        dup: None,
+        cov: None,
+        rank: taxon.rank.clone(),
+        taxid: taxon.taxid.clone(),
+        taxon: taxon.name.clone(),
+        ranks: Vec::new(),
+        taxids: Vec::new(),
+        taxa: Vec::new(),
+        level: taxon.depth,
+    };
+    report.set_lineage(tree, i);
+    report
+}
+
+/// The taxa of `tree` selected by `taxonomy` (all of them without it), in the
+/// order of the tree. Each row is only built to be matched and then dropped,
+/// so that a large taxonomy such as the NCBI one is never held as reports.
+pub(crate) fn select_tree_taxa(
+    tree: &TaxonomyTree,
+    taxonomy: Option<&[&str]>,
+) -> Result<Vec<usize>> {
+    let Some(taxonomy) = taxonomy else {
+        return Ok((0 .. tree.len()).collect());
+    };
+    let selection = Selection::parse(taxonomy)?;
+    let selected = (0 .. tree.len())
+        .filter(|&i| selection.matches(&tree_kreport(tree, i)))
+        .collect::<Vec<_>>();
+    if selected.is_empty() {
+        return Err(anyhow!(
+            "No taxonomic matches found in the taxonomy for {:?}.",
+            taxonomy
+        ));
+    }
+    Ok(selected)
+}
+
 #[extendr]
-fn read_kreport(kreport: &str, taxonomy: Robj) -> std::result::Result<List, String> {
-    let kreports = taxonomy_kreport(kreport, taxonomy).map_err(|e| format!("{:?}", e))?;
+fn read_kreport(
+    kreport: &str,
+    taxonomy: Robj,
+    taxdump: Option<&str>,
+) -> std::result::Result<List, String> {
+    let tree = taxdump
+        .map(read_taxonomy)
+        .transpose()
+        .map_err(|e| format!("{:?}", e))?;
+    let (kreports, summary) =
+        taxonomy_kreport(Some(kreport), taxonomy, tree.as_ref()).map_err(|e| format!("{:?}", e))?;
 
     let mut percents = Vec::with_capacity(kreports.len());
     let mut total_reads = Vec::with_capacity(kreports.len());
@@ -241,6 +438,9 @@ fn read_kreport(kreport: &str, taxonomy: Robj) -> std::result::Result<List, Stri
     // Optional columns
     let mut minimizer_len = Vec::with_capacity(kreports.len());
     let mut minimizer_n_unique = Vec::with_capacity(kreports.len());
+    let mut kmers = Vec::with_capacity(kreports.len());
+    let mut dup = Vec::with_capacity(kreports.len());
+    let mut cov = Vec::with_capacity(kreports.len());
     for report in kreports {
         percents.push(report.percents);
         total_reads.push(report.total_reads as f64);
@@ -254,51 +454,166 @@ fn read_kreport(kreport: &str, taxonomy: Robj) -> std::result::Result<List, Stri
         taxids.push(Robj::from(u8_to_list_rstr(report.taxids)));
         taxa.push(Robj::from(u8_to_list_rstr(report.taxa)));
 
-        match (report.minimizer_len, report.minimizer_n_unique) {
-            (Some(a), Some(b)) => {
-                minimizer_len.push(a as f64);
-                minimizer_n_unique.push(b as f64);
-            }
-            _ => {}
+        if let (Some(a), Some(b)) = (report.minimizer_len, report.minimizer_n_unique) {
+            minimizer_len.push(a as f64);
+            minimizer_n_unique.push(b as f64);
+        }
+        if let (Some(a), Some(b), Some(c)) = (report.kmers, report.dup, report.cov) {
+            kmers.push(a as f64);
+            dup.push(b);
+            cov.push(c);
         }
     }
 
-    let ranks = List::from_values(ranks);
-    let taxids = List::from_values(taxids);
-    let taxa = List::from_values(taxa);
-
     // Create R dataframe
-    let out = if minimizer_len.is_empty() {
-        list![
-            percents = percents,
-            total_reads = total_reads,
-            reads = reads,
-            rank = rank,
-            taxid = taxid,
-            taxon = taxon,
-            ranks = ranks,
-            taxids = taxids,
-            taxa = taxa
-        ]
-    } else {
-        list![
-            percents = percents,
-            total_reads = total_reads,
-            reads = reads,
-            minimizer_len = minimizer_len,
-            minimizer_n_unique = minimizer_n_unique,
-            rank = rank,
-            taxid = taxid,
-            taxon = taxon,
-            ranks = ranks,
-            taxids = taxids,
-            taxa = taxa
-        ]
-    };
-    Ok(out)
+    let mut names = vec!["percents", "total_reads", "reads"];
+    let mut columns: Vec<Robj> = vec![percents.into(), total_reads.into(), reads.into()];
+    if !minimizer_len.is_empty() {
+        names.extend(["minimizer_len", "minimizer_n_unique"]);
+        columns.extend([minimizer_len.into(), minimizer_n_unique.into()]);
+    }
+    if !kmers.is_empty() {
+        names.extend(["kmers", "dup", "cov"]);
+        columns.extend([kmers.into(), dup.into(), cov.into()]);
+    }
+    names.extend(["rank", "taxid", "taxon", "ranks", "taxids", "taxa"]);
+    columns.extend([
+        rank.into(),
+        taxid.into(),
+        taxon.into(),
+        List::from_values(ranks).into(),
+        List::from_values(taxids).into(),
+        List::from_values(taxa).into(),
+    ]);
+    let kreport = List::from_names_and_values(names, columns)
+        .map_err(|e| format!("Failed to create list for kraken report: {:?}", e))?;
+    Ok(list![kreport = kreport, sample = summary.to_list()])
 }
 
 extendr_module! {
     mod kreport;
     fn read_kreport;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn write_kreport(content: &str) -> Result<tempfile::NamedTempFile> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(content.as_bytes())?;
+        file.flush()?;
+        Ok(file)
+    }
+
+    #[test]
+    fn test_parse_bracken_kreport() -> Result<()> {
+        // Bracken reports skip the intermediate ranks and may hold decimal counts
+        let file = write_kreport(concat!(
+            "100.00\t1000\t0\tR\t1\troot\n",
+            "100.00\t1000\t0\tD\t2\t  Bacteria\n",
+            "60.00\t600\t0\tG\t561\t      Escherichia\n",
+            "60.00\t600\t600\tS\t562\t        Escherichia coli\n",
+            "40.00\t400.0\t0\tG\t1279\t      Staphylococcus\n",
+            "40.00\t400\t400\tS\t1280\t        Staphylococcus aureus\n",
+        ))?;
+        let (kreports, summary) = parse_kreport(file.path())?;
+        assert_eq!(kreports.len(), 6);
+        let coli = &kreports[3];
+        assert_eq!(
+            coli.taxids,
+            vec![b"2".to_vec(), b"561".to_vec(), b"562".to_vec()]
+        );
+        assert_eq!(coli.minimizer_len, None);
+        assert_eq!(coli.kmers, None);
+        assert_eq!(kreports[4].total_reads, 400);
+        assert_eq!(summary.unclassified_reads, None);
+        assert_eq!(summary.classified_reads, 1000);
+        assert_eq!(summary.total_reads(), None);
+        assert_eq!(
+            kreports[5].taxids,
+            vec![b"2".to_vec(), b"1279".to_vec(), b"1280".to_vec()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_krakenuniq_kreport() -> Result<()> {
+        let file = write_kreport(concat!(
+            "# KrakenUniq v1.0.4 DATE:2024-01-01T00:00:00Z DB:db\n",
+            "# Database size: 100 Mb\n",
+            "\n",
+            "%\treads\ttaxReads\tkmers\tdup\tcov\ttaxID\trank\ttaxName\n",
+            "10\t100\t100\t0\t0\t0\t0\tno rank\tunclassified\n",
+            "90\t900\t0\t5000\t1.2\t0.001\t1\tno rank\troot\n",
+            "90\t900\t0\t5000\t1.2\t0.001\t131567\tno rank\t  cellular organisms\n",
+            "90\t900\t0\t5000\t1.2\t0.001\t2\tsuperkingdom\t    Bacteria\n",
+            "90\t900\t100\t4000\t1.1\t0.002\t562\tspecies\t      Escherichia coli\n",
+            "80\t800\t800\t3000\t1.05\t0.01\t83333\tstrain\t        Escherichia coli K-12\n",
+        ))?;
+        let (kreports, summary) = parse_kreport(file.path())?;
+        // The unclassified row only counts towards the summary
+        assert_eq!(summary.unclassified_reads, Some(100));
+        assert_eq!(summary.classified_reads, 900);
+        assert_eq!(summary.total_reads(), Some(1000));
+        assert_eq!(summary.classified_fraction(), Some(0.9));
+        let ranks = kreports
+            .iter()
+            .map(|kr| kr.rank.as_slice())
+            .collect::<Vec<_>>();
+        assert_eq!(ranks, vec![&b"R"[..], b"R1", b"D", b"S", b"S1"]);
+        let strain = &kreports[4];
+        assert_eq!((strain.total_reads, strain.reads), (800, 800));
+        assert_eq!(
+            (strain.kmers, strain.dup, strain.cov),
+            (Some(3000), Some(1.05), Some(0.01))
+        );
+        assert_eq!(strain.taxon, b"Escherichia coli K-12");
+        // As in kraken2 reports, root ranks are not part of the lineage
+        assert_eq!(
+            strain.ranks,
+            vec![b"D".to_vec(), b"S".to_vec(), b"S1".to_vec()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_kreport_invalid() -> Result<()> {
+        let file = write_kreport("100.00\t1000\t0\troot\n")?;
+        assert!(parse_kreport(file.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_tree_taxa() -> Result<()> {
+        let file = write_kreport(concat!(
+            "100.00\t1000\t0\tR\t1\troot\n",
+            "100.00\t1000\t0\tD\t2\t  Bacteria\n",
+            "60.00\t600\t0\tG\t561\t    Escherichia\n",
+            "60.00\t600\t600\tS\t562\t      Escherichia coli\n",
+            "40.00\t400\t400\tS\t1280\t    Staphylococcus aureus\n",
+        ))?;
+        let (kreports, _) = parse_kreport(file.path())?;
+        let tree = TaxonomyTree::from_kreports(&kreports);
+        assert_eq!(select_tree_taxa(&tree, None)?.len(), tree.len());
+        let selected = select_tree_taxa(&tree, Some(&["G__Escherichia"]))?;
+        let rows = selected
+            .iter()
+            .map(|&i| tree_kreport(&tree, i))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows.iter()
+                .map(|kr| kr.taxid.as_slice())
+                .collect::<Vec<_>>(),
+            vec![b"561".as_slice(), b"562".as_slice()]
+        );
+        assert_eq!(
+            rows[1].taxids,
+            vec![b"2".to_vec(), b"561".to_vec(), b"562".to_vec()]
+        );
+        assert!(select_tree_taxa(&tree, Some(&["G__Bacillus"])).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+use anyhow::{anyhow, Context, Result};
+use extendr_api::prelude::*;
+use rayon::prelude::*;
+use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::FxHashSet as HashSet;
+
+use crate::kreport::{parse_kreport, Kreport, KreportSummary};
+use crate::selector::Selection;
+use crate::taxonomy::{rank_order_key, read_taxonomy, TaxonomyTree};
+use crate::utils::*;
+
+/// MergedKreports: Kraken reports of several samples aligned on taxid.
+///
+/// The counts are kept as sparse triplets: the row of the taxon, the column
+/// of the sample and the counts, only for the taxa with reads in the sample.
+#[derive(Default)]
+struct MergedKreports {
+    /// One report row per taxon, in order of first appearance, holding its
+    /// lineage
+    taxa: Vec<Kreport>,
+    index: HashMap<Vec<u8>, usize>,
+    rows: Vec<usize>,
+    cols: Vec<usize>,
+    reads: Vec<usize>,
+    total_reads: Vec<usize>,
+    minimizer_len: Vec<usize>,
+    minimizer_n_unique: Vec<usize>,
+    /// Whether any report has the minimizer columns
+    minimizers: bool,
+    summaries: Vec<KreportSummary>,
+}
+
+impl MergedKreports {
+    fn add(&mut self, kreports: Vec<Kreport>, summary: KreportSummary) {
+        let col = self.summaries.len();
+        self.summaries.push(summary);
+        for report in kreports {
+            let row = match self.index.get(&report.taxid) {
+                Some(&row) => {
+                    // Thresholds of the selectors are met if met in any sample
+                    let taxon = &mut self.taxa[row];
+                    taxon.total_reads = taxon.total_reads.max(report.total_reads);
+                    taxon.percents = taxon.percents.max(report.percents);
+                    row
+                }
+                None => {
+                    self.index.insert(report.taxid.clone(), self.taxa.len());
+                    self.taxa.push(report.clone());
+                    self.taxa.len() - 1
+                }
+            };
+            // Rows of `--report-zero-counts` reports only add the taxon
+            if report.total_reads == 0 {
+                continue;
+            }
+            self.rows.push(row);
+            self.cols.push(col);
+            self.reads.push(report.reads);
+            self.total_reads.push(report.total_reads);
+            self.minimizers |= report.minimizer_len.is_some();
+            self.minimizer_len.push(report.minimizer_len.unwrap_or(0));
+            self.minimizer_n_unique
+                .push(report.minimizer_n_unique.unwrap_or(0));
+        }
+    }
+
+    /// Keeps the taxa for which `keep` is true, along with their counts
+    fn retain<F: Fn(&Kreport) -> bool>(&mut self, keep: F) {
+        let mut new_rows = vec![None; self.taxa.len()];
+        let mut n = 0;
+        for (row, report) in self.taxa.iter().enumerate() {
+            if keep(report) {
+                new_rows[row] = Some(n);
+                n += 1;
+            }
+        }
+        let taxa = std::mem::take(&mut self.taxa);
+        self.taxa = taxa
+            .into_iter()
+            .zip(new_rows.iter())
+            .filter_map(|(report, new_row)| new_row.map(|_| report))
+            .collect();
+        self.index = self
+            .taxa
+            .iter()
+            .enumerate()
+            .map(|(row, report)| (report.taxid.clone(), row))
+            .collect();
+
+        let mut kept = 0;
+        for k in 0 .. self.rows.len() {
+            if let Some(row) = new_rows[self.rows[k]] {
+                self.rows[kept] = row;
+                self.cols[kept] = self.cols[k];
+                self.reads[kept] = self.reads[k];
+                self.total_reads[kept] = self.total_reads[k];
+                self.minimizer_len[kept] = self.minimizer_len[k];
+                self.minimizer_n_unique[kept] = self.minimizer_n_unique[k];
+                kept += 1;
+            }
+        }
+        self.rows.truncate(kept);
+        self.cols.truncate(kept);
+        self.reads.truncate(kept);
+        self.total_reads.truncate(kept);
+        self.minimizer_len.truncate(kept);
+        self.minimizer_n_unique.truncate(kept);
+    }
+}
+
+/// Parses the reports with `threads` threads, and merges them in the order
+/// of `kreports`. With a `tree`, the lineages of the taxa come from the tree.
+fn merge_kreport_files(
+    kreports: &[String],
+    tree: Option<&TaxonomyTree>,
+    threads: usize,
+) -> Result<MergedKreports> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .with_context(|| format!("Failed to create thread pool"))?;
+    let parsed = pool.install(|| {
+        kreports
+            .par_iter()
+            .map(|kreport| {
+                let (mut reports, summary) = parse_kreport(kreport)?;
+                if let Some(tree) = tree {
+                    for report in reports.iter_mut() {
+                        if let Some(i) = tree.get(&report.taxid) {
+                            report.set_lineage(tree, i);
+                        }
+                    }
+                }
+                Ok((reports, summary))
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+    let mut merged = MergedKreports::default();
+    for (reports, summary) in parsed {
+        merged.add(reports, summary);
+    }
+    Ok(merged)
+}
+
+#[extendr]
+fn merge_kreports(
+    kreports: Vec<String>,
+    taxonomy: Robj,
+    taxdump: Option<&str>,
+    threads: usize,
+) -> std::result::Result<List, String> {
+    merge_kreports_internal(kreports, taxonomy, taxdump, threads).map_err(|e| format!("{:?}", e))
+}
+
+fn merge_kreports_internal(
+    kreports: Vec<String>,
+    taxonomy: Robj,
+    taxdump: Option<&str>,
+    threads: usize,
+) -> Result<List> {
+    let taxonomy =
+        robj_to_option_str(&taxonomy).with_context(|| format!("Failed to parse 'taxonomy'"))?;
+    let tree = taxdump.map(read_taxonomy).transpose()?;
+    let mut merged = merge_kreport_files(&kreports, tree.as_ref(), threads)?;
+    if let Some(taxonomy) = taxonomy {
+        let selection = Selection::parse(&taxonomy)?;
+        merged.retain(|kr| selection.matches(kr));
+        if merged.taxa.is_empty() {
+            return Err(anyhow!(
+                "No taxonomic matches found in the kreport files for {:?}.",
+                taxonomy
+            ));
+        }
+    }
+
+    // ─── Build taxa table by rank (columns) ──────────────
+    // The name of the taxon of each rank in the lineage of each taxon
+    let mut ordered_ranks = merged
+        .taxa
+        .iter()
+        .flat_map(|report| report.ranks.iter())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    ordered_ranks.sort_by_key(|rank| rank_order_key(rank));
+    let mut names = vec!["taxid".to_string(), "rank".to_string(), "taxon".to_string()];
+    let mut columns: Vec<Robj> = vec![
+        merged
+            .taxa
+            .iter()
+            .map(|report| u8_to_rstr(report.taxid.clone()))
+            .collect::<Vec<_>>()
+            .into(),
+        merged
+            .taxa
+            .iter()
+            .map(|report| u8_to_rstr(report.rank.clone()))
+            .collect::<Vec<_>>()
+            .into(),
+        merged
+            .taxa
+            .iter()
+            .map(|report| u8_to_rstr(report.taxon.clone()))
+            .collect::<Vec<_>>()
+            .into(),
+    ];
+    for rank in ordered_ranks {
+        names.push(String::from_utf8_lossy(rank).into_owned());
+        columns.push(
+            merged
+                .taxa
+                .iter()
+                .map(|report| {
+                    report
+                        .ranks
+                        .iter()
+                        .position(|r| r == rank)
+                        .map_or_else(Rstr::na, |i| u8_to_rstr(report.taxa[i].clone()))
+                })
+                .collect::<Vec<_>>()
+                .into(),
+        );
+    }
+    let taxa = List::from_names_and_values(names, columns)
+        .map_err(|e| anyhow!("Failed to create list for taxa: {}", e))?;
+
+    // ─── Sample-level counts ─────────────────────────────
+    let count = |reads: Option<usize>| Rfloat::from(reads.map(|reads| reads as f64));
+    let sample = list![
+        unclassified_reads = merged
+            .summaries
+            .iter()
+            .map(|s| count(s.unclassified_reads))
+            .collect::<Vec<_>>(),
+        classified_reads = merged
+            .summaries
+            .iter()
+            .map(|s| s.classified_reads as f64)
+            .collect::<Vec<_>>(),
+        total_reads = merged
+            .summaries
+            .iter()
+            .map(|s| count(s.total_reads()))
+            .collect::<Vec<_>>(),
+        classified_fraction = merged
+            .summaries
+            .iter()
+            .map(|s| Rfloat::from(s.classified_fraction()))
+            .collect::<Vec<_>>()
+    ];
+
+    // ─── Sparse count matrices, 1-based triplets ─────────
+    let to_f64 = |counts: &[usize]| counts.iter().map(|&x| x as f64).collect::<Vec<_>>();
+    let (minimizer_len, minimizer_n_unique) = if merged.minimizers {
+        (
+            Some(to_f64(&merged.minimizer_len)),
+            Some(to_f64(&merged.minimizer_n_unique)),
+        )
+    } else {
+        (None, None)
+    };
+    Ok(list![
+        taxa = taxa,
+        sample = sample,
+        i = merged
+            .rows
+            .iter()
+            .map(|&row| (row + 1) as i32)
+            .collect::<Vec<_>>(),
+        j = merged
+            .cols
+            .iter()
+            .map(|&col| (col + 1) as i32)
+            .collect::<Vec<_>>(),
+        reads = to_f64(&merged.reads),
+        total_reads = to_f64(&merged.total_reads),
+        minimizer_len = minimizer_len,
+        minimizer_n_unique = minimizer_n_unique
+    ])
+}
+
+extendr_module! {
+    mod kreport_merge;
+    fn merge_kreports;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn write_kreport(dir: &std::path::Path, name: &str, content: &str) -> Result<String> {
+        let path = dir.join(name);
+        std::fs::File::create(&path)?.write_all(content.as_bytes())?;
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn test_merge_kreport_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sample1 = write_kreport(
+            dir.path(),
+            "sample1.kreport",
+            concat!(
+                " 20.00\t2\t2\tU\t0\tunclassified\n",
+                " 80.00\t8\t0\tR\t1\troot\n",
+                " 80.00\t8\t1\tD\t2\t  Bacteria\n",
+                " 70.00\t7\t7\tS\t562\t    Escherichia coli\n",
+            ),
+        )?;
+        let sample2 = write_kreport(
+            dir.path(),
+            "sample2.kreport",
+            concat!(
+                "100.00\t4\t0\tR\t1\troot\n",
+                "100.00\t4\t0\tD\t2\t  Bacteria\n",
+                "  0.00\t0\t0\tS\t562\t    Escherichia coli\n",
+                "100.00\t4\t4\tS\t1280\t    Staphylococcus aureus\n",
+            ),
+        )?;
+        let mut merged = merge_kreport_files(&[sample1, sample2], None, 2)?;
+        let taxids = merged
+            .taxa
+            .iter()
+            .map(|report| String::from_utf8(report.taxid.clone()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(taxids, vec!["1", "2", "562", "1280"]);
+        // The zero-count row of sample 2 is not a matrix entry
+        assert_eq!(merged.rows, vec![0, 1, 2, 0, 1, 3]);
+        assert_eq!(merged.cols, vec![0, 0, 0, 1, 1, 1]);
+        assert_eq!(merged.reads, vec![0, 1, 7, 0, 0, 4]);
+        assert_eq!(merged.total_reads, vec![8, 8, 7, 4, 4, 4]);
+        assert!(!merged.minimizers);
+        assert_eq!(merged.summaries[0].total_reads(), Some(10));
+        assert_eq!(merged.summaries[1].total_reads(), None);
+
+        merged.retain(|report| report.rank == b"S");
+        assert_eq!(merged.taxa.len(), 2);
+        assert_eq!(merged.index[&b"1280"[..]], 1);
+        assert_eq!(merged.rows, vec![0, 1]);
+        assert_eq!(merged.cols, vec![0, 1]);
+        assert_eq!(merged.total_reads, vec![7, 4]);
+        Ok(())
+    }
+}
@@ -1,17 +1,30 @@
 use extendr_api::prelude::*;
 
+mod bam_reader;
 mod batchsender;
+mod bgzf;
 mod fastq_reader;
 mod fastq_record;
+mod koutput_kreport;
 mod koutput_reads;
 mod kractor;
 mod krcount;
 mod kreport;
+mod kreport_merge;
+mod mmap_reader;
+mod paired_reader;
+mod parallel_gzip;
+mod quarantine;
+mod read_id;
 mod reader;
 mod seq_range;
 mod seq_refine;
+mod selector;
 mod seq_tag;
+mod subsample;
+mod taxonomy;
 pub(crate) mod utils;
+mod validate;
 
 // https://extendr.github.io/extendr/extendr_api/#returning-resultt-e-to-r
 // https://github.com/extendr/extendr/blob/master/extendr-api/src/robj/into_robj.rs#L100
@@ -31,8 +44,12 @@ pub(crate) mod utils;
 extendr_module! {
     mod mire;
     use kreport;
+    use kreport_merge;
     use seq_refine;
+    use koutput_kreport;
     use koutput_reads;
     use krcount;
     use kractor;
+    use taxonomy;
+    use validate;
 }
@@ -0,0 +1,278 @@
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{anyhow, Context, Result};
+use memchr::memchr;
+use memmap2::Mmap;
+
+use crate::bam_reader::BAM_MAGIC;
+use crate::fastq_reader::{FastqReader, ParseOptions};
+use crate::reader::LineReader;
+use crate::utils::*;
+
+/// Where a memory-mapped input is split into ranges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Boundary {
+    /// Any line, for line-oriented inputs like Kraken2 output
+    Line,
+    /// The header line of a FASTQ or FASTA record
+    Record,
+}
+
+/// MmapInput: An uncompressed input file mapped into memory.
+///
+/// The file is split into byte ranges of about `range_size` bytes ending at
+/// a [`Boundary`], so that several reader threads can parse it at once
+/// instead of a single thread feeding the parsers. Ranges are taken in order
+/// with [`MmapInput::next_range`], their index numbers the batch sent to the
+/// parser threads, which keeps the ordered output mode working.
+pub(crate) struct MmapInput {
+    name: String,
+    mmap: Mmap,
+    ranges: Vec<Range<usize>>,
+    next: AtomicUsize,
+}
+
+impl MmapInput {
+    /// Maps `path` as chosen by `mmap`: `Some(false)` never maps the file,
+    /// `None` maps uncompressed regular files, and `Some(true)` fails for
+    /// inputs that cannot be mapped: the standard input, named pipes,
+    /// compressed and BAM files.
+    pub(crate) fn open(
+        path: &Path,
+        mmap: Option<bool>,
+        boundary: Boundary,
+        range_size: usize,
+    ) -> Result<Option<Self>> {
+        if mmap == Some(false) {
+            return Ok(None);
+        }
+        let unsupported = |reason: &str| -> Result<Option<Self>> {
+            if mmap == Some(true) {
+                Err(anyhow!(
+                    "Cannot memory-map {}: {}",
+                    path.display(),
+                    reason
+                ))
+            } else {
+                Ok(None)
+            }
+        };
+        if is_stdio(path) {
+            return unsupported("the standard input is a stream");
+        }
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open file: {}", path.display()))?;
+        let metadata = file
+            .metadata()
+            .with_context(|| format!("Failed to read file: {}", path.display()))?;
+        if !metadata.is_file() {
+            return unsupported("not a regular file");
+        }
+        // Nothing to split, the reader handles empty files
+        if metadata.len() == 0 {
+            return Ok(None);
+        }
+        // SAFETY: the mapping stays valid while the file is truncated or
+        // modified by another process, reading it then gives undefined
+        // results, as it would with any reader.
+        let mmap = unsafe { Mmap::map(&file) }
+            .with_context(|| format!("Failed to memory-map file: {}", path.display()))?;
+        if is_compressed(&mmap) {
+            return unsupported("the file is compressed");
+        }
+        if boundary == Boundary::Record && mmap.starts_with(BAM_MAGIC) {
+            return unsupported("BAM records are binary");
+        }
+        let ranges = split_ranges(&mmap, boundary, range_size);
+        Ok(Some(Self {
+            name: path.display().to_string(),
+            mmap,
+            ranges,
+            next: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Takes the next range not read by any thread yet, with its index
+    pub(crate) fn next_range(&self) -> Option<(usize, Range<usize>)> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        self.ranges.get(index).map(|range| (index, range.clone()))
+    }
+
+    pub(crate) fn line_reader(&self, range: Range<usize>) -> LineReader<&[u8]> {
+        LineReader::with_capacity(BUFFER_SIZE, &self.mmap[range])
+    }
+
+    /// Reads the records of `range`, line numbers of malformed records are
+    /// still counted from the start of the file
+    pub(crate) fn fastq_reader<'a>(
+        &'a self,
+        range: Range<usize>,
+        parse_options: ParseOptions<'a>,
+    ) -> FastqReader<'a, &'a [u8]> {
+        let mut reader = FastqReader::with_options(
+            BUFFER_SIZE,
+            &self.mmap[range.start .. range.end],
+            parse_options,
+        );
+        reader.set_name(self.name.clone());
+        reader.set_preceding(&self.mmap[.. range.start]);
+        reader
+    }
+}
+
+/// Splits `data` into ranges of at least `range_size` bytes, each but the
+/// last one ending right before a `boundary`
+fn split_ranges(data: &[u8], boundary: Boundary, range_size: usize) -> Vec<Range<usize>> {
+    let range_size = range_size.max(1);
+    // The format is detected from the first header, as in `FastqReader`
+    let fasta = data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'>');
+    let mut ranges = Vec::with_capacity(data.len() / range_size + 1);
+    let mut start = 0;
+    while start < data.len() {
+        let end = if data.len() - start <= range_size {
+            data.len()
+        } else {
+            let line = line_start(data, start + range_size);
+            match boundary {
+                Boundary::Line => line,
+                Boundary::Record => record_start(data, line, fasta),
+            }
+        };
+        ranges.push(start .. end);
+        start = end;
+    }
+    ranges
+}
+
+/// Start of the first line beginning at or after `pos`
+fn line_start(data: &[u8], pos: usize) -> usize {
+    memchr(b'\n', &data[pos - 1 ..]).map_or(data.len(), |i| pos + i)
+}
+
+/// Start of the line following the one starting at `line`
+fn next_line(data: &[u8], line: usize) -> usize {
+    memchr(b'\n', &data[line ..]).map_or(data.len(), |i| line + i + 1)
+}
+
+/// Start of the first record header beginning at or after `line`. A FASTQ
+/// quality line may start with `@` as well, so a header must also be
+/// followed by a `+` separator two lines below.
+fn record_start(data: &[u8], mut line: usize, fasta: bool) -> usize {
+    while line < data.len() {
+        let header = if fasta {
+            data[line] == b'>'
+        } else {
+            data[line] == b'@' && {
+                let sep = next_line(data, next_line(data, line));
+                data.get(sep) == Some(&b'+')
+            }
+        };
+        if header {
+            return line;
+        }
+        line = next_line(data, line);
+    }
+    data.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::fastq_record::FastqParseError;
+    use crate::quarantine::Quarantine;
+
+    #[test]
+    fn test_split_ranges() {
+        let data = b"a\tr1\nb\tr2\nc\tr3\n";
+        assert_eq!(split_ranges(data, Boundary::Line, 4), vec![0 .. 5, 5 .. 10, 10 .. 15]);
+        assert_eq!(split_ranges(data, Boundary::Line, 5), vec![0 .. 5, 5 .. 10, 10 .. 15]);
+        assert_eq!(split_ranges(data, Boundary::Line, 6), vec![0 .. 10, 10 .. 15]);
+        assert_eq!(split_ranges(data, Boundary::Line, 100), vec![0 .. 15]);
+
+        // Quality lines starting with '@' are not taken for headers
+        let data = b"@r1\nAC\n+\n@@\n@r2\nGT\n+\n@I\n";
+        assert_eq!(split_ranges(data, Boundary::Record, 10), vec![0 .. 12, 12 .. 24]);
+        assert_eq!(split_ranges(data, Boundary::Record, 1), vec![0 .. 12, 12 .. 24]);
+
+        let data = b">r1\nAC\nGT\n>r2\nAC\n";
+        assert_eq!(split_ranges(data, Boundary::Record, 1), vec![0 .. 10, 10 .. 17]);
+    }
+
+    #[test]
+    fn test_mmap_input() -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        let records = (0 .. 100)
+            .map(|i| format!("@r{}\nACGT\n+\nIIII\n", i))
+            .collect::<String>();
+        file.write_all(records.as_bytes())?;
+        file.flush()?;
+
+        assert!(MmapInput::open(file.path(), Some(false), Boundary::Record, 64)?.is_none());
+        let input = MmapInput::open(file.path(), None, Boundary::Record, 64)?.unwrap();
+        let mut ids = Vec::new();
+        let mut expected_index = 0;
+        while let Some((index, range)) = input.next_range() {
+            assert_eq!(index, expected_index);
+            expected_index += 1;
+            let mut reader = input.fastq_reader(range, ParseOptions::default());
+            while let Some(record) = reader.read_record()? {
+                ids.push(String::from_utf8(record.id.to_vec())?);
+            }
+        }
+        assert!(expected_index > 1);
+        assert_eq!(ids, (0 .. 100).map(|i| format!("r{}", i)).collect::<Vec<_>>());
+
+        // Compressed files are read with the reader
+        let mut gzip = tempfile::NamedTempFile::new()?;
+        gzip.write_all(&[0x1f, 0x8b, 0x08, 0x00])?;
+        gzip.flush()?;
+        assert!(MmapInput::open(gzip.path(), None, Boundary::Line, 64)?.is_none());
+        assert!(MmapInput::open(gzip.path(), Some(true), Boundary::Line, 64).is_err());
+        assert!(MmapInput::open(Path::new("-"), Some(true), Boundary::Line, 64).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mmap_line_numbers() -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(b"@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nIIII\n@r3\nACGT\n+\nIII\n")?;
+        file.flush()?;
+        let input = MmapInput::open(file.path(), Some(true), Boundary::Record, 16)?.unwrap();
+        let mut error = None;
+        while let Some((_, range)) = input.next_range() {
+            let mut reader = input.fastq_reader(range, ParseOptions::default());
+            if let Err(e) = reader.read_record() {
+                error = Some(e);
+            }
+        }
+        match error.unwrap().downcast::<FastqParseError>()? {
+            FastqParseError::UnequalLength { pos, .. } => assert_eq!(pos, 12),
+            error => panic!("unexpected error: {}", error),
+        }
+
+        // In lenient mode, the quarantine reports the line numbers as well
+        let quarantine = Quarantine::new(None, None)?;
+        let options = ParseOptions {
+            quarantine: Some(&quarantine),
+            ..Default::default()
+        };
+        let input = MmapInput::open(file.path(), Some(true), Boundary::Record, 16)?.unwrap();
+        let mut ids = Vec::new();
+        while let Some((_, range)) = input.next_range() {
+            let mut reader = input.fastq_reader(range, options);
+            while let Some(record) = reader.read_record()? {
+                ids.push(record.id);
+            }
+        }
+        assert_eq!(ids.len(), 2);
+        assert!(quarantine.first_error().unwrap().contains("(line: 12)"));
+        Ok(())
+    }
+}
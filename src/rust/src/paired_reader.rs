@@ -0,0 +1,518 @@
+use std::path::PathBuf;
+use std::thread::{Scope, ScopedJoinHandle};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, SendError, Sender};
+use indicatif::ProgressBar;
+
+use crate::bam_reader::SequenceReader;
+use crate::batchsender::BatchSender;
+use crate::fastq_reader::*;
+use crate::fastq_record::FastqRecord;
+use crate::subsample::{Subsample, Subsampler};
+use crate::utils::*;
+
+/// A batch of mate 1 records and the batch of their mate 2 records, in the
+/// same order.
+pub(crate) type RecordPairs = (Vec<FastqRecord<Bytes>>, Vec<FastqRecord<Bytes>>);
+
+/// Source of paired-end records. Each mate may be split into several files,
+/// read in order.
+pub(crate) enum PairedInput<'a> {
+    /// Mate 1 and mate 2 records in separate files.
+    Split {
+        input1: &'a [PathBuf],
+        input1_bar: Option<ProgressBar>,
+        input2: &'a [PathBuf],
+        input2_bar: Option<ProgressBar>,
+    },
+    /// Mate 1 and mate 2 records alternating in the same files.
+    Interleaved {
+        input: &'a [PathBuf],
+        input_bar: Option<ProgressBar>,
+    },
+}
+
+/// Handles of the threads spawned by [`spawn_paired_reader`].
+pub(crate) struct PairedReaderHandles<'scope> {
+    handles: Vec<(&'static str, ScopedJoinHandle<'scope, Result<()>>)>,
+}
+
+impl<'scope> PairedReaderHandles<'scope> {
+    pub(crate) fn join(self) -> Result<()> {
+        for (name, handle) in self.handles {
+            handle
+                .join()
+                .map_err(|e| anyhow!("({}) thread panicked: {:?}", name, e))??;
+        }
+        Ok(())
+    }
+}
+
+/// Spawns the reader threads for paired-end input. Batches of record pairs
+/// are sent to `reader_tx` in input order, numbered from zero, each holding at
+/// most `batch_size` pairs. In lenient mode, the mate of a skipped malformed
+/// record is dropped as well. With a `subsample`, only the drawn pairs are
+/// sent.
+pub(crate) fn spawn_paired_reader<'scope, 'env>(
+    scope: &'scope Scope<'scope, 'env>,
+    input: PairedInput<'env>,
+    parse_options: ParseOptions<'env>,
+    subsample: Option<Subsample>,
+    batch_size: usize,
+    nqueue: Option<usize>,
+    reader_tx: Sender<(usize, RecordPairs)>,
+) -> PairedReaderHandles<'scope> {
+    let handles = match input {
+        PairedInput::Split {
+            input1,
+            input1_bar,
+            input2,
+            input2_bar,
+        } => {
+            // Skipped records are sent as `None`, so that mates stay aligned
+            let (reader1_tx, reader1_rx): (
+                Sender<(usize, Vec<Option<FastqRecord<Bytes>>>)>,
+                Receiver<(usize, Vec<Option<FastqRecord<Bytes>>>)>,
+            ) = new_channel(nqueue);
+            let (reader2_tx, reader2_rx): (
+                Sender<(usize, Vec<Option<FastqRecord<Bytes>>>)>,
+                Receiver<(usize, Vec<Option<FastqRecord<Bytes>>>)>,
+            ) = new_channel(nqueue);
+
+            // Pairs the batches of the two readers, both readers use the same
+            // batch size, so the n-th batches always hold the same mates
+            let collect_handle = scope.spawn(move || -> Result<()> {
+                let mut sampler = Subsampler::new(subsample);
+                let mut pairs_tx = PairSender::new(batch_size, reader_tx);
+                loop {
+                    let (records1, records2) = match (reader1_rx.recv(), reader2_rx.recv()) {
+                        (Ok((_, rec1)), Ok((_, rec2))) => (rec1, rec2),
+                        (Err(_), Ok(_)) => {
+                            return Err(anyhow!(
+                                "(Reader collect) FASTQ pairing error: read1 channel closed before read2"
+                            ));
+                        }
+                        (Ok(_), Err(_)) => {
+                            return Err(anyhow!(
+                                "(Reader collect) FASTQ pairing error: read2 channel closed before read1"
+                            ));
+                        }
+                        (Err(_), Err(_)) => {
+                            break;
+                        }
+                    };
+                    if records1.len() != records2.len() {
+                        return Err(anyhow!("(Reader collect) FASTQ pairing error: record count mismatch (read1: {}, read2: {})", records1.len(), records2.len()));
+                    }
+                    let pairs = records1
+                        .into_iter()
+                        .zip(records2)
+                        .filter_map(|(record1, record2)| record1.zip(record2));
+                    for pair in pairs {
+                        if let Some(pair) = sampler.offer(pair) {
+                            pairs_tx.send(pair).with_context(|| {
+                                format!(
+                                    "(Reader collect) Failed to send parsed record pair to Parser thread"
+                                )
+                            })?;
+                        }
+                    }
+                }
+                for pair in sampler.finish() {
+                    pairs_tx.send(pair).with_context(|| {
+                        format!("(Reader collect) Failed to send parsed record pair to Parser thread")
+                    })?;
+                }
+                pairs_tx.flush().with_context(|| {
+                    format!("(Reader collect) Failed to flush record pairs to Parser thread")
+                })?;
+                Ok(())
+            });
+            let reader1_handle = spawn_mate_reader(
+                scope,
+                "Reader1",
+                input1,
+                input1_bar,
+                parse_options,
+                batch_size,
+                reader1_tx,
+            );
+            let reader2_handle = spawn_mate_reader(
+                scope,
+                "Reader2",
+                input2,
+                input2_bar,
+                parse_options,
+                batch_size,
+                reader2_tx,
+            );
+            vec![
+                ("Reader collect", collect_handle),
+                ("Reader1", reader1_handle),
+                ("Reader2", reader2_handle),
+            ]
+        }
+        PairedInput::Interleaved { input, input_bar } => {
+            let handle = scope.spawn(move || -> Result<()> {
+                let mut reader = SequenceReader::open(input, input_bar, parse_options)?;
+                let mut sampler = Subsampler::new(subsample);
+                let mut pairs_tx = PairSender::new(batch_size, reader_tx);
+                while let Some(record1) = reader
+                    .read_or_skip()
+                    .with_context(|| format!("(Reader) Failed to read FASTQ record"))?
+                {
+                    let read1_pos = reader.offset();
+                    let record2 = reader
+                        .read_or_skip()
+                        .with_context(|| format!("(Reader) Failed to read FASTQ record"))?;
+                    let (record1, record2) = match (record1, record2) {
+                        (Some(record1), Some(Some(record2))) => (record1, record2),
+                        (Some(record1), None) => {
+                            return Err(anyhow!(
+                                "(Reader) Interleaved FASTQ ends with an unpaired record: {}",
+                                String::from_utf8_lossy(&record1.id)
+                            ));
+                        }
+                        // A skipped malformed record at the end has no mate
+                        (None, None) => break,
+                        // A pair with a skipped mate is dropped
+                        _ => continue,
+                    };
+                    parse_options.id_normalizer.check_pair(
+                        &record1,
+                        &record2,
+                        Some(read1_pos),
+                        Some(reader.offset()),
+                    )?;
+                    if let Some(pair) = sampler.offer((record1, record2)) {
+                        pairs_tx.send(pair).with_context(|| {
+                            format!("(Reader) Failed to send FASTQ record pair to Parser thread")
+                        })?;
+                    }
+                }
+                for pair in sampler.finish() {
+                    pairs_tx.send(pair).with_context(|| {
+                        format!("(Reader) Failed to send FASTQ record pair to Parser thread")
+                    })?;
+                }
+                pairs_tx.flush().with_context(|| {
+                    format!("(Reader) Failed to flush FASTQ record pairs to Parser thread")
+                })?;
+                Ok(())
+            });
+            vec![("Reader", handle)]
+        }
+    };
+    PairedReaderHandles { handles }
+}
+
+/// Groups record pairs into batches of [`RecordPairs`] numbered in sending
+/// order, as [`BatchSender`] does for single records
+struct PairSender {
+    records1: Vec<FastqRecord<Bytes>>,
+    records2: Vec<FastqRecord<Bytes>>,
+    tx: Sender<(usize, RecordPairs)>,
+    capacity: usize,
+    index: usize,
+}
+
+impl PairSender {
+    fn new(capacity: usize, tx: Sender<(usize, RecordPairs)>) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            records1: Vec::with_capacity(capacity),
+            records2: Vec::with_capacity(capacity),
+            tx,
+            capacity,
+            index: 0,
+        }
+    }
+
+    fn send(
+        &mut self,
+        (record1, record2): (FastqRecord<Bytes>, FastqRecord<Bytes>),
+    ) -> Result<(), SendError<(usize, RecordPairs)>> {
+        self.records1.push(record1);
+        self.records2.push(record2);
+        if self.records1.len() >= self.capacity {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SendError<(usize, RecordPairs)>> {
+        if !self.records1.is_empty() {
+            let pack1 = std::mem::replace(&mut self.records1, Vec::with_capacity(self.capacity));
+            let pack2 = std::mem::replace(&mut self.records2, Vec::with_capacity(self.capacity));
+            self.tx.send((self.index, (pack1, pack2)))?;
+            self.index += 1;
+        }
+        Ok(())
+    }
+}
+
+fn spawn_mate_reader<'scope, 'env>(
+    scope: &'scope Scope<'scope, 'env>,
+    name: &'static str,
+    input: &'env [PathBuf],
+    input_bar: Option<ProgressBar>,
+    parse_options: ParseOptions<'env>,
+    batch_size: usize,
+    tx: Sender<(usize, Vec<Option<FastqRecord<Bytes>>>)>,
+) -> ScopedJoinHandle<'scope, Result<()>> {
+    scope.spawn(move || -> Result<()> {
+        let mut reader = SequenceReader::open(input, input_bar, parse_options)?;
+        let mut thread_tx = BatchSender::with_capacity(batch_size, tx);
+        while let Some(record) = reader
+            .read_or_skip()
+            .with_context(|| format!("({}) Failed to read FASTQ record", name))?
+        {
+            thread_tx.send(record).with_context(|| {
+                format!(
+                    "({}) Failed to send FASTQ record to reader collect thread",
+                    name
+                )
+            })?;
+        }
+        thread_tx.flush().with_context(|| {
+            format!(
+                "({}) Failed to flush records to reader collect thread",
+                name
+            )
+        })?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quarantine::Quarantine;
+
+    fn collect_pairs(input: PairedInput<'_>, batch_size: usize) -> Result<Vec<RecordPairs>> {
+        collect_pairs_with(input, ParseOptions::default(), batch_size)
+    }
+
+    fn collect_pairs_with<'a>(
+        input: PairedInput<'a>,
+        parse_options: ParseOptions<'a>,
+        batch_size: usize,
+    ) -> Result<Vec<RecordPairs>> {
+        collect_subsample(input, parse_options, None, batch_size)
+    }
+
+    fn collect_subsample<'a>(
+        input: PairedInput<'a>,
+        parse_options: ParseOptions<'a>,
+        subsample: Option<Subsample>,
+        batch_size: usize,
+    ) -> Result<Vec<RecordPairs>> {
+        std::thread::scope(|scope| {
+            let (tx, rx): (Sender<(usize, RecordPairs)>, Receiver<(usize, RecordPairs)>) =
+                new_channel(None);
+            let handles =
+                spawn_paired_reader(scope, input, parse_options, subsample, batch_size, None, tx);
+            let mut batches = Vec::new();
+            for (index, pairs) in rx {
+                assert_eq!(index, batches.len());
+                batches.push(pairs);
+            }
+            handles.join()?;
+            Ok(batches)
+        })
+    }
+
+    #[test]
+    fn test_interleaved_pairs() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("reads.fq");
+        std::fs::write(
+            &path,
+            b"@r1/1\nAC\n+\n!!\n@r1/2\nGT\n+\n##\n@r2\nAA\n+\n!!\n@r2\nTT\n+\n##\n@r3\nCC\n+\n!!\n@r3\nGG\n+\n##\n",
+        )?;
+        let batches = collect_pairs(
+            PairedInput::Interleaved {
+                input: std::slice::from_ref(&path),
+                input_bar: None,
+            },
+            2,
+        );
+        // IDs are compared up to the first space, so `r1/1` and `r1/2` differ
+        assert!(batches.is_err());
+
+        std::fs::write(
+            &path,
+            b"@r1\nAC\n+\n!!\n@r1\nGT\n+\n##\n@r2\nAA\n+\n!!\n@r2\nTT\n+\n##\n@r3\nCC\n+\n!!\n@r3\nGG\n+\n##\n",
+        )?;
+        let batches = collect_pairs(
+            PairedInput::Interleaved {
+                input: std::slice::from_ref(&path),
+                input_bar: None,
+            },
+            2,
+        )?;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].0.len(), 2);
+        assert_eq!(batches[1].0.len(), 1);
+        let (mates1, mates2): (Vec<_>, Vec<_>) = batches.into_iter().unzip();
+        let seqs1 = mates1
+            .into_iter()
+            .flatten()
+            .map(|r| r.seq)
+            .collect::<Vec<_>>();
+        let seqs2 = mates2
+            .into_iter()
+            .flatten()
+            .map(|r| r.seq)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs1, vec!["AC", "AA", "CC"]);
+        assert_eq!(seqs2, vec!["GT", "TT", "GG"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_interleaved_unpaired_tail() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("reads.fq");
+        std::fs::write(&path, b"@r1\nAC\n+\n!!\n@r1\nGT\n+\n##\n@r2\nAA\n+\n!!\n")?;
+        let err = collect_pairs(
+            PairedInput::Interleaved {
+                input: std::slice::from_ref(&path),
+                input_bar: None,
+            },
+            4,
+        )
+        .unwrap_err();
+        assert!(format!("{}", err).contains("unpaired record: r2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lenient_drops_mates() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path1 = tmp.path().join("reads_1.fq");
+        let path2 = tmp.path().join("reads_2.fq");
+        // r2 mate 1 has a short quality, r3 mate 2 is truncated
+        std::fs::write(&path1, b"@r1\nAC\n+\n!!\n@r2\nAA\n+\n!\n@r3\nCC\n+\n!!\n")?;
+        std::fs::write(&path2, b"@r1\nGT\n+\n##\n@r2\nTT\n+\n##\n@r3\nG")?;
+        let quarantine = Quarantine::new(None, None)?;
+        let parse_options = ParseOptions {
+            quarantine: Some(&quarantine),
+            ..Default::default()
+        };
+        let batches = collect_pairs_with(
+            PairedInput::Split {
+                input1: std::slice::from_ref(&path1),
+                input1_bar: None,
+                input2: std::slice::from_ref(&path2),
+                input2_bar: None,
+            },
+            parse_options,
+            2,
+        )?;
+        let ids = batches
+            .into_iter()
+            .flat_map(|(mates1, mates2)| mates1.into_iter().zip(mates2))
+            .map(|(mate1, mate2)| (mate1.id, mate2.id))
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![(Bytes::from("r1"), Bytes::from("r1"))]);
+        assert_eq!(quarantine.counts(), [0, 0, 1, 1, 0, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_lanes() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let mut lanes1 = Vec::new();
+        let mut lanes2 = Vec::new();
+        for lane in 1 ..= 2 {
+            let path1 = tmp.path().join(format!("reads_L00{}_R1.fq", lane));
+            let path2 = tmp.path().join(format!("reads_L00{}_R2.fq", lane));
+            std::fs::write(&path1, format!("@l{0}r1\nAC\n+\n!!\n@l{0}r2\nAA\n+\n!!\n", lane))?;
+            std::fs::write(&path2, format!("@l{0}r1\nGT\n+\n##\n@l{0}r2\nTT\n+\n##\n", lane))?;
+            lanes1.push(path1);
+            lanes2.push(path2);
+        }
+        let batches = collect_pairs(
+            PairedInput::Split {
+                input1: &lanes1,
+                input1_bar: None,
+                input2: &lanes2,
+                input2_bar: None,
+            },
+            3,
+        )?;
+        let ids = batches
+            .into_iter()
+            .flat_map(|(mates1, mates2)| mates1.into_iter().zip(mates2))
+            .map(|(mate1, mate2)| {
+                assert_eq!(mate1.id, mate2.id);
+                String::from_utf8_lossy(&mate1.id).to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["l1r1", "l1r2", "l2r1", "l2r2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_subsample_pairs() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path1 = tmp.path().join("reads_1.fq");
+        let path2 = tmp.path().join("reads_2.fq");
+        let interleaved = tmp.path().join("reads.fq");
+        let mut mates1 = String::new();
+        let mut mates2 = String::new();
+        let mut mates = String::new();
+        for i in 0 .. 200 {
+            let mate1 = format!("@r{}\nAC\n+\n!!\n", i);
+            let mate2 = format!("@r{}\nGT\n+\n##\n", i);
+            mates.push_str(&mate1);
+            mates.push_str(&mate2);
+            mates1.push_str(&mate1);
+            mates2.push_str(&mate2);
+        }
+        std::fs::write(&path1, mates1)?;
+        std::fs::write(&path2, mates2)?;
+        std::fs::write(&interleaved, mates)?;
+        let ids = |batches: Vec<RecordPairs>| {
+            batches
+                .into_iter()
+                .flat_map(|(mates1, mates2)| mates1.into_iter().zip(mates2))
+                .map(|(mate1, mate2)| {
+                    assert_eq!(mate1.id, mate2.id);
+                    assert_eq!(mate2.seq, "GT");
+                    mate1.id
+                })
+                .collect::<Vec<_>>()
+        };
+        for size in [0.2, 50.0] {
+            let subsample = Subsample::parse(Some(size), 11)?;
+            let split = collect_subsample(
+                PairedInput::Split {
+                    input1: std::slice::from_ref(&path1),
+                    input1_bar: None,
+                    input2: std::slice::from_ref(&path2),
+                    input2_bar: None,
+                },
+                ParseOptions::default(),
+                subsample,
+                16,
+            )?;
+            let interleaved = collect_subsample(
+                PairedInput::Interleaved {
+                    input: std::slice::from_ref(&interleaved),
+                    input_bar: None,
+                },
+                ParseOptions::default(),
+                subsample,
+                16,
+            )?;
+            let split = ids(split);
+            assert!(!split.is_empty() && split.len() < 200);
+            assert_eq!(split, ids(interleaved));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,532 @@
+use std::io::{BufReader, Cursor, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use flate2::bufread::MultiGzDecoder;
+use libdeflater::Decompressor;
+use memchr::memmem;
+
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_FOOTER_SIZE: usize = 8;
+
+/// Target size of the compressed data decoded by one job
+const SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Segments without any member boundary are not split further: once larger
+/// than this, the stream is taken for a single-member gzip file and decoded
+/// sequentially
+const MAX_SEGMENT_SIZE: usize = 64 * 1024 * 1024;
+
+/// Upper bound of the deflate compression ratio
+const MAX_DEFLATE_RATIO: usize = 1032;
+
+/// Compressed data starting and ending at gzip member boundaries, along with
+/// the offsets of the candidate member starts within it.
+struct Segment {
+    raw: Vec<u8>,
+    members: Vec<usize>,
+}
+
+/// A decoded segment, the compressed bytes are kept so that decoding can
+/// continue sequentially from this segment if a later one fails.
+struct Decoded {
+    raw: Vec<u8>,
+    data: Option<Vec<u8>>,
+}
+
+type DecodedReceiver = Receiver<std::io::Result<Decoded>>;
+
+fn thread_exited() -> std::io::Error {
+    std::io::Error::other("gzip decoding thread exited unexpectedly")
+}
+
+/// ParallelGzipReader: Decompresses gzip and BGZF streams with several
+/// threads.
+///
+/// A segmenter thread splits the compressed stream into segments at member
+/// boundaries: exact boundaries from the `BC` block size field for BGZF, and
+/// candidate gzip headers otherwise. Worker threads inflate the members of
+/// each segment, and the reader returns the decoded segments in input order.
+///
+/// A candidate header may be a false positive within the deflate data, so
+/// each decoded member is checked against the CRC32 and size of the trailer
+/// that ends it. When a segment cannot be decoded on its own (for instance a
+/// single-member gzip file larger than the segment size), the reader falls
+/// back to sequential decoding from the start of that segment.
+///
+/// `threads` counts the segmenter thread, at least one worker thread is used.
+pub(crate) struct ParallelGzipReader {
+    receivers: Receiver<DecodedReceiver>,
+    fallback: Arc<AtomicBool>,
+    current: Cursor<Vec<u8>>,
+    sequential: Option<MultiGzDecoder<BufReader<SegmentChain>>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl ParallelGzipReader {
+    pub(crate) fn new<R: Read + Send + 'static>(reader: R, threads: usize) -> Self {
+        let threads = threads.saturating_sub(1).max(1);
+        let mut handles = Vec::with_capacity(threads + 1);
+        let fallback = Arc::new(AtomicBool::new(false));
+        let (job_tx, job_rx): (
+            Sender<(Segment, Sender<std::io::Result<Decoded>>)>,
+            Receiver<(Segment, Sender<std::io::Result<Decoded>>)>,
+        ) = bounded(threads * 2);
+        let (order_tx, order_rx): (Sender<DecodedReceiver>, Receiver<DecodedReceiver>) =
+            bounded(threads * 2);
+
+        // ─── Segmenter Thread ──────────────────────────────────
+        {
+            let fallback = fallback.clone();
+            handles.push(std::thread::spawn(move || {
+                let mut segmenter = Segmenter::new(reader, fallback);
+                loop {
+                    let (tx, rx) = bounded(1);
+                    let sent = match segmenter.next_segment() {
+                        Ok(Some(segment)) => {
+                            order_tx.send(rx).is_ok() && job_tx.send((segment, tx)).is_ok()
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            // Hand the error over to the reader in order
+                            let _ = tx.send(Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                format!("{:?}", e),
+                            )));
+                            let _ = order_tx.send(rx);
+                            break;
+                        }
+                    };
+                    // The reader was dropped if any channel is closed
+                    if !sent {
+                        break;
+                    }
+                }
+            }));
+        }
+
+        // ─── Worker Thread ─────────────────────────────────────
+        for _ in 0 .. threads {
+            let rx = job_rx.clone();
+            let fallback = fallback.clone();
+            handles.push(std::thread::spawn(move || {
+                let mut decompressor = Decompressor::new();
+                for (segment, tx) in rx {
+                    let data = if fallback.load(Ordering::Relaxed) {
+                        None
+                    } else {
+                        decode_segment(&segment, &mut decompressor)
+                    };
+                    let _ = tx.send(Ok(Decoded {
+                        raw: segment.raw,
+                        data,
+                    }));
+                }
+            }));
+        }
+
+        Self {
+            receivers: order_rx,
+            fallback,
+            current: Cursor::new(Vec::new()),
+            sequential: None,
+            handles,
+        }
+    }
+
+    /// Waits for the decoding threads, which have all exited or are about to
+    /// once the segments are consumed or the reader is dropped
+    fn join(&mut self) -> std::io::Result<()> {
+        let mut result = Ok(());
+        for handle in self.handles.drain(..) {
+            if handle.join().is_err() {
+                result = Err(thread_exited());
+            }
+        }
+        result
+    }
+
+    /// Moves to the next decoded segment, returns `false` at the end of the
+    /// stream
+    fn next_segment(&mut self) -> std::io::Result<bool> {
+        let decoded = match self.receivers.recv() {
+            Ok(rx) => rx.recv().map_err(|_| thread_exited())??,
+            Err(_) => {
+                self.join()?;
+                return Ok(false);
+            }
+        };
+        match decoded.data {
+            Some(data) => self.current = Cursor::new(data),
+            None => {
+                // Decode this and all following segments sequentially
+                self.fallback.store(true, Ordering::Relaxed);
+                let chain = SegmentChain {
+                    receivers: self.receivers.clone(),
+                    current: Cursor::new(decoded.raw),
+                };
+                self.sequential = Some(MultiGzDecoder::new(BufReader::new(chain)));
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Drop for ParallelGzipReader {
+    fn drop(&mut self) {
+        // Workers skip the remaining segments, and the segmenter stops once
+        // it finds the channel to the reader closed
+        self.fallback.store(true, Ordering::Relaxed);
+        self.sequential = None;
+        self.receivers = crossbeam_channel::never();
+        let _ = self.join();
+    }
+}
+
+impl Read for ParallelGzipReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(decoder) = &mut self.sequential {
+                return decoder.read(buf);
+            }
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            if !self.next_segment()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+/// Concatenates the compressed bytes of the remaining segments
+struct SegmentChain {
+    receivers: Receiver<DecodedReceiver>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for SegmentChain {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.receivers.recv() {
+                Ok(rx) => {
+                    let decoded = rx.recv().map_err(|_| thread_exited())??;
+                    self.current = Cursor::new(decoded.raw);
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+struct Segmenter<R> {
+    reader: R,
+    fallback: Arc<AtomicBool>,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Segmenter<R> {
+    fn new(reader: R, fallback: Arc<AtomicBool>) -> Self {
+        Self {
+            reader,
+            fallback,
+            buffer: Vec::with_capacity(SEGMENT_SIZE * 2),
+            eof: false,
+        }
+    }
+
+    /// Reads more compressed data, returns `false` at the end of the stream
+    fn fill(&mut self) -> Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let start = self.buffer.len();
+        self.buffer.resize(start + SEGMENT_SIZE, 0);
+        let n = loop {
+            match self.reader.read(&mut self.buffer[start ..]) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.buffer.truncate(start);
+                    return Err(e.into());
+                }
+            }
+        };
+        self.buffer.truncate(start + n);
+        self.eof = n == 0;
+        Ok(!self.eof)
+    }
+
+    fn next_segment(&mut self) -> Result<Option<Segment>> {
+        loop {
+            if self.fallback.load(Ordering::Relaxed) {
+                // Sequential decoding only needs the compressed bytes
+                if self.buffer.is_empty() && !self.fill()? {
+                    return Ok(None);
+                }
+                return Ok(Some(self.take(self.buffer.len(), Vec::new())));
+            }
+            if let Some(segment) = self.split()? {
+                return Ok(Some(segment));
+            }
+            if !self.fill()? && self.buffer.is_empty() {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn take(&mut self, end: usize, members: Vec<usize>) -> Segment {
+        let rest = self.buffer.split_off(end);
+        let raw = std::mem::replace(&mut self.buffer, rest);
+        Segment { raw, members }
+    }
+
+    /// Splits the leading segment from the buffer if its end is known
+    fn split(&mut self) -> Result<Option<Segment>> {
+        let len = self.buffer.len();
+        if len == 0 {
+            return Ok(None);
+        }
+        if !is_gzip_header(&self.buffer) {
+            if self.eof || len >= GZIP_HEADER_SIZE {
+                return Err(anyhow!("Invalid gzip data: missing member header"));
+            }
+            return Ok(None);
+        }
+        let mut members = vec![0];
+        let mut pos = 0;
+        loop {
+            let next = match bgzf_block_size(&self.buffer[pos ..]) {
+                // BGZF block: exact boundary
+                Some(size) if pos + size <= len => Some(pos + size),
+                Some(_) => None,
+                None => find_gzip_header(&self.buffer, pos + GZIP_HEADER_SIZE + GZIP_FOOTER_SIZE),
+            };
+            match next {
+                Some(next) => {
+                    pos = next;
+                    if pos >= SEGMENT_SIZE || pos == len {
+                        break;
+                    }
+                    members.push(pos);
+                }
+                None => {
+                    if self.eof {
+                        pos = len;
+                    } else if len >= MAX_SEGMENT_SIZE {
+                        // No member boundary found: skip parallel decoding,
+                        // so that no worker inflates a segment on its own
+                        // that does not end with its member
+                        self.fallback.store(true, Ordering::Relaxed);
+                        pos = len;
+                    } else if pos > 0 {
+                        // The last member may continue in the data not read yet
+                        members.pop();
+                    }
+                    break;
+                }
+            }
+        }
+        if pos == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.take(pos, members)))
+    }
+}
+
+/// Checks the fixed part of a gzip member header
+#[inline]
+fn is_gzip_header(bytes: &[u8]) -> bool {
+    bytes.len() >= GZIP_HEADER_SIZE &&
+        bytes[0] == 0x1f &&
+        bytes[1] == 0x8b &&
+        bytes[2] == 0x08 &&
+        bytes[3] & 0xe0 == 0
+}
+
+/// Returns the total size of a BGZF block starting at `bytes`
+#[inline]
+fn bgzf_block_size(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() >= 18 &&
+        bytes[3] & 0x04 != 0 &&
+        bytes[10 .. 12] == [6, 0] &&
+        bytes[12 .. 16] == [b'B', b'C', 2, 0]
+    {
+        Some(u16::from_le_bytes([bytes[16], bytes[17]]) as usize + 1)
+    } else {
+        None
+    }
+}
+
+/// Finds the next candidate gzip header at or after `from`
+fn find_gzip_header(bytes: &[u8], from: usize) -> Option<usize> {
+    let finder = memmem::Finder::new(&[0x1f, 0x8b, 0x08]);
+    let mut from = from;
+    while from < bytes.len() {
+        let pos = from + finder.find(&bytes[from ..])?;
+        if pos + GZIP_HEADER_SIZE > bytes.len() {
+            return None;
+        }
+        if is_gzip_header(&bytes[pos ..]) {
+            return Some(pos);
+        }
+        from = pos + 1;
+    }
+    None
+}
+
+/// Inflates all members of a segment, `None` if the segment cannot be
+/// decoded on its own.
+fn decode_segment(segment: &Segment, decompressor: &mut Decompressor) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bounds = segment.members[1 ..]
+        .iter()
+        .copied()
+        .chain(std::iter::once(segment.raw.len()))
+        .peekable();
+    let mut start = 0;
+    while let Some(end) = bounds.next() {
+        // A false candidate header splits a member in two, retry with the
+        // next boundary
+        if decode_member(&segment.raw[start .. end], decompressor, &mut out) {
+            start = end;
+        } else if bounds.peek().is_none() {
+            return None;
+        }
+    }
+    Some(out)
+}
+
+/// Inflates one gzip member, appending the decoded bytes to `out`.
+///
+/// The size in the trailer of a false candidate member is arbitrary, so the
+/// output buffer starts from a typical compression ratio and only grows up to
+/// that size while the member inflates to more.
+fn decode_member(member: &[u8], decompressor: &mut Decompressor, out: &mut Vec<u8>) -> bool {
+    if member.len() < GZIP_HEADER_SIZE + GZIP_FOOTER_SIZE {
+        return false;
+    }
+    let footer = &member[member.len() - GZIP_FOOTER_SIZE ..];
+    let crc = u32::from_le_bytes([footer[0], footer[1], footer[2], footer[3]]);
+    let size = u32::from_le_bytes([footer[4], footer[5], footer[6], footer[7]]) as usize;
+    if size > member.len().saturating_mul(MAX_DEFLATE_RATIO) {
+        return false;
+    }
+    let start = out.len();
+    let mut capacity = size.min(member.len().saturating_mul(8));
+    loop {
+        out.resize(start + capacity, 0);
+        match decompressor.gzip_decompress(member, &mut out[start ..]) {
+            Ok(n) if n == size && libdeflater::crc32(&out[start .. start + n]) == crc => {
+                out.truncate(start + n);
+                return true;
+            }
+            Err(libdeflater::DecompressionError::InsufficientSpace) if capacity < size => {
+                capacity = capacity.saturating_mul(2).min(size);
+            }
+            _ => {
+                out.truncate(start);
+                return false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use libdeflater::{CompressionLvl, Compressor};
+
+    use super::*;
+    use crate::bgzf::*;
+    use crate::utils::gzip_pack;
+
+    fn read_all(data: Vec<u8>, threads: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        ParallelGzipReader::new(Cursor::new(data), threads).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn sample(n: usize) -> Vec<u8> {
+        (0 .. n)
+            .flat_map(|i| format!("@read{}\nACGT\n+\nIIII\n", i).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_parallel_bgzf_and_members() -> Result<()> {
+        let data = sample(400_000);
+        let mut compressor = Compressor::new(CompressionLvl::default());
+
+        let mut bgzf = bgzf_pack(&data, &mut compressor)?;
+        bgzf.extend_from_slice(&BGZF_EOF);
+        assert_eq!(read_all(bgzf, 4)?, data);
+
+        let members = data
+            .chunks(1024 * 1024)
+            .map(|chunk| gzip_pack(chunk, &mut compressor))
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+        assert_eq!(read_all(members, 3)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_single_member_fallback() -> Result<()> {
+        // One member spanning several segments, with a fake header inside
+        let mut data = sample(1_500_000);
+        data.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0, 0xff]);
+        data.extend(sample(10));
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&data)?;
+        let gzip = encoder.finish()?;
+        assert!(gzip.len() > SEGMENT_SIZE);
+        assert_eq!(read_all(gzip, 2)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_member_bogus_size() -> Result<()> {
+        let mut compressor = Compressor::new(CompressionLvl::default());
+        let mut member = gzip_pack(&sample(100), &mut compressor)?;
+        let mut decompressor = Decompressor::new();
+        let mut out = Vec::new();
+        assert!(decode_member(&member, &mut decompressor, &mut out));
+        assert_eq!(out, sample(100));
+
+        // A trailer size out of reach of the compressed length is rejected
+        // before any allocation, a wrong one after the member is inflated
+        let n = member.len();
+        member[n - 4 ..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(!decode_member(&member, &mut decompressor, &mut out));
+        member[n - 4 ..].copy_from_slice(&(n as u32 * 100).to_le_bytes());
+        assert!(!decode_member(&member, &mut decompressor, &mut out));
+        assert_eq!(out, sample(100));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_drop_early() -> Result<()> {
+        let data = sample(400_000);
+        let mut compressor = Compressor::new(CompressionLvl::default());
+        let bgzf = bgzf_pack(&data, &mut compressor)?;
+        let mut reader = ParallelGzipReader::new(Cursor::new(bgzf), 4);
+        let mut buf = [0; 16];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(&buf, &data[.. 16]);
+        // Joins the decoding threads
+        drop(reader);
+        Ok(())
+    }
+}
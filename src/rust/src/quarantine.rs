@@ -0,0 +1,147 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use extendr_api::prelude::*;
+
+use crate::fastq_record::FastqParseError;
+
+/// Kinds of malformed records counted in lenient mode, in the order of
+/// [`Quarantine::counts`]
+pub(crate) const BAD_RECORD_KINDS: [&str; 7] = [
+    "invalid_head",
+    "invalid_sep",
+    "unequal_length",
+    "incomplete_record",
+    "invalid_base",
+    "invalid_quality",
+    "sep_mismatch",
+];
+
+/// Number of skipped records of each kind in [`BAD_RECORD_KINDS`]
+pub(crate) type BadRecordCounts = [usize; BAD_RECORD_KINDS.len()];
+
+/// Collects the malformed records skipped in lenient parsing mode, shared by
+/// all reader threads of a run.
+///
+/// Each bad record is written to the quarantine file as a header line
+/// `#<input>:<first line>-<last line>\t<kind>` followed by its raw lines. The
+/// quarantine file is written in place rather than atomically, so that it can
+/// still be inspected when the run is aborted.
+#[derive(Debug)]
+pub(crate) struct Quarantine {
+    path: Option<String>,
+    writer: Option<Mutex<BufWriter<File>>>,
+    counts: [AtomicUsize; BAD_RECORD_KINDS.len()],
+    max_bad: Option<usize>,
+    first_error: OnceLock<String>,
+}
+
+impl Quarantine {
+    pub(crate) fn new(path: Option<&str>, max_bad: Option<usize>) -> Result<Self> {
+        let writer = path
+            .map(|path| {
+                File::create(path)
+                    .with_context(|| format!("Failed to create quarantine file {}", path))
+            })
+            .transpose()?
+            .map(|file| Mutex::new(BufWriter::new(file)));
+        Ok(Self {
+            path: path.map(|path| path.to_string()),
+            writer,
+            counts: Default::default(),
+            max_bad,
+            first_error: OnceLock::new(),
+        })
+    }
+
+    /// Records a skipped record, `lines` are its raw lines starting at line
+    /// number `start` of `input`. Fails once more than `max_bad` records were
+    /// skipped.
+    pub(crate) fn add(
+        &self,
+        input: &str,
+        start: usize,
+        lines: &[Bytes],
+        error: FastqParseError,
+    ) -> Result<()> {
+        let kind = match error {
+            FastqParseError::InvalidHead { .. } => 0,
+            FastqParseError::InvalidSep { .. } => 1,
+            FastqParseError::UnequalLength { .. } => 2,
+            FastqParseError::IncompleteRecord { .. } => 3,
+            FastqParseError::InvalidBase { .. } => 4,
+            FastqParseError::InvalidQuality { .. } => 5,
+            FastqParseError::SepMismatch { .. } => 6,
+            FastqParseError::FastqPairError { .. } => return Err(error.into()),
+        };
+        self.counts[kind].fetch_add(1, Ordering::Relaxed);
+        if self.first_error.get().is_none() {
+            let _ = self.first_error.set(format!("{}: {}", input, error));
+        }
+
+        if let Some(writer) = &self.writer {
+            let mut writer = writer
+                .lock()
+                .map_err(|_| anyhow!("Quarantine writer lock poisoned"))?;
+            let end = start + lines.len().max(1) - 1;
+            writeln!(writer, "#{}:{}-{}\t{}", input, start, end, BAD_RECORD_KINDS[kind])
+                .and_then(|_| {
+                    for line in lines {
+                        writer.write_all(line)?;
+                        writer.write_all(b"\n")?;
+                    }
+                    Ok(())
+                })
+                .with_context(|| format!("Failed to write to quarantine file"))?;
+        }
+
+        let total = self.total();
+        if let Some(max_bad) = self.max_bad {
+            if total > max_bad {
+                return Err(anyhow!(
+                    "Too many malformed records: {} skipped, more than the allowed {}{}",
+                    total,
+                    max_bad,
+                    self.path
+                        .as_ref()
+                        .map_or_else(String::new, |path| format!(" (see {})", path))
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn counts(&self) -> BadRecordCounts {
+        std::array::from_fn(|i| self.counts[i].load(Ordering::Relaxed))
+    }
+
+    /// Message of the first skipped record
+    pub(crate) fn first_error(&self) -> Option<&str> {
+        self.first_error.get().map(|error| error.as_str())
+    }
+
+    pub(crate) fn total(&self) -> usize {
+        self.counts().iter().sum()
+    }
+
+    pub(crate) fn flush(&self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            writer
+                .lock()
+                .map_err(|_| anyhow!("Quarantine writer lock poisoned"))?
+                .flush()
+                .with_context(|| format!("Failed to flush quarantine file"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts the counts of [`Quarantine::counts`] into a named R list
+pub(crate) fn bad_record_counts(counts: BadRecordCounts) -> std::result::Result<List, String> {
+    List::from_names_and_values(BAD_RECORD_KINDS, counts.map(|count| count as f64))
+        .map_err(|e| format!("{:?}", e))
+}
@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use memchr::memchr;
+
+use crate::fastq_record::{FastqParseError, FastqRecord};
+
+/// How read IDs are normalised before mates are paired and before reads are
+/// looked up in the Kraken2 output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ReadIdNormalizer {
+    /// Use the IDs as they are.
+    #[default]
+    Identity,
+    /// Strip a trailing `/1` or `/2` mate suffix.
+    MateSuffix,
+    /// Strip a trailing `.1` or `.2` mate suffix. Not the default for mates,
+    /// since SRA spot numbers such as `SRR001.1` and `SRR001.2` also end so.
+    MateDotSuffix,
+    /// Keep the ID up to the first occurrence of the delimiter.
+    Delimiter(u8),
+}
+
+impl ReadIdNormalizer {
+    /// Parses the R-side value: `NULL` keeps IDs as they are, `"mate"` strips
+    /// `/1`/`/2` mate suffixes, `"mate_dot"` strips `.1`/`.2` mate suffixes and
+    /// a single character cuts IDs at that delimiter.
+    pub(crate) fn parse(value: Option<&str>) -> Result<Self> {
+        match value {
+            None => Ok(Self::Identity),
+            Some("mate") => Ok(Self::MateSuffix),
+            Some("mate_dot") => Ok(Self::MateDotSuffix),
+            Some(delimiter) if delimiter.len() == 1 => Ok(Self::Delimiter(delimiter.as_bytes()[0])),
+            Some(value) => Err(anyhow!(
+                "Invalid 'id_normalize': {} (expected \"mate\", \"mate_dot\" or a single delimiter character)",
+                value
+            )),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn normalize<'a>(&self, id: &'a [u8]) -> &'a [u8] {
+        match self {
+            Self::Identity => id,
+            Self::MateSuffix => strip_mate_suffix(id, b'/'),
+            Self::MateDotSuffix => strip_mate_suffix(id, b'.'),
+            Self::Delimiter(delimiter) => match memchr(*delimiter, id) {
+                Some(pos) => &id[.. pos],
+                None => id,
+            },
+        }
+    }
+
+    /// Checks that both mates carry the same normalised ID.
+    #[inline]
+    pub(crate) fn check_pair(
+        &self,
+        record1: &FastqRecord<Bytes>,
+        record2: &FastqRecord<Bytes>,
+        read1_pos: Option<usize>,
+        read2_pos: Option<usize>,
+    ) -> Result<()> {
+        if self.normalize(&record1.id) != self.normalize(&record2.id) {
+            return Err(anyhow!(
+                "{}",
+                FastqParseError::FastqPairError {
+                    read1_id: String::from_utf8_lossy(&record1.id).to_string(),
+                    read2_id: String::from_utf8_lossy(&record2.id).to_string(),
+                    read1_pos,
+                    read2_pos,
+                }
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+fn strip_mate_suffix(id: &[u8], separator: u8) -> &[u8] {
+    let n = id.len();
+    if n > 2 && id[n - 2] == separator && matches!(id[n - 1], b'1' | b'2') {
+        &id[.. n - 2]
+    } else {
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let mate = ReadIdNormalizer::MateSuffix;
+        assert_eq!(mate.normalize(b"read/1"), b"read");
+        assert_eq!(mate.normalize(b"read/2"), b"read");
+        assert_eq!(mate.normalize(b"read/3"), b"read/3");
+        assert_eq!(mate.normalize(b"/1"), b"/1");
+        // Distinct SRA spots stay distinct
+        assert_eq!(mate.normalize(b"SRR001.1"), b"SRR001.1");
+        assert_ne!(mate.normalize(b"SRR001.1"), mate.normalize(b"SRR001.2"));
+
+        let mate_dot = ReadIdNormalizer::parse(Some("mate_dot")).unwrap();
+        assert_eq!(mate_dot.normalize(b"read.2"), b"read");
+        assert_eq!(mate_dot.normalize(b"read/2"), b"read/2");
+        assert_eq!(mate_dot.normalize(b"SRR001.12"), b"SRR001.12");
+
+        let delimiter = ReadIdNormalizer::parse(Some("_")).unwrap();
+        assert_eq!(delimiter.normalize(b"read_1_x"), b"read");
+        assert_eq!(delimiter.normalize(b"read"), b"read");
+
+        assert_eq!(ReadIdNormalizer::Identity.normalize(b"read/1"), b"read/1");
+        assert!(ReadIdNormalizer::parse(Some("ab")).is_err());
+    }
+
+    #[test]
+    fn test_check_pair() {
+        let record1 = FastqRecord::fasta(Bytes::from("r1/1"), None, Bytes::from("A"));
+        let record2 = FastqRecord::fasta(Bytes::from("r1/2"), None, Bytes::from("A"));
+        assert!(ReadIdNormalizer::Identity
+            .check_pair(&record1, &record2, None, None)
+            .is_err());
+        assert!(ReadIdNormalizer::MateSuffix
+            .check_pair(&record1, &record2, None, None)
+            .is_ok());
+    }
+}
@@ -0,0 +1,502 @@
+use anyhow::{anyhow, Context, Result};
+use regex::bytes::{Regex, RegexBuilder};
+
+use crate::kreport::Kreport;
+use crate::taxonomy::rank_order_key;
+
+// Taxon selectors, as accepted by the `taxonomy` argument:
+//
+// selector  := ["!"] (ranks | [ranks "<"] pattern) ["@" condition {"," condition}]
+// pattern   := ["="] (taxid | ranks "__" name)
+// ranks     := "*" | rank {"," rank}, rank := code | code "-" code
+// name      := text | "~" text | "/" regex "/"
+// condition := ("reads" | "percent") ">=" number
+//
+// A pattern selects the clade of the taxa it matches: the taxa with a
+// matching taxon in their lineage, or only the matching taxa themselves with
+// "=". The ranks before "<" (or alone) restrict the rank of the selected
+// taxa themselves. Names match exactly, case-insensitively with "~", or by
+// regex search with "/.../". Conditions are thresholds on the clade reads and
+// percent of the kraken report. A selector starting with "!" excludes taxa.
+// Names and regexes may hold "<" and "@": "<" only ends a list of ranks, and
+// "@" only starts the conditions when followed by "reads" or "percent", after
+// the closing "/" of a regex.
+
+/// Selection: The union of the included selectors, minus the excluded ones.
+/// Without any included selector, all taxa are included.
+#[derive(Debug)]
+pub(crate) struct Selection {
+    include: Vec<Selector>,
+    exclude: Vec<Selector>,
+}
+
+impl Selection {
+    pub(crate) fn parse<S: AsRef<str>>(selectors: &[S]) -> Result<Self> {
+        Self::new(
+            selectors
+                .iter()
+                .map(|s| Selector::parse(s.as_ref()))
+                .collect::<Result<Vec<_>>>()?,
+        )
+    }
+
+    pub(crate) fn new(selectors: Vec<Selector>) -> Result<Self> {
+        if selectors.is_empty() {
+            return Err(anyhow!("No taxon selector provided"));
+        }
+        let (exclude, include) = selectors.into_iter().partition(|s| s.negate);
+        Ok(Self { include, exclude })
+    }
+
+    pub(crate) fn matches(&self, kr: &Kreport) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|s| s.matches(kr)))
+            && !self.exclude.iter().any(|s| s.matches(kr))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Selector {
+    negate: bool,
+    ranks: Option<Ranks>,
+    pattern: Option<Pattern>,
+    conditions: Vec<Condition>,
+}
+
+impl Selector {
+    pub(crate) fn parse(selector: &str) -> Result<Self> {
+        Self::parse_inner(selector.trim())
+            .with_context(|| format!("Invalid taxon selector: '{}'", selector))
+    }
+
+    fn parse_inner(selector: &str) -> Result<Self> {
+        let (negate, selector) = match selector.strip_prefix('!') {
+            Some(selector) => (true, selector.trim_start()),
+            None => (false, selector),
+        };
+        // The ranks before "<" hold none of the characters of a pattern
+        let (ranks, selector) = match selector.split_once('<') {
+            Some((ranks, pattern))
+                if ranks
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b" ,-*".contains(&b)) =>
+            {
+                (Some(ranks.trim()), pattern.trim_start())
+            }
+            _ => (None, selector),
+        };
+        let (body, conditions) = match split_conditions(selector) {
+            Some((body, conditions)) => (
+                body.trim(),
+                conditions
+                    .split(',')
+                    .map(Condition::parse)
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            None => (selector, Vec::new()),
+        };
+        let (ranks, pattern) = match ranks {
+            Some(ranks) => (Ranks::parse(ranks)?, Some(Pattern::parse(body)?)),
+            None if body.contains("__")
+                || body.starts_with('=')
+                || (!body.is_empty() && body.bytes().all(|b| b.is_ascii_digit())) =>
+            {
+                (None, Some(Pattern::parse(body)?))
+            }
+            None => (Ranks::parse(body)?, None),
+        };
+        Ok(Self {
+            negate,
+            ranks,
+            pattern,
+            conditions,
+        })
+    }
+
+    /// Selects the taxon `taxid` alone
+    pub(crate) fn taxid(taxid: &str) -> Self {
+        Self::from_target(Target::Taxid(taxid.as_bytes().to_vec()))
+    }
+
+    /// Selects the taxa named `name` alone, whatever their rank
+    pub(crate) fn name(name: &str) -> Self {
+        Self::from_target(Target::Name {
+            ranks: None,
+            name: NameMatch::Exact(name.as_bytes().to_vec()),
+        })
+    }
+
+    /// Selects the taxa at any of `ranks`, rank codes alone
+    pub(crate) fn ranks<S: AsRef<str>>(ranks: &[S]) -> Result<Self> {
+        let ranks = ranks
+            .iter()
+            .map(|rank| parse_rank(rank.as_ref()).map(|rank| (rank.clone(), rank)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            negate: false,
+            ranks: Some(Ranks(ranks)),
+            pattern: None,
+            conditions: Vec::new(),
+        })
+    }
+
+    fn from_target(target: Target) -> Self {
+        Self {
+            negate: false,
+            ranks: None,
+            pattern: Some(Pattern {
+                target,
+                anchored: true,
+            }),
+            conditions: Vec::new(),
+        }
+    }
+
+    fn matches(&self, kr: &Kreport) -> bool {
+        self.ranks
+            .as_ref()
+            .map_or(true, |ranks| ranks.matches(&kr.rank))
+            && self
+                .pattern
+                .as_ref()
+                .map_or(true, |pattern| pattern.matches(kr))
+            && self
+                .conditions
+                .iter()
+                .all(|condition| condition.matches(kr))
+    }
+}
+
+/// Splits the conditions after "@" from the rest of the selector. Names may
+/// hold "@", so that within a `rank__name` pattern, only an "@" after the
+/// closing "/" of a regex and followed by a condition field is a separator.
+fn split_conditions(selector: &str) -> Option<(&str, &str)> {
+    let Some((_, name)) = selector.split_once("__") else {
+        return selector.rsplit_once('@');
+    };
+    let start = selector.len() - name.len();
+    let start = match name.strip_prefix('/') {
+        Some(_) => selector
+            .rfind('/')
+            .filter(|&end| end > start)
+            .map_or(start, |end| end + 1),
+        None => start,
+    };
+    selector[start ..]
+        .match_indices('@')
+        .map(|(i, _)| start + i)
+        .find(|&i| {
+            let conditions = selector[i + 1 ..].trim_start();
+            conditions.starts_with("reads") || conditions.starts_with("percent")
+        })
+        .map(|i| (&selector[.. i], &selector[i + 1 ..]))
+}
+
+/// A set of rank codes, `None` for any rank
+#[derive(Debug)]
+struct Ranks(Vec<(Vec<u8>, Vec<u8>)>);
+
+impl Ranks {
+    fn parse(ranks: &str) -> Result<Option<Self>> {
+        if ranks == "*" {
+            return Ok(None);
+        }
+        let ranks = ranks
+            .split(',')
+            .map(|rank| {
+                let rank = rank.trim();
+                match rank.split_once('-') {
+                    Some((from, to)) => Ok((parse_rank(from)?, parse_rank(to)?)),
+                    None => parse_rank(rank).map(|rank| (rank.clone(), rank)),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(Self(ranks)))
+    }
+
+    fn matches(&self, rank: &[u8]) -> bool {
+        let key = rank_order_key(rank);
+        self.0
+            .iter()
+            .any(|(from, to)| rank_order_key(from) <= key && key <= rank_order_key(to))
+    }
+}
+
+/// A kraken2 rank code, the rank letter and the distance from the rank
+fn parse_rank(rank: &str) -> Result<Vec<u8>> {
+    let rank = rank.trim().to_ascii_uppercase().into_bytes();
+    match rank.split_first() {
+        Some((letter, distance))
+            if b"URDKPCOFGS".contains(letter) && distance.iter().all(|b| b.is_ascii_digit()) =>
+        {
+            Ok(rank)
+        }
+        _ => Err(anyhow!(
+            "Invalid rank code: '{}', expected one of U, R, D, K, P, C, O, F, G, S optionally followed by a number",
+            String::from_utf8_lossy(&rank)
+        )),
+    }
+}
+
+#[derive(Debug)]
+struct Pattern {
+    target: Target,
+    /// Only match the taxon itself rather than its lineage
+    anchored: bool,
+}
+
+#[derive(Debug)]
+enum Target {
+    Taxid(Vec<u8>),
+    Name {
+        ranks: Option<Ranks>,
+        name: NameMatch,
+    },
+}
+
+#[derive(Debug)]
+enum NameMatch {
+    Exact(Vec<u8>),
+    IgnoreCase(Vec<u8>),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Self> {
+        let (anchored, pattern) = match pattern.strip_prefix('=') {
+            Some(pattern) => (true, pattern.trim_start()),
+            None => (false, pattern),
+        };
+        let target = match pattern.split_once("__") {
+            Some((ranks, name)) => Target::Name {
+                ranks: Ranks::parse(ranks.trim())?,
+                name: NameMatch::parse(name)?,
+            },
+            None if !pattern.is_empty() && pattern.bytes().all(|b| b.is_ascii_digit()) => {
+                Target::Taxid(pattern.as_bytes().to_vec())
+            }
+            None => {
+                return Err(anyhow!(
+                    "Invalid pattern: '{}', expected a taxid or 'rank__name'",
+                    pattern
+                ))
+            }
+        };
+        Ok(Self { target, anchored })
+    }
+
+    fn matches(&self, kr: &Kreport) -> bool {
+        if self.anchored {
+            self.target.matches(&kr.rank, &kr.taxid, &kr.taxon)
+        } else {
+            // The lineage ends with the taxon itself
+            kr.ranks
+                .iter()
+                .zip(kr.taxids.iter())
+                .zip(kr.taxa.iter())
+                .any(|((rank, taxid), taxon)| self.target.matches(rank, taxid, taxon))
+        }
+    }
+}
+
+impl Target {
+    fn matches(&self, rank: &[u8], taxid: &[u8], taxon: &[u8]) -> bool {
+        match self {
+            Self::Taxid(id) => id == taxid,
+            Self::Name { ranks, name } => {
+                ranks.as_ref().map_or(true, |ranks| ranks.matches(rank)) && name.matches(taxon)
+            }
+        }
+    }
+}
+
+impl NameMatch {
+    fn parse(name: &str) -> Result<Self> {
+        if let Some(re) = name
+            .strip_prefix('/')
+            .and_then(|name| name.strip_suffix('/'))
+        {
+            let re = RegexBuilder::new(re)
+                .unicode(false)
+                .build()
+                .with_context(|| format!("Invalid regex: '{}'", re))?;
+            Ok(Self::Regex(re))
+        } else if let Some(name) = name.strip_prefix('~') {
+            Ok(Self::IgnoreCase(name.as_bytes().to_vec()))
+        } else {
+            Ok(Self::Exact(name.as_bytes().to_vec()))
+        }
+    }
+
+    fn matches(&self, taxon: &[u8]) -> bool {
+        match self {
+            Self::Exact(name) => name == taxon,
+            Self::IgnoreCase(name) => name.eq_ignore_ascii_case(taxon),
+            Self::Regex(re) => re.is_match(taxon),
+        }
+    }
+}
+
+/// A threshold on the counts of the kraken report
+#[derive(Debug)]
+enum Condition {
+    /// Minimum reads of the clade
+    Reads(usize),
+    /// Minimum percent of reads of the clade
+    Percent(f64),
+}
+
+impl Condition {
+    fn parse(condition: &str) -> Result<Self> {
+        let (field, value) = condition.split_once(">=").ok_or_else(|| {
+            anyhow!(
+                "Invalid condition: '{}', expected 'reads>=n' or 'percent>=x'",
+                condition
+            )
+        })?;
+        let value = value.trim();
+        match field.trim() {
+            "reads" => value
+                .parse()
+                .map(Self::Reads)
+                .with_context(|| format!("Invalid reads threshold: '{}'", value)),
+            "percent" => value
+                .parse()
+                .map(Self::Percent)
+                .with_context(|| format!("Invalid percent threshold: '{}'", value)),
+            field => Err(anyhow!(
+                "Invalid condition field: '{}', expected 'reads' or 'percent'",
+                field
+            )),
+        }
+    }
+
+    fn matches(&self, kr: &Kreport) -> bool {
+        match self {
+            Self::Reads(reads) => kr.total_reads >= *reads,
+            Self::Percent(percent) => kr.percents >= *percent,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kreport(lineage: &[(&str, &str, &str)], total_reads: usize, percents: f64) -> Kreport {
+        let (rank, taxid, taxon) = *lineage.last().unwrap();
+        Kreport {
+            percents,
+            total_reads,
+            reads: 0,
+            minimizer_len: None,
+            minimizer_n_unique: None,
+            kmers: None,
+            dup: None,
+            cov: None,
+            rank: rank.as_bytes().to_vec(),
+            taxid: taxid.as_bytes().to_vec(),
+            taxon: taxon.as_bytes().to_vec(),
+            ranks: lineage.iter().map(|x| x.0.as_bytes().to_vec()).collect(),
+            taxids: lineage.iter().map(|x| x.1.as_bytes().to_vec()).collect(),
+            taxa: lineage.iter().map(|x| x.2.as_bytes().to_vec()).collect(),
+            level: lineage.len(),
+        }
+    }
+
+    #[test]
+    fn test_selection() -> Result<()> {
+        let bacteria = ("D", "2", "Bacteria");
+        let genus = ("G", "848", "Fusobacterium");
+        let species = ("S", "851", "Fusobacterium nucleatum");
+        let strain = ("S1", "76856", "Fusobacterium nucleatum subsp. nucleatum");
+        let rows = [
+            kreport(&[bacteria], 100, 50.0),
+            kreport(&[bacteria, genus], 20, 10.0),
+            kreport(&[bacteria, genus, species], 15, 7.5),
+            kreport(&[bacteria, genus, species, strain], 5, 2.5),
+            kreport(&[bacteria, ("S", "562", "Escherichia coli")], 2, 1.0),
+        ];
+        let selected = |selectors: &[&str]| -> Result<Vec<String>> {
+            let selection = Selection::parse(selectors)?;
+            Ok(rows
+                .iter()
+                .filter(|kr| selection.matches(kr))
+                .map(|kr| String::from_utf8(kr.taxid.clone()).unwrap())
+                .collect())
+        };
+
+        // Exact `rank__name` pairs select the clade, as before
+        assert_eq!(
+            selected(&["G__Fusobacterium"])?,
+            vec!["848", "851", "76856"]
+        );
+        assert_eq!(selected(&["848"])?, vec!["848", "851", "76856"]);
+        assert_eq!(selected(&["=848"])?, vec!["848"]);
+        assert_eq!(selected(&["g__fusobacterium"])?, Vec::<String>::new());
+        assert_eq!(
+            selected(&["g__~fusobacterium"])?,
+            vec!["848", "851", "76856"]
+        );
+        assert_eq!(selected(&["=S__/^Fuso/"])?, vec!["851"]);
+        assert_eq!(selected(&["=*__/coli$/"])?, vec!["562"]);
+        // Rank ranges under a clade
+        assert_eq!(
+            selected(&["S-S1 < g__Fusobacterium"])?,
+            vec!["851", "76856"]
+        );
+        assert_eq!(selected(&["S"])?, vec!["851", "562"]);
+        assert_eq!(selected(&["G-S"])?, vec!["848", "851", "562"]);
+        // Negation, alone or with included selectors
+        assert_eq!(selected(&["!G__Fusobacterium"])?, vec!["2", "562"]);
+        assert_eq!(
+            selected(&["D__Bacteria", "!=S__Fusobacterium nucleatum"])?,
+            vec!["2", "848", "76856", "562"]
+        );
+        // Thresholds
+        assert_eq!(selected(&["S,S1 @ reads>=5"])?, vec!["851", "76856"]);
+        assert_eq!(
+            selected(&["D__Bacteria @reads>=5,percent>=10"])?,
+            vec!["2", "848"]
+        );
+
+        // Names and regexes holding "<" and "@"
+        let rows = [
+            kreport(&[bacteria, ("S", "1", "a<b")], 3, 1.5),
+            kreport(&[bacteria, ("S", "2", "x@y")], 4, 2.0),
+            kreport(&[bacteria, ("S", "3", "a@b<c")], 5, 2.5),
+        ];
+        let selected = |selectors: &[&str]| -> Result<Vec<String>> {
+            let selection = Selection::parse(selectors)?;
+            Ok(rows
+                .iter()
+                .filter(|kr| selection.matches(kr))
+                .map(|kr| String::from_utf8(kr.taxid.clone()).unwrap())
+                .collect())
+        };
+        assert_eq!(selected(&["=S__/a<b/"])?, vec!["1"]);
+        assert_eq!(selected(&["S__~X@Y"])?, vec!["2"]);
+        assert_eq!(selected(&["S < S__x@y @ reads>=4"])?, vec!["2"]);
+        assert_eq!(selected(&["S__x@y @reads>=5"])?, Vec::<String>::new());
+        assert_eq!(selected(&["=S__/@b</ @ reads>=5"])?, vec!["3"]);
+        assert_eq!(selected(&["S<*__/<|@/@percent>=2"])?, vec!["2", "3"]);
+
+        assert!(Selection::parse(&["Bacteria"]).is_err());
+        // Rank codes alone, without the selector grammar
+        let ranks = Selection::new(vec![Selector::ranks(&["S", "S1"])?])?;
+        let selected = rows
+            .iter()
+            .filter(|kr| ranks.matches(kr))
+            .map(|kr| String::from_utf8(kr.taxid.clone()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(selected, vec!["1", "2", "3"]);
+        for ranks in ["562", "G-S", "!S", "=S", "S @ reads>=5", "*"] {
+            assert!(Selector::ranks(&[ranks]).is_err(), "{}", ranks);
+        }
+        assert!(Selection::parse(&["X__Bacteria"]).is_err());
+        assert!(Selection::parse(&["S__/(/"]).is_err());
+        assert!(Selection::parse(&["S @ reads>5"]).is_err());
+        assert!(Selection::parse(&["S @ size>=5"]).is_err());
+        assert!(Selection::parse::<&str>(&[]).is_err());
+        Ok(())
+    }
+}
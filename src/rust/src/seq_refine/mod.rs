@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use anyhow::{anyhow, Context, Result};
 use extendr_api::prelude::*;
 use indicatif::{MultiProgress, ProgressBar, ProgressFinish};
@@ -9,75 +11,152 @@ mod seq_action;
 
 use seq_action::*;
 
+use crate::fastq_reader::ParseOptions;
+use crate::paired_reader::PairedInput;
+use crate::quarantine::{bad_record_counts, Quarantine};
+use crate::read_id::ReadIdNormalizer;
+use crate::subsample::Subsample;
 use crate::utils::*;
 
 #[extendr]
 fn seq_refine(
-    fq1: &str,
+    fq1: Vec<String>,
     ofile1: Option<&str>,
-    fq2: Option<&str>,
+    fq2: Option<Vec<String>>,
     ofile2: Option<&str>,
     actions1: Robj,
     actions2: Robj,
+    multiline: bool,
+    interleaved: bool,
+    id_normalize: Option<&str>,
+    validate: bool,
+    lenient: bool,
+    quarantine: Option<&str>,
+    max_bad: Option<usize>,
+    subsample: Option<f64>,
+    seed: u64,
     batch_size: usize,
     chunk_bytes: usize,
     compression_level: i32,
+    bgzf: bool,
+    bgzf_index: bool,
+    ordered: bool,
+    shards: Option<usize>,
+    shard_records: Option<usize>,
+    shard_bytes: Option<usize>,
     nqueue: Option<usize>,
     threads: usize,
-) -> std::result::Result<(), String> {
+) -> std::result::Result<List, String> {
+    let sharding =
+        Sharding::parse(shards, shard_records, shard_bytes).map_err(|e| format!("{:?}", e))?;
+    let output_options = OutputOptions {
+        bgzf,
+        bgzf_index,
+        ordered,
+        sharding,
+    };
+    let (batch_size, chunk_bytes) = output_options.chunk_sizes(batch_size, chunk_bytes);
     let actions1 = robj_to_seq_actions(&actions1)
         .with_context(|| format!("Failed to parse actions1"))
         .map_err(|e| format!("{:?}", e))?;
     let actions2 = robj_to_seq_actions(&actions2)
         .with_context(|| format!("Failed to parse actions2"))
         .map_err(|e| format!("{:?}", e))?;
+    check_stdio(fq1.iter().chain(fq2.iter().flatten()).map(|fq| fq.as_str()))
+        .map_err(|e| format!("{:?}", e))?;
+    check_mate_files(&fq1, fq2.as_deref()).map_err(|e| format!("{:?}", e))?;
+    let fq1 = fq1.iter().map(PathBuf::from).collect::<Vec<_>>();
+    let fq2 = fq2.map(|fq2| fq2.iter().map(PathBuf::from).collect::<Vec<_>>());
+    check_stdio([ofile1, ofile2].into_iter().flatten()).map_err(|e| format!("{:?}", e))?;
     let threads = threads.max(1); // always use at least one thread
-    if let Some(fq2) = fq2 {
+    let id_normalizer = ReadIdNormalizer::parse(id_normalize).map_err(|e| format!("{:?}", e))?;
+    let subsample = Subsample::parse(subsample, seed).map_err(|e| format!("{:?}", e))?;
+    let quarantine = lenient
+        .then(|| Quarantine::new(quarantine, max_bad))
+        .transpose()
+        .map_err(|e| format!("{:?}", e))?;
+    let parse_options = ParseOptions {
+        multiline,
+        id_normalizer,
+        decode_threads: decode_threads(threads, if fq2.is_some() { 2 } else { 1 }),
+        validate,
+        quarantine: quarantine.as_ref(),
+    };
+    if fq2.is_some() || interleaved {
         seq_refine_paired_read(
-            fq1,
+            &fq1,
             ofile1,
-            fq2,
+            fq2.as_deref(),
             ofile2,
+            interleaved,
             actions1,
             actions2,
+            parse_options,
+            subsample,
             batch_size,
             chunk_bytes,
             compression_level,
+            output_options,
             nqueue,
             threads,
         )
-        .map_err(|e| format!("{:?}", e))
+        .map_err(|e| format!("{:?}", e))?;
     } else {
         seq_refine_single_read(
-            fq1,
+            &fq1,
             ofile1,
             actions1,
+            parse_options,
+            subsample,
             batch_size,
             chunk_bytes,
             compression_level,
+            output_options,
             nqueue,
             threads,
         )
-        .map_err(|e| format!("{:?}", e))
+        .map_err(|e| format!("{:?}", e))?;
+    }
+    match quarantine {
+        Some(quarantine) => {
+            quarantine.flush().map_err(|e| format!("{:?}", e))?;
+            bad_record_counts(quarantine.counts())
+        }
+        None => bad_record_counts(Default::default()),
     }
 }
 
 #[extendr]
 #[cfg(feature = "bench")]
 fn pprof_seq_refine(
-    fq1: &str,
+    fq1: Vec<String>,
     ofile1: Option<&str>,
-    fq2: Option<&str>,
+    fq2: Option<Vec<String>>,
     ofile2: Option<&str>,
     actions1: Robj,
     actions2: Robj,
+    multiline: bool,
+    interleaved: bool,
+    id_normalize: Option<&str>,
+    validate: bool,
+    lenient: bool,
+    quarantine: Option<&str>,
+    max_bad: Option<usize>,
+    subsample: Option<f64>,
+    seed: u64,
     batch_size: usize,
     chunk_bytes: usize,
     compression_level: i32,
+    bgzf: bool,
+    bgzf_index: bool,
+    ordered: bool,
+    shards: Option<usize>,
+    shard_records: Option<usize>,
+    shard_bytes: Option<usize>,
     nqueue: Option<usize>,
     threads: usize,
     pprof_file: &str,
-) -> std::result::Result<(), String> {
+) -> std::result::Result<List, String> {
     let guard = pprof::ProfilerGuardBuilder::default()
         .frequency(2000)
         .build()
@@ -90,9 +169,24 @@ fn pprof_seq_refine(
         ofile2,
         actions1,
         actions2,
+        multiline,
+        interleaved,
+        id_normalize,
+        validate,
+        lenient,
+        quarantine,
+        max_bad,
+        subsample,
+        seed,
         batch_size,
         chunk_bytes,
         compression_level,
+        bgzf,
+        bgzf_index,
+        ordered,
+        shards,
+        shard_records,
+        shard_bytes,
         nqueue,
         threads,
     );
@@ -111,37 +205,39 @@ fn pprof_seq_refine(
 }
 
 fn seq_refine_single_read(
-    fq1: &str,
+    fq1: &[PathBuf],
     ofile1: Option<&str>,
     actions: Option<SubseqActions>,
+    parse_options: ParseOptions,
+    subsample: Option<Subsample>,
     batch_size: usize,
     chunk_bytes: usize,
     compression_level: i32,
+    output_options: OutputOptions,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
     let ofile1 = ofile1.ok_or_else(|| anyhow!("No output file specified."))?;
     let actions = actions.ok_or_else(|| anyhow!("No sequence actions were specified."))?;
-    let reader_style = progress_reader_style()?;
     let writer_style = progress_writer_style()?;
     let progress = MultiProgress::new();
-    let pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq1)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
+    let pb1 = progress.add(inputs_progress_bar(fq1)?);
     pb1.set_prefix("Reading fastq");
-    pb1.set_style(reader_style);
 
     let pb2 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
     pb2.set_prefix("Writing fastq");
     pb2.set_style(writer_style);
 
     single::seq_refine_single_read(
-        &fq1,
+        fq1,
         Some(pb1),
+        parse_options,
+        subsample,
         &ofile1,
         Some(pb2),
         &actions,
         compression_level,
+        output_options,
         batch_size,
         chunk_bytes,
         nqueue,
@@ -150,15 +246,19 @@ fn seq_refine_single_read(
 }
 
 fn seq_refine_paired_read(
-    fq1: &str,
+    fq1: &[PathBuf],
     ofile1: Option<&str>,
-    fq2: &str,
+    fq2: Option<&[PathBuf]>,
     ofile2: Option<&str>,
+    interleaved: bool,
     actions1: Option<SubseqActions>,
     actions2: Option<SubseqActions>,
+    parse_options: ParseOptions,
+    subsample: Option<Subsample>,
     batch_size: usize,
     chunk_bytes: usize,
     compression_level: i32,
+    output_options: OutputOptions,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
@@ -171,28 +271,42 @@ fn seq_refine_paired_read(
         ));
     }
 
-    let reader_style = progress_reader_style()?;
+    // With interleaved mode, both mates are written into `ofile1` when no
+    // `ofile2` is given
+    let interleaved_output = interleaved && ofile2.is_none();
     let writer_style = progress_writer_style()?;
     let progress = MultiProgress::new();
-    let pb1 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq1)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
-    pb1.set_prefix("Reading fq1");
-    pb1.set_style(reader_style.clone());
+    let pb1 = progress.add(inputs_progress_bar(fq1)?);
     let pb2 = if let Some(_) = ofile1 {
         let pb2 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
-        pb2.set_prefix("Writing fq1");
+        if interleaved_output {
+            pb2.set_prefix("Writing fastq");
+        } else {
+            pb2.set_prefix("Writing fq1");
+        }
         pb2.set_style(writer_style.clone());
         Some(pb2)
     } else {
         None
     };
 
-    let pb3 = progress.add(
-        ProgressBar::new(std::fs::metadata(fq2)?.len() as u64).with_finish(ProgressFinish::Abandon),
-    );
-    pb3.set_prefix("Reading fq2");
-    pb3.set_style(reader_style);
+    let input = if let Some(fq2) = fq2 {
+        pb1.set_prefix("Reading fq1");
+        let pb3 = progress.add(inputs_progress_bar(fq2)?);
+        pb3.set_prefix("Reading fq2");
+        PairedInput::Split {
+            input1: fq1,
+            input1_bar: Some(pb1),
+            input2: fq2,
+            input2_bar: Some(pb3),
+        }
+    } else {
+        pb1.set_prefix("Reading fastq");
+        PairedInput::Interleaved {
+            input: fq1,
+            input_bar: Some(pb1),
+        }
+    };
     let pb4 = if let Some(_) = ofile2 {
         let pb4 = progress.add(ProgressBar::no_length().with_finish(ProgressFinish::Abandon));
         pb4.set_prefix("Writing fq2");
@@ -204,16 +318,17 @@ fn seq_refine_paired_read(
 
     let actions = SubseqPairedActions::new(actions1, actions2);
     paired::seq_refine_paired_read(
-        fq1,
-        Some(pb1),
-        fq2,
-        Some(pb3),
+        input,
+        parse_options,
+        subsample,
         ofile1,
         pb2,
         ofile2,
         pb4,
+        interleaved_output,
         &actions,
         compression_level,
+        output_options,
         batch_size,
         chunk_bytes,
         nqueue,
@@ -1,12 +1,11 @@
 use anyhow::{anyhow, Error, Result};
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Bytes, BytesMut};
 use extendr_api::prelude::*;
 use rustc_hash::FxHashMap as HashMap;
 
 use crate::fastq_record::FastqRecord;
 use crate::seq_range::{check_overlap, SeqRange, SeqRanges};
 use crate::seq_tag::*;
-use crate::utils::*;
 
 pub(in crate::seq_refine) struct SubseqEmbedActions {
     tags: TagRanges,
@@ -99,14 +98,20 @@ impl SubseqTrimActions {
 
         let total_seq_len = keep.iter().map(|(start, end)| end - start).sum();
         let mut trimmed_seq = BytesMut::with_capacity(total_seq_len);
-        let mut trimmed_qual = BytesMut::with_capacity(total_seq_len);
+        // FASTA records have no quality to trim
+        let mut trimmed_qual = record
+            .qual
+            .as_ref()
+            .map(|_| BytesMut::with_capacity(total_seq_len));
 
         for (start, end) in keep {
             trimmed_seq.extend_from_slice(&record.seq[start .. end]);
-            trimmed_qual.extend_from_slice(&record.qual[start .. end]);
+            if let (Some(trimmed), Some(qual)) = (trimmed_qual.as_mut(), &record.qual) {
+                trimmed.extend_from_slice(&qual[start .. end]);
+            }
         }
         record.seq = trimmed_seq.freeze();
-        record.qual = trimmed_qual.freeze();
+        record.qual = trimmed_qual.map(|qual| qual.freeze());
         Ok(())
     }
 }
@@ -348,36 +353,3 @@ impl TryFrom<&Robj> for SeqAction {
         Ok(resolved_action)
     }
 }
-
-fn make_description(tag_map: &HashMap<Bytes, Vec<&[u8]>>, desc: &Option<&[u8]>) -> Bytes {
-    // add prefix, tag and seprator
-    let mut out = BytesMut::with_capacity(
-        // original description length
-        desc.map_or(0, |d| d.len() + 1)
-            // prefix
-            + TAG_PREFIX.len()
-            // all tag
-            + tag_map.iter().map(|(tag, sequence)| tag.len() + 1 + sequence.iter().map(|s| s.len()).sum::<usize>()).sum::<usize>()
-            // all seprator
-            + tag_map.len().saturating_sub(1)
-            // suffix
-            + 1,
-    );
-    if let Some(v) = desc {
-        out.extend_from_slice(&v);
-        out.put_u8(b' ');
-    }
-    out.extend_from_slice(TAG_PREFIX);
-    for (i, (tag, sequences)) in tag_map.iter().enumerate() {
-        if i > 0 {
-            out.put_u8(b':');
-        }
-        out.extend_from_slice(&tag);
-        out.put_u8(b':');
-        for seq in sequences {
-            out.extend_from_slice(seq);
-        }
-    }
-    out.put_u8(TAG_SUFFIX);
-    out.freeze()
-}
@@ -1,31 +1,34 @@
-use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
 use indicatif::ProgressBar;
-use libdeflater::{CompressionLvl, Compressor};
+use libdeflater::CompressionLvl;
 
 use super::seq_action::*;
+use crate::bam_reader::SequenceReader;
 use crate::batchsender::BatchSender;
 use crate::fastq_reader::*;
 use crate::fastq_record::FastqRecord;
+use crate::subsample::{Subsample, Subsampler};
 use crate::utils::*;
 
 pub(crate) fn seq_refine_single_read<P: AsRef<Path> + ?Sized>(
-    input_path: &P,
+    input: &[PathBuf],
     input_bar: Option<ProgressBar>,
+    parse_options: ParseOptions,
+    subsample: Option<Subsample>,
     output_path: &P,
     output_bar: Option<ProgressBar>,
     actions: &SubseqActions,
     compression_level: i32,
+    output_options: OutputOptions,
     batch_size: usize,
     chunk_bytes: usize,
     nqueue: Option<usize>,
     threads: usize,
 ) -> Result<()> {
-    let input: &Path = input_path.as_ref();
     let output: &Path = output_path.as_ref();
 
     // Ensure compression level is validated and converted before entering thread scope.
@@ -36,28 +39,29 @@ pub(crate) fn seq_refine_single_read<P: AsRef<Path> + ?Sized>(
         // Two communication pipelines are set up to decouple IO and CPU-intensive work:
         // - reader_tx: transfers raw FASTQ records to parser threads
         // - writer_tx: receives compressed byte chunks from parser threads
-        let (writer_tx, writer_rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = new_channel(nqueue);
+        let (writer_tx, writer_rx): (Sender<(usize, Chunk)>, Receiver<(usize, Chunk)>) = new_channel(nqueue);
         let (reader_tx, reader_rx): (
-            Sender<Vec<FastqRecord<Bytes>>>,
-            Receiver<Vec<FastqRecord<Bytes>>>,
+            Sender<(usize, Vec<FastqRecord<Bytes>>)>,
+            Receiver<(usize, Vec<FastqRecord<Bytes>>)>,
         ) = new_channel(nqueue);
 
         // ─── Writer Thread ─────────────────────────────────────
         // A single thread handles file output to ensure atomic write order and leverage buffered IO.
         // This thread consumes compressed chunks, not raw records, for performance.
-        let writer_handle = scope.spawn(move || -> Result<()> {
-            let mut writer = BufWriter::with_capacity(chunk_bytes, new_writer(output, output_bar)?);
+        let writer_handle = scope.spawn(move || -> Result<PendingOutput> {
+            let mut writer = ShardedWriter::new(output, output_bar, chunk_bytes, output_options)?;
+            let mut shards = ShardCounter::new(output_options.sharding);
 
             // Iterate over each received batch of records
-            for chunk in writer_rx {
-                writer
-                    .write_all(&chunk)
+            let mut chunks = OrderedChunks::new(output_options.ordered);
+            for (index, chunk) in writer_rx {
+                chunks
+                    .push(index, chunk, |chunk| writer.write_chunk(shards.next([&chunk]), &chunk.bytes))
                     .with_context(|| format!("(Writer) Failed to write FastqRecord to output"))?;
             }
-            writer
-                .flush()
-                .with_context(|| format!("(Writer) Failed to flush writer"))?;
-            Ok(())
+            chunks.finish().with_context(|| format!("(Writer) Failed to order output"))?;
+            let output = writer.finish().with_context(|| format!("(Writer) Failed to finish writer"))?;
+            Ok(output)
         });
 
         // ─── Parser Thread ─────────────────────────────────────
@@ -65,48 +69,64 @@ pub(crate) fn seq_refine_single_read<P: AsRef<Path> + ?Sized>(
         // Each thread transforms records and buffers them into a local pool,
         // which is periodically flushed into the writer pipeline.
         let mut parser_handles = Vec::with_capacity(threads);
-        let gzip = gz_compressed(output);
+        let compression = output_compression(output, output_options);
         for _ in 0 .. threads {
             let rx = reader_rx.clone();
             let tx = writer_tx.clone();
             let handle = scope.spawn(move || -> Result<()> {
                 // Temporary buffer for current output chunk
                 let mut records_pool: Vec<u8> = Vec::with_capacity(chunk_bytes);
-                let mut compressor = Compressor::new(compression_level);
-                while let Ok(records) = rx.recv() {
+                let mut pool_records = 0;
+                let mut compressor = ChunkCompressor::new(compression, compression_level)?;
+                while let Ok((index, records)) = rx.recv() {
                     for mut record in records {
                         // Apply trimming, tag embedding, and other sequence transformations
                         actions.transform_fastq(&mut record)?;
 
                         // Flush when pool is too full to accept the next record.
                         // This ensures output chunks remain near the target block size.
-                        if records_pool.capacity() - records_pool.len() < record.bytes_size() {
+                        if !output_options.ordered &&
+                            (records_pool.capacity() - records_pool.len() < record.bytes_size() ||
+                                output_options.chunk_full(pool_records))
+                        {
                             let mut pack = Vec::with_capacity(chunk_bytes);
                             std::mem::swap(&mut records_pool, &mut pack);
-                            // Compress if gzip file
-                            if gzip {
-                                pack = gzip_pack(&pack, &mut compressor)?
-                            }
+                            // Compress if gzip or zstd file
+                            let pack = Chunk {
+                                bytes: compressor.pack(pack)?,
+                                records: std::mem::take(&mut pool_records),
+                            };
 
                             // Send compressed or raw bytes to writer
-                            tx.send(pack).with_context(|| {
+                            tx.send((index, pack)).with_context(|| {
                                 format!("(Parser) Failed to send parsed record to Writer thread")
                             })?;
                         }
 
                         // Append encoded record to buffer
                         record.extend(&mut records_pool);
+                        pool_records += 1;
+                    }
+                    // In ordered mode, each batch is sent as a single chunk
+                    if output_options.ordered {
+                        let pack = Chunk {
+                            bytes: compressor.pack(std::mem::take(&mut records_pool))?,
+                            records: std::mem::take(&mut pool_records),
+                        };
+                        tx.send((index, pack)).with_context(|| {
+                            format!("(Parser) Failed to send parsed record to Writer thread")
+                        })?;
                     }
                 }
 
-                // Flush remaining records if any
+                // Flush remaining records if any, the index only matters in
+                // ordered mode, where the pool is always empty here
                 if !records_pool.is_empty() {
-                    let pack = if gzip {
-                        gzip_pack(&records_pool, &mut compressor)?
-                    } else {
-                        records_pool
+                    let pack = Chunk {
+                        bytes: compressor.pack(records_pool)?,
+                        records: pool_records,
                     };
-                    tx.send(pack).with_context(|| {
+                    tx.send((0, pack)).with_context(|| {
                         format!("(Parser) Failed to send parsed record to Writer thread")
                     })?;
                 }
@@ -119,13 +139,20 @@ pub(crate) fn seq_refine_single_read<P: AsRef<Path> + ?Sized>(
 
         // ─── reader Thread ─────────────────────────────────────
         let reader_handle = scope.spawn(move || -> Result<()> {
-            let mut reader =
-                FastqReader::with_capacity(BUFFER_SIZE, new_reader(input, BUFFER_SIZE, input_bar)?);
+            let mut reader = SequenceReader::open(input, input_bar, parse_options)?;
+            let mut sampler = Subsampler::new(subsample);
             let mut reader_tx = BatchSender::with_capacity(batch_size, reader_tx);
             while let Some(record) = reader
                 .read_record()
                 .with_context(|| format!("(Reader) Failed to read FASTQ record"))?
             {
+                if let Some(record) = sampler.offer(record) {
+                    reader_tx.send(record).with_context(|| {
+                        format!("(Reader) Failed to send FASTQ records to Parser thread")
+                    })?;
+                }
+            }
+            for record in sampler.finish() {
                 reader_tx.send(record).with_context(|| {
                     format!("(Reader) Failed to send FASTQ records to Parser thread")
                 })?;
@@ -137,7 +164,7 @@ pub(crate) fn seq_refine_single_read<P: AsRef<Path> + ?Sized>(
         });
 
         // ─── Join Threads and Propagate Errors ────────────────
-        writer_handle
+        let output = writer_handle
             .join()
             .map_err(|e| anyhow!("(Writer) thread panicked: {:?}", e))??;
         for handler in parser_handles {
@@ -148,7 +175,7 @@ pub(crate) fn seq_refine_single_read<P: AsRef<Path> + ?Sized>(
         reader_handle
             .join()
             .map_err(|e| anyhow!("(Reader) thread panicked: {:?}", e))??;
-        Ok(())
+        commit_outputs([output])
     })
 }
 
@@ -195,12 +222,15 @@ mod tests {
 
         // Call the function
         let result = seq_refine_single_read(
-            &input_path,
+            std::slice::from_ref(&input_path),
             None, // No progress bar
+            ParseOptions::default(),
+            None, // No subsampling
             &output_path,
             None, // No progress bar
             &actions,
             1,       // No compression
+            OutputOptions::default(),
             1,       // chunk size
             8192,    // buffer size
             Some(2), // queue size
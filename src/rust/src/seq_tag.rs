@@ -1,9 +1,10 @@
 use anyhow::{anyhow, Error, Result};
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use extendr_api::prelude::*;
 use rustc_hash::FxHashMap as HashMap;
 
 use crate::seq_range::{check_overlap, SeqRanges};
+use crate::utils::*;
 
 /// A collection of (tag name → sequence ranges) mappings.
 /// Each tag (as `Bytes`) maps to a `SeqRanges` defining subsequence locations to extract.
@@ -101,6 +102,41 @@ impl<'a> IntoIterator for &'a TagRanges {
     }
 }
 
+/// Builds a description embedding `tag_map` as `MIRE{tag:seq:tag:seq}`, appended
+/// to the original description `desc` if any.
+pub(crate) fn make_description(tag_map: &HashMap<Bytes, Vec<&[u8]>>, desc: &Option<&[u8]>) -> Bytes {
+    // add prefix, tag and seprator
+    let mut out = BytesMut::with_capacity(
+        // original description length
+        desc.map_or(0, |d| d.len() + 1)
+            // prefix
+            + TAG_PREFIX.len()
+            // all tag
+            + tag_map.iter().map(|(tag, sequence)| tag.len() + 1 + sequence.iter().map(|s| s.len()).sum::<usize>()).sum::<usize>()
+            // all seprator
+            + tag_map.len().saturating_sub(1)
+            // suffix
+            + 1,
+    );
+    if let Some(v) = desc {
+        out.extend_from_slice(&v);
+        out.put_u8(b' ');
+    }
+    out.extend_from_slice(TAG_PREFIX);
+    for (i, (tag, sequences)) in tag_map.iter().enumerate() {
+        if i > 0 {
+            out.put_u8(b':');
+        }
+        out.extend_from_slice(&tag);
+        out.put_u8(b':');
+        for seq in sequences {
+            out.extend_from_slice(seq);
+        }
+    }
+    out.put_u8(TAG_SUFFIX);
+    out.freeze()
+}
+
 // Create object from R
 pub(crate) fn robj_to_tag_ranges<'r>(ranges: &Robj) -> Result<Option<TagRanges>> {
     if ranges.is_null() {
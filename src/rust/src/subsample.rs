@@ -0,0 +1,147 @@
+use anyhow::{anyhow, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// How many records a [`Subsample`] keeps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SampleSize {
+    /// Each record is kept with this probability (Bernoulli sampling)
+    Fraction(f64),
+    /// Exactly this many records are kept, or all records if there are fewer
+    /// (reservoir sampling)
+    Count(usize),
+}
+
+/// Subsample: A seeded random subsample of the input records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Subsample {
+    pub(crate) size: SampleSize,
+    pub(crate) seed: u64,
+}
+
+impl Subsample {
+    /// As in `seqtk sample`, values below 1 are a fraction of the records,
+    /// other values a number of records
+    pub(crate) fn parse(subsample: Option<f64>, seed: u64) -> Result<Option<Self>> {
+        let size = match subsample {
+            None => return Ok(None),
+            Some(value) if value > 0.0 && value < 1.0 => SampleSize::Fraction(value),
+            Some(value) if value >= 1.0 && value.fract() == 0.0 && value <= usize::MAX as f64 => {
+                SampleSize::Count(value as usize)
+            }
+            Some(value) => {
+                return Err(anyhow!(
+                    "'subsample' must be a fraction between 0 and 1 or a whole number of records, got: {}",
+                    value
+                ));
+            }
+        };
+        Ok(Some(Self { size, seed }))
+    }
+}
+
+/// Subsampler: Draws items from a stream offered in input order.
+///
+/// The draws only depend on the seed and on the position of each item, the
+/// subsampler must live in the single thread reading the input, so the same
+/// seed keeps the same records whatever the number of threads. Paired-end
+/// reads are offered as pairs to keep mates together.
+pub(crate) struct Subsampler<T> {
+    subsample: Option<Subsample>,
+    rng: StdRng,
+    seen: usize,
+    // Items kept by reservoir sampling, with their position
+    reservoir: Vec<(usize, T)>,
+}
+
+impl<T> Subsampler<T> {
+    /// Without a `subsample`, every item is kept
+    pub(crate) fn new(subsample: Option<Subsample>) -> Self {
+        let seed = subsample.map_or(0, |subsample| subsample.seed);
+        Self {
+            subsample,
+            rng: StdRng::seed_from_u64(seed),
+            seen: 0,
+            reservoir: Vec::new(),
+        }
+    }
+
+    /// Offers the next item, which is returned if it is kept right away.
+    /// Items held in the reservoir are only known once the whole input was
+    /// offered, and are returned by [`Subsampler::finish`].
+    pub(crate) fn offer(&mut self, item: T) -> Option<T> {
+        let pos = self.seen;
+        self.seen += 1;
+        match self.subsample.map(|subsample| subsample.size) {
+            None => Some(item),
+            Some(SampleSize::Fraction(fraction)) => self.rng.gen_bool(fraction).then_some(item),
+            Some(SampleSize::Count(count)) => {
+                if self.reservoir.len() < count {
+                    self.reservoir.push((pos, item));
+                } else {
+                    let i = self.rng.gen_range(0 ..= pos);
+                    if i < count {
+                        self.reservoir[i] = (pos, item);
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// The items of the reservoir, in input order
+    pub(crate) fn finish(mut self) -> Vec<T> {
+        self.reservoir.sort_unstable_by_key(|(pos, _)| *pos);
+        self.reservoir.into_iter().map(|(_, item)| item).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(subsample: Option<Subsample>, n: usize) -> Vec<usize> {
+        let mut sampler = Subsampler::new(subsample);
+        let mut kept = (0 .. n)
+            .filter_map(|i| sampler.offer(i))
+            .collect::<Vec<_>>();
+        kept.extend(sampler.finish());
+        kept
+    }
+
+    #[test]
+    fn test_parse_subsample() {
+        assert_eq!(Subsample::parse(None, 1).unwrap(), None);
+        assert_eq!(
+            Subsample::parse(Some(0.25), 1).unwrap().unwrap().size,
+            SampleSize::Fraction(0.25)
+        );
+        assert_eq!(
+            Subsample::parse(Some(100.0), 1).unwrap().unwrap().size,
+            SampleSize::Count(100)
+        );
+        assert!(Subsample::parse(Some(0.0), 1).is_err());
+        assert!(Subsample::parse(Some(2.5), 1).is_err());
+        assert!(Subsample::parse(Some(f64::NAN), 1).is_err());
+    }
+
+    #[test]
+    fn test_subsampler() {
+        assert_eq!(draw(None, 5), vec![0, 1, 2, 3, 4]);
+
+        let fraction = Subsample::parse(Some(0.1), 42).unwrap();
+        let kept = draw(fraction, 10000);
+        assert!(kept.len() > 800 && kept.len() < 1200);
+        assert!(kept.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(kept, draw(fraction, 10000));
+        assert_ne!(kept, draw(Subsample::parse(Some(0.1), 7).unwrap(), 10000));
+
+        let count = Subsample::parse(Some(100.0), 42).unwrap();
+        let kept = draw(count, 10000);
+        assert_eq!(kept.len(), 100);
+        assert!(kept.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(kept, draw(count, 10000));
+        // Fewer items than requested are all kept
+        assert_eq!(draw(count, 10), (0 .. 10).collect::<Vec<_>>());
+    }
+}
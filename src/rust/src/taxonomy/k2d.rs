@@ -0,0 +1,162 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use super::{rank_code, Taxon, TaxonomyTree};
+
+// https://github.com/DerrickWood/kraken2/blob/master/src/taxonomy.cc
+// taxo.k2d is written by `Taxonomy::WriteToDisk` as:
+// 1. The magic string "K2TAXDAT"
+// 2. The number of nodes, the length of the name data and the length of the
+//    rank data, as 64-bit integers
+// 3. The nodes, seven 64-bit integers each: the internal parent id, the first
+//    child, the number of children, the offsets of the name and of the rank,
+//    the external (NCBI) taxid and a reserved godparent id
+// 4. The names then the ranks, as NUL-terminated strings
+//
+// Internal ids number the nodes in breadth-first order, node 0 is a null
+// node and node 1 the root, so a parent always comes before its children.
+const K2D_MAGIC: &[u8] = b"K2TAXDAT";
+const K2D_NODE_SIZE: usize = 7 * 8;
+
+/// Reads the taxonomy of a kraken2 database from its `taxo.k2d`. The taxa
+/// keep the order of the internal ids, the taxon at index `i` has the
+/// internal id `i + 1`.
+pub(crate) fn read_taxo_k2d<P: AsRef<Path> + ?Sized>(taxo: &P) -> Result<TaxonomyTree> {
+    let path: &Path = taxo.as_ref();
+    let data =
+        std::fs::read(path).with_context(|| format!("Failed to read file: {}", path.display()))?;
+    parse_taxo_k2d(&data)
+        .with_context(|| format!("Failed to parse kraken2 taxonomy: '{}'", path.display()))
+}
+
+fn parse_taxo_k2d(data: &[u8]) -> Result<TaxonomyTree> {
+    if !data.starts_with(K2D_MAGIC) {
+        return Err(anyhow!(
+            "Not a taxo.k2d file, missing the 'K2TAXDAT' magic string"
+        ));
+    }
+    let header = |i: usize| read_u64(data, K2D_MAGIC.len() + i * 8);
+    let node_count = header(0)? as usize;
+    let name_len = header(1)? as usize;
+    let rank_len = header(2)? as usize;
+    if node_count > data.len() / K2D_NODE_SIZE || name_len > data.len() || rank_len > data.len() {
+        return Err(anyhow!("Truncated file: {} bytes", data.len()));
+    }
+    let nodes_start = K2D_MAGIC.len() + 3 * 8;
+    let names_start = nodes_start + node_count * K2D_NODE_SIZE;
+    let ranks_start = names_start + name_len;
+    if data.len() < ranks_start + rank_len {
+        return Err(anyhow!(
+            "Truncated file: expected {} bytes, got {}",
+            ranks_start + rank_len,
+            data.len()
+        ));
+    }
+    let names = &data[names_start .. ranks_start];
+    let ranks = &data[ranks_start .. ranks_start + rank_len];
+
+    let mut taxa: Vec<Taxon> = Vec::with_capacity(node_count.saturating_sub(1));
+    for id in 1 .. node_count {
+        let node = |field: usize| read_u64(data, nodes_start + id * K2D_NODE_SIZE + field * 8);
+        let parent_id = node(0)? as usize;
+        let parent = match parent_id {
+            0 => None,
+            _ if parent_id < id => Some(parent_id - 1),
+            _ => {
+                return Err(anyhow!(
+                    "Node {} comes before its parent node {}",
+                    id,
+                    parent_id
+                ))
+            }
+        };
+        let name = c_string(names, node(3)? as usize)?;
+        let rank = c_string(ranks, node(4)? as usize)?;
+        let taxid = node(5)?.to_string().into_bytes();
+        let parent_rank = parent.map(|parent| taxa[parent].rank.as_slice());
+        taxa.push(Taxon {
+            rank: rank_code(rank, &taxid, parent_rank),
+            name: name.to_vec(),
+            taxid,
+            parent,
+            depth: 0,
+        });
+    }
+    Ok(TaxonomyTree::new(taxa))
+}
+
+fn read_u64(data: &[u8], pos: usize) -> Result<u64> {
+    data.get(pos .. pos + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| anyhow!("Truncated file at byte {}", pos))
+}
+
+/// The NUL-terminated string starting at `offset`
+fn c_string(data: &[u8], offset: usize) -> Result<&[u8]> {
+    let string = data
+        .get(offset ..)
+        .ok_or_else(|| anyhow!("String offset {} out of range", offset))?;
+    Ok(memchr::memchr(0, string).map_or(string, |end| &string[.. end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A taxo.k2d with the nodes given as (parent, name, rank, external id)
+    fn write_taxo_k2d(nodes: &[(u64, &str, &str, u64)]) -> Vec<u8> {
+        let mut names = Vec::new();
+        let mut ranks = Vec::new();
+        let mut node_data = vec![0u8; K2D_NODE_SIZE];
+        for (parent, name, rank, taxid) in nodes {
+            let fields = [
+                *parent,
+                0,
+                0,
+                names.len() as u64,
+                ranks.len() as u64,
+                *taxid,
+                0,
+            ];
+            node_data.extend(fields.iter().flat_map(|field| field.to_le_bytes()));
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+            ranks.extend_from_slice(rank.as_bytes());
+            ranks.push(0);
+        }
+        let mut data = K2D_MAGIC.to_vec();
+        for value in [nodes.len() + 1, names.len(), ranks.len()] {
+            data.extend((value as u64).to_le_bytes());
+        }
+        data.extend(node_data);
+        data.extend(names);
+        data.extend(ranks);
+        data
+    }
+
+    #[test]
+    fn test_parse_taxo_k2d() -> Result<()> {
+        let data = write_taxo_k2d(&[
+            (0, "root", "no rank", 1),
+            (1, "Bacteria", "superkingdom", 2),
+            (1, "Viruses", "superkingdom", 10239),
+            (2, "Escherichia", "genus", 561),
+            (4, "Escherichia coli", "species", 562),
+        ]);
+        let tree = parse_taxo_k2d(&data)?;
+        assert_eq!(tree.len(), 5);
+        let coli = tree.get(b"562").unwrap();
+        assert_eq!(coli, 4);
+        assert_eq!(tree.taxon(coli).name, b"Escherichia coli");
+        let ranks = tree
+            .ancestors(coli)
+            .map(|i| String::from_utf8(tree.taxon(i).rank.clone()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ranks, vec!["S", "G", "D", "R"]);
+
+        assert!(parse_taxo_k2d(&data[.. data.len() - 1]).is_err());
+        assert!(parse_taxo_k2d(b"K2TAXDAX").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+use std::path::Path;
+
+use anyhow::Result;
+use extendr_api::prelude::*;
+use rustc_hash::FxHashMap as HashMap;
+use rustc_hash::FxHashSet as HashSet;
+
+use crate::kreport::{taxonomy_kreport, Kreport};
+use crate::utils::*;
+
+mod k2d;
+mod taxdump;
+
+use k2d::read_taxo_k2d;
+use taxdump::read_taxdump;
+
+/// Reads the taxonomy at `path`: a kraken2 database directory or its
+/// `taxo.k2d` file, or an NCBI taxdump directory
+pub(crate) fn read_taxonomy<P: AsRef<Path> + ?Sized>(path: &P) -> Result<TaxonomyTree> {
+    let path: &Path = path.as_ref();
+    if path.is_dir() {
+        let taxo = path.join("taxo.k2d");
+        if taxo.is_file() {
+            read_taxo_k2d(&taxo)
+        } else {
+            read_taxdump(path)
+        }
+    } else {
+        read_taxo_k2d(path)
+    }
+}
+
+/// A node of the [`TaxonomyTree`]
+pub(crate) struct Taxon {
+    pub(crate) taxid: Vec<u8>,
+    pub(crate) rank: Vec<u8>,
+    pub(crate) name: Vec<u8>,
+    pub(crate) parent: Option<usize>,
+    pub(crate) depth: usize,
+}
+
+/// TaxonomyTree: Taxa linked to their parent and children by index.
+///
+/// Built once from a taxonomy source, it answers the ancestor, descendant and
+/// LCA queries by walking the tree, instead of comparing the lineages of
+/// every pair of taxa. A taxon without a parent is the root of its own tree,
+/// e.g. the top taxa of a report filtered by taxonomy.
+pub(crate) struct TaxonomyTree {
+    taxa: Vec<Taxon>,
+    children: Vec<Vec<usize>>,
+    index: HashMap<Vec<u8>, usize>,
+}
+
+impl TaxonomyTree {
+    /// The taxa keep the indices of `kreports`. Reports are written in
+    /// pre-order, so the parent of a taxon is the last taxon above its level,
+    /// as long as it is in the lineage of the taxon: the parent of a taxon may
+    /// have been filtered out of the reports. Root ranks, never part of a
+    /// lineage, are the parent of any taxon below them.
+    pub(crate) fn from_kreports(kreports: &[Kreport]) -> Self {
+        let mut parents = Vec::with_capacity(kreports.len());
+        let mut stack: Vec<usize> = Vec::new();
+        for (i, report) in kreports.iter().enumerate() {
+            while let Some(&last) = stack.last() {
+                if kreports[last].level >= report.level {
+                    stack.pop();
+                } else {
+                    break;
+                }
+            }
+            let parent = stack.last().copied().filter(|&last| {
+                let last = &kreports[last];
+                last.rank.first() == Some(&b'R') || report.taxids.contains(&last.taxid)
+            });
+            parents.push(parent);
+            stack.push(i);
+        }
+        Self::new(kreports.iter().zip(parents).map(|(report, parent)| Taxon {
+            taxid: report.taxid.clone(),
+            rank: report.rank.clone(),
+            name: report.taxon.clone(),
+            parent,
+            depth: 0,
+        }))
+    }
+
+    /// Builds the tree from taxa linked to the index of their parent, every
+    /// parent coming before its children. The depths are computed here.
+    pub(crate) fn new<I>(taxa: I) -> Self
+    where
+        I: IntoIterator<Item = Taxon>,
+    {
+        let taxa = taxa.into_iter();
+        let mut tree = Self {
+            taxa: Vec::with_capacity(taxa.size_hint().0),
+            children: Vec::with_capacity(taxa.size_hint().0),
+            index: HashMap::with_capacity_and_hasher(taxa.size_hint().0, Default::default()),
+        };
+        for (i, mut taxon) in taxa.enumerate() {
+            if let Some(parent) = taxon.parent {
+                taxon.depth = tree.taxa[parent].depth + 1;
+                tree.children[parent].push(i);
+            }
+            tree.index.entry(taxon.taxid.clone()).or_insert(i);
+            tree.taxa.push(taxon);
+            tree.children.push(Vec::new());
+        }
+        tree
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.taxa.len()
+    }
+
+    /// The index of `taxid`, the first one if the taxid is repeated
+    pub(crate) fn get(&self, taxid: &[u8]) -> Option<usize> {
+        self.index.get(taxid).copied()
+    }
+
+    pub(crate) fn taxon(&self, i: usize) -> &Taxon {
+        &self.taxa[i]
+    }
+
+    pub(crate) fn children(&self, i: usize) -> &[usize] {
+        &self.children[i]
+    }
+
+    /// The taxon `i` followed by its ancestors, up to the root of its tree
+    pub(crate) fn ancestors(&self, i: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(Some(i), move |&i| self.taxa[i].parent)
+    }
+
+    /// The taxon `i` and all its descendants, in pre-order
+    #[cfg(test)]
+    pub(crate) fn descendants(&self, i: usize) -> Vec<usize> {
+        self.clades(std::iter::once(i))
+    }
+
+    /// The lineage of the taxon `i` as written in kraken reports: its
+    /// ancestors from the top, without the root ranks, then the taxon itself
+    pub(crate) fn lineage(&self, i: usize) -> Vec<usize> {
+        let mut lineage = self
+            .ancestors(i)
+            .skip(1)
+            .filter(|&ancestor| self.taxa[ancestor].rank.first() != Some(&b'R'))
+            .collect::<Vec<_>>();
+        lineage.reverse();
+        lineage.push(i);
+        lineage
+    }
+
+    /// The taxa `roots` and all their descendants, each taxon once. Shared
+    /// subtrees are only walked once, so this is linear in the number of taxa.
+    pub(crate) fn clades<I: IntoIterator<Item = usize>>(&self, roots: I) -> Vec<usize> {
+        let mut seen = vec![false; self.taxa.len()];
+        let mut clades = Vec::new();
+        let mut stack = Vec::new();
+        for root in roots {
+            stack.push(root);
+            while let Some(i) = stack.pop() {
+                if std::mem::replace(&mut seen[i], true) {
+                    continue;
+                }
+                clades.push(i);
+                stack.extend(self.children[i].iter().rev());
+            }
+        }
+        clades
+    }
+
+    /// The lowest common ancestor of two taxa, `None` if they are in
+    /// different trees
+    #[cfg(test)]
+    pub(crate) fn lca(&self, mut a: usize, mut b: usize) -> Option<usize> {
+        while self.taxa[a].depth > self.taxa[b].depth {
+            a = self.taxa[a].parent?;
+        }
+        while self.taxa[b].depth > self.taxa[a].depth {
+            b = self.taxa[b].parent?;
+        }
+        while a != b {
+            a = self.taxa[a].parent?;
+            b = self.taxa[b].parent?;
+        }
+        Some(a)
+    }
+
+    /// The taxon `i` or its closest ancestor at `rank`
+    #[cfg(test)]
+    pub(crate) fn rank_at(&self, i: usize, rank: &[u8]) -> Option<usize> {
+        self.ancestors(i)
+            .find(|&ancestor| self.taxa[ancestor].rank == rank)
+    }
+
+    /// The taxids of the taxa `roots` and of all their descendants
+    pub(crate) fn clade_taxids<I: IntoIterator<Item = usize>>(&self, roots: I) -> HashSet<&[u8]> {
+        self.clades(roots)
+            .into_iter()
+            .map(|i| self.taxa[i].taxid.as_slice())
+            .collect()
+    }
+}
+
+/// Kraken2 rank code of an NCBI rank name. As in kraken2, taxa outside
+/// the main ranks take the code of their parent with a number giving the
+/// distance from that rank, e.g. "S1" for a strain below a species.
+pub(crate) fn rank_code(rank: &[u8], taxid: &[u8], parent: Option<&[u8]>) -> Vec<u8> {
+    let code: &[u8] = match rank.trim_ascii() {
+        b"superkingdom" | b"domain" => b"D",
+        b"kingdom" => b"K",
+        b"phylum" => b"P",
+        b"class" => b"C",
+        b"order" => b"O",
+        b"family" => b"F",
+        b"genus" => b"G",
+        b"species" => b"S",
+        _ => match taxid.trim_ascii() {
+            b"0" => b"U",
+            b"1" => b"R",
+            _ => {
+                let Some((&code, distance)) = parent.and_then(|parent| parent.split_first()) else {
+                    return b"R".to_vec();
+                };
+                let distance = parse_usize(distance).unwrap_or(0) + 1;
+                let mut rank = vec![code];
+                rank.extend_from_slice(distance.to_string().as_bytes());
+                return rank;
+            }
+        },
+    };
+    code.to_vec()
+}
+
+/// The sort key of a rank code: the rank letters from unclassified to
+/// species, then the distance from the rank.
+pub(crate) fn rank_order_key(rank: &[u8]) -> (usize, usize) {
+    match rank {
+        b"U" => (0, 0),
+        b"R" => (1, 0),
+        b"D" => (2, 0),
+        b"K" => (3, 0),
+        b"P" => (4, 0),
+        b"C" => (5, 0),
+        b"O" => (6, 0),
+        b"F" => (7, 0),
+        b"G" => (8, 0),
+        b"S" => (9, 0),
+        _ if rank.len() > 1 => (
+            match unsafe { rank.get_unchecked(0) } {
+                b'U' => 0,
+                b'R' => 1,
+                b'D' => 2,
+                b'K' => 3,
+                b'P' => 4,
+                b'C' => 5,
+                b'O' => 6,
+                b'F' => 7,
+                b'G' => 8,
+                b'S' => 9,
+                _ => 10,
+            },
+            parse_usize(&rank[1 ..]).map_or_else(|_| usize::max_value(), |x| x),
+        ),
+        _ => (10, 0),
+    }
+}
+
+#[extendr]
+fn read_taxo(taxo: &str, taxonomy: Robj) -> std::result::Result<List, String> {
+    let tree = read_taxonomy(taxo).map_err(|e| format!("{:?}", e))?;
+    let (kreports, _) =
+        taxonomy_kreport(None::<&str>, taxonomy, Some(&tree)).map_err(|e| format!("{:?}", e))?;
+
+    let mut taxid = Vec::with_capacity(kreports.len());
+    let mut parent = Vec::with_capacity(kreports.len());
+    let mut rank = Vec::with_capacity(kreports.len());
+    let mut taxon = Vec::with_capacity(kreports.len());
+    let mut ranks = Vec::with_capacity(kreports.len());
+    let mut taxids = Vec::with_capacity(kreports.len());
+    let mut taxa = Vec::with_capacity(kreports.len());
+    for report in kreports {
+        parent.push(
+            tree.get(&report.taxid)
+                .and_then(|i| tree.taxon(i).parent)
+                .map_or_else(Rstr::na, |i| u8_to_rstr(tree.taxon(i).taxid.clone())),
+        );
+        taxid.push(u8_to_rstr(report.taxid));
+        rank.push(u8_to_rstr(report.rank));
+        taxon.push(u8_to_rstr(report.taxon));
+        ranks.push(Robj::from(u8_to_list_rstr(report.ranks)));
+        taxids.push(Robj::from(u8_to_list_rstr(report.taxids)));
+        taxa.push(Robj::from(u8_to_list_rstr(report.taxa)));
+    }
+    Ok(list![
+        taxid = taxid,
+        parent = parent,
+        rank = rank,
+        taxon = taxon,
+        ranks = List::from_values(ranks),
+        taxids = List::from_values(taxids),
+        taxa = List::from_values(taxa)
+    ])
+}
+
+extendr_module! {
+    mod taxonomy;
+    fn read_taxo;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kreport(taxid: &str, rank: &str, level: usize, lineage: &[&str]) -> Kreport {
+        Kreport {
+            percents: 0.0,
+            total_reads: 0,
+            reads: 0,
+            minimizer_len: None,
+            minimizer_n_unique: None,
+            kmers: None,
+            dup: None,
+            cov: None,
+            rank: rank.as_bytes().to_vec(),
+            taxid: taxid.as_bytes().to_vec(),
+            taxon: Vec::new(),
+            ranks: Vec::new(),
+            taxids: lineage.iter().map(|t| t.as_bytes().to_vec()).collect(),
+            taxa: Vec::new(),
+            level,
+        }
+    }
+
+    fn taxids(tree: &TaxonomyTree, taxa: Vec<usize>) -> Vec<&str> {
+        taxa.into_iter()
+            .map(|i| std::str::from_utf8(&tree.taxon(i).taxid).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_taxonomy_tree() {
+        let kreports = vec![
+            kreport("1", "R", 0, &["1"]),
+            kreport("2", "D", 1, &["2"]),
+            kreport("561", "G", 2, &["2", "561"]),
+            kreport("562", "S", 3, &["2", "561", "562"]),
+            kreport("83333", "S1", 4, &["2", "561", "562", "83333"]),
+            kreport("1279", "G", 2, &["2", "1279"]),
+            kreport("1280", "S", 3, &["2", "1279", "1280"]),
+            kreport("10239", "D", 1, &["10239"]),
+        ];
+        let tree = TaxonomyTree::from_kreports(&kreports);
+        assert_eq!(tree.len(), 8);
+        let strain = tree.get(b"83333").unwrap();
+        assert_eq!(
+            taxids(&tree, tree.ancestors(strain).collect()),
+            vec!["83333", "562", "561", "2", "1"]
+        );
+        assert_eq!(
+            taxids(&tree, tree.descendants(tree.get(b"561").unwrap())),
+            vec!["561", "562", "83333"]
+        );
+        assert_eq!(tree.descendants(0).len(), 8);
+        assert_eq!(
+            taxids(&tree, tree.children(1).to_vec()),
+            vec!["561", "1279"]
+        );
+
+        let aureus = tree.get(b"1280").unwrap();
+        assert_eq!(tree.lca(strain, aureus), tree.get(b"2"));
+        assert_eq!(tree.lca(strain, 7), Some(0));
+        assert_eq!(tree.lca(strain, strain), Some(strain));
+        assert_eq!(tree.rank_at(strain, b"G"), tree.get(b"561"));
+        assert_eq!(tree.rank_at(aureus, b"K"), None);
+
+        // Overlapping clades are only listed once
+        let clades = tree.clades([2, 1, 3]);
+        assert_eq!(clades.len(), 6);
+        assert_eq!(tree.clade_taxids([5, 7]).len(), 3);
+
+        // Without their parent, taxa are the roots of their own tree
+        let filtered = vec![
+            kreports[2].clone(),
+            kreports[3].clone(),
+            kreports[6].clone(),
+        ];
+        let tree = TaxonomyTree::from_kreports(&filtered);
+        assert_eq!(tree.taxon(0).parent, None);
+        assert_eq!(tree.taxon(1).parent, Some(0));
+        assert_eq!(tree.taxon(2).parent, None);
+        assert_eq!(tree.lca(1, 2), None);
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use memchr::memmem;
+use rustc_hash::FxHashMap as HashMap;
+
+use super::{rank_code, Taxon, TaxonomyTree};
+use crate::reader::LineReader;
+use crate::utils::BUFFER_SIZE;
+
+/// Reads the NCBI taxonomy of a taxdump directory, from its `nodes.dmp` and
+/// the scientific names of its `names.dmp`. The NCBI ranks are converted to
+/// kraken2 rank codes.
+pub(crate) fn read_taxdump<P: AsRef<Path> + ?Sized>(taxdump: &P) -> Result<TaxonomyTree> {
+    let dir: &Path = taxdump.as_ref();
+    let mut names = HashMap::default();
+    read_dmp(&dir.join("names.dmp"), 4, |fields| {
+        if fields[3] == b"scientific name" {
+            names.insert(fields[0].to_vec(), fields[1].to_vec());
+        }
+    })?;
+    // taxid, parent taxid and rank of each node
+    let mut nodes: Vec<(Vec<u8>, Vec<u8>, Vec<u8>)> = Vec::new();
+    let nodes_file = dir.join("nodes.dmp");
+    read_dmp(&nodes_file, 3, |fields| {
+        nodes.push((fields[0].to_vec(), fields[1].to_vec(), fields[2].to_vec()));
+    })?;
+    if nodes.is_empty() {
+        return Err(anyhow!("No taxa found in: '{}'", nodes_file.display()));
+    }
+
+    // The root is its own parent
+    let positions = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, (taxid, _, _))| (taxid.as_slice(), i))
+        .collect::<HashMap<&[u8], usize>>();
+    let mut children = vec![Vec::new(); nodes.len()];
+    let mut queue = VecDeque::new();
+    for (i, (_, parent, _)) in nodes.iter().enumerate() {
+        match positions.get(parent.as_slice()) {
+            Some(&parent) if parent != i => children[parent].push(i),
+            _ => queue.push_back((i, None)),
+        }
+    }
+
+    // Walk the nodes breadth-first, so that parents come before children
+    let mut taxa: Vec<Taxon> = Vec::with_capacity(nodes.len());
+    while let Some((i, parent)) = queue.pop_front() {
+        let (taxid, _, rank) = &nodes[i];
+        let parent_rank = parent.map(|parent: usize| taxa[parent].rank.as_slice());
+        let taxon = Taxon {
+            rank: rank_code(rank, taxid, parent_rank),
+            name: names.remove(taxid).unwrap_or_default(),
+            taxid: taxid.clone(),
+            parent,
+            depth: 0,
+        };
+        let index = taxa.len();
+        taxa.push(taxon);
+        queue.extend(children[i].iter().map(|&child| (child, Some(index))));
+    }
+    Ok(TaxonomyTree::new(taxa))
+}
+
+/// Calls `f` with the fields of each line of a `.dmp` file, lines have at
+/// least `n_fields` fields separated by `\t|\t` and end with `\t|`
+fn read_dmp<F>(path: &Path, n_fields: usize, mut f: F) -> Result<()>
+where
+    F: FnMut(&[&[u8]]),
+{
+    let mut reader = LineReader::with_capacity(
+        BUFFER_SIZE,
+        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?,
+    );
+    let finder = memmem::Finder::new(b"\t|\t");
+    while let Some(line) = reader.read_line()? {
+        if line.iter().all(|b| b.is_ascii_whitespace()) {
+            continue;
+        }
+        let line = line.strip_suffix(b"\t|").unwrap_or(&line);
+        let mut fields = Vec::with_capacity(n_fields);
+        let mut start = 0;
+        for pos in finder.find_iter(line) {
+            fields.push(&line[start .. pos]);
+            start = pos + 3;
+        }
+        fields.push(&line[start ..]);
+        if fields.len() < n_fields {
+            return Err(anyhow!(
+                "Invalid line with {} fields: {:?}",
+                fields.len(),
+                String::from_utf8_lossy(line)
+            ))
+            .with_context(|| format!("Failed to parse taxdump file: '{}'", path.display()));
+        }
+        f(&fields);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_read_taxdump() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut nodes = File::create(dir.path().join("nodes.dmp"))?;
+        // Children listed before their parent
+        nodes.write_all(
+            concat!(
+                "562\t|\t561\t|\tspecies\t|\tEB\t|\n",
+                "83333\t|\t562\t|\tstrain\t|\t\t|\n",
+                "1\t|\t1\t|\tno rank\t|\t\t|\n",
+                "131567\t|\t1\t|\tcellular root\t|\t\t|\n",
+                "2\t|\t131567\t|\tdomain\t|\t\t|\n",
+                "561\t|\t543\t|\tgenus\t|\t\t|\n",
+                "543\t|\t2\t|\tfamily\t|\t\t|\n",
+            )
+            .as_bytes(),
+        )?;
+        let mut names = File::create(dir.path().join("names.dmp"))?;
+        names.write_all(
+            concat!(
+                "1\t|\troot\t|\t\t|\tscientific name\t|\n",
+                "2\t|\tBacteria\t|\tBacteria <bacteria>\t|\tscientific name\t|\n",
+                "2\t|\teubacteria\t|\t\t|\tgenbank common name\t|\n",
+                "562\t|\tEscherichia coli\t|\t\t|\tscientific name\t|\n",
+            )
+            .as_bytes(),
+        )?;
+
+        let tree = read_taxdump(dir.path())?;
+        assert_eq!(tree.len(), 7);
+        let strain = tree.get(b"83333").unwrap();
+        let ranks = tree
+            .ancestors(strain)
+            .map(|i| String::from_utf8(tree.taxon(i).rank.clone()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ranks, vec!["S1", "S", "G", "F", "D", "R1", "R"]);
+        assert_eq!(tree.taxon(tree.get(b"2").unwrap()).name, b"Bacteria");
+        assert_eq!(
+            tree.taxon(tree.get(b"562").unwrap()).name,
+            b"Escherichia coli"
+        );
+        let lineage = tree
+            .lineage(strain)
+            .into_iter()
+            .map(|i| String::from_utf8(tree.taxon(i).taxid.clone()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lineage, vec!["2", "543", "561", "562", "83333"]);
+
+        assert!(read_taxdump(&dir.path().join("missing")).is_err());
+        Ok(())
+    }
+}
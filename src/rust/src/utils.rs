@@ -1,21 +1,26 @@
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, BufWriter};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
+use bzip2::bufread::MultiBzDecoder;
 use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
 use extendr_api::prelude::*;
 #[cfg(not(feature = "isal"))]
-use flate2::bufread::GzDecoder;
+use flate2::bufread::MultiGzDecoder;
 use indicatif::style::TemplateError;
-use indicatif::ProgressBar;
+use indicatif::{ProgressBar, ProgressFinish};
 use indicatif::ProgressStyle;
 #[cfg(feature = "isal")]
 use isal::read::GzipDecoder;
-use libdeflater::Compressor;
+use libdeflater::{CompressionLvl, Compressor};
+use liblzma::bufread::XzDecoder;
 use memchr::memmem::Finder;
 
+use crate::bgzf::*;
+use crate::parallel_gzip::ParallelGzipReader;
 use crate::reader::*;
 
 pub(crate) const BLOCK_SIZE: usize = 8 * 1024 * 1024;
@@ -54,10 +59,96 @@ pub(crate) fn u8_to_rstr(bytes: Vec<u8>) -> Rstr {
     Rstr::from_string(&unsafe { String::from_utf8_unchecked(bytes) })
 }
 
-pub(crate) fn gz_compressed(path: &Path) -> bool {
-    path.extension()
-        .and_then(|e| e.to_str())
-        .map_or(false, |s| s.eq_ignore_ascii_case("gz"))
+/// Options of the output writers
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct OutputOptions {
+    /// Write `.gz` outputs as BGZF blocks
+    pub(crate) bgzf: bool,
+    /// Write a `.gzi` index next to BGZF outputs
+    pub(crate) bgzf_index: bool,
+    /// Write the records in input order, whatever the number of threads
+    pub(crate) ordered: bool,
+    /// Split the outputs into shards
+    pub(crate) sharding: Option<Sharding>,
+}
+
+impl OutputOptions {
+    /// Whether a parser pool of `records` records must be sent as a chunk
+    /// before the next record: chunks never hold more records than a shard.
+    pub(crate) fn chunk_full(&self, records: usize) -> bool {
+        matches!(self.sharding, Some(Sharding::Records(limit)) if records >= limit)
+    }
+
+    /// The `batch_size` and `chunk_bytes` of a command, capped so that a chunk
+    /// (a batch in ordered mode) fits in a shard
+    pub(crate) fn chunk_sizes(&self, batch_size: usize, chunk_bytes: usize) -> (usize, usize) {
+        match self.sharding {
+            Some(Sharding::Records(limit)) if self.ordered => (batch_size.min(limit), chunk_bytes),
+            Some(Sharding::Bytes(limit)) => (batch_size, chunk_bytes.min(limit)),
+            _ => (batch_size, chunk_bytes),
+        }
+    }
+}
+
+/// How an output is split into shards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Sharding {
+    /// Chunks are dealt in turn into this many shards
+    Count(usize),
+    /// A new shard is started once a shard holds this many records
+    Records(usize),
+    /// A new shard is started once a shard holds this many bytes
+    Bytes(usize),
+}
+
+impl Sharding {
+    pub(crate) fn parse(
+        shards: Option<usize>,
+        shard_records: Option<usize>,
+        shard_bytes: Option<usize>,
+    ) -> Result<Option<Self>> {
+        match (shards, shard_records, shard_bytes) {
+            (None, None, None) => Ok(None),
+            (Some(n), None, None) if n > 0 => Ok(Some(Self::Count(n))),
+            (None, Some(n), None) if n > 0 => Ok(Some(Self::Records(n))),
+            (None, None, Some(n)) if n > 0 => Ok(Some(Self::Bytes(n))),
+            _ => Err(anyhow!(
+                "Only one of 'shards', 'shard_records' and 'shard_bytes' can be used, with a positive value"
+            )),
+        }
+    }
+}
+
+/// Chunk: Encoded records sent by a parser thread to a writer thread.
+#[derive(Debug, Default)]
+pub(crate) struct Chunk {
+    /// The records, compressed as the output
+    pub(crate) bytes: Vec<u8>,
+    /// The number of records
+    pub(crate) records: usize,
+}
+
+/// Compression of an output file, chosen by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputCompression {
+    None,
+    Gzip,
+    Bgzf,
+    Zstd,
+}
+
+pub(crate) fn output_compression(path: &Path, options: OutputOptions) -> OutputCompression {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("gz") => {
+            if options.bgzf {
+                OutputCompression::Bgzf
+            } else {
+                OutputCompression::Gzip
+            }
+        }
+        Some(ext) if ext.eq_ignore_ascii_case("zst") => OutputCompression::Zstd,
+        _ => OutputCompression::None,
+    }
 }
 
 pub(crate) fn gzip_pack(bytes: &[u8], compressor: &mut Compressor) -> Result<Vec<u8>> {
@@ -69,13 +160,105 @@ pub(crate) fn gzip_pack(bytes: &[u8], compressor: &mut Compressor) -> Result<Vec
     Ok(pack)
 }
 
+/// ChunkCompressor: Compresses the chunks sent to a writer thread.
+///
+/// Each chunk becomes complete gzip members, BGZF blocks or a zstd frame, so
+/// chunks compressed by different parser threads can simply be concatenated.
+pub(crate) struct ChunkCompressor {
+    gzip: Option<Compressor>,
+    bgzf: bool,
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+}
+
+impl ChunkCompressor {
+    pub(crate) fn new(compression: OutputCompression, level: CompressionLvl) -> Result<Self> {
+        let mut out = Self {
+            gzip: None,
+            bgzf: false,
+            zstd: None,
+        };
+        match compression {
+            OutputCompression::None => {}
+            OutputCompression::Gzip => out.gzip = Some(Compressor::new(level)),
+            OutputCompression::Bgzf => {
+                out.gzip = Some(Compressor::new(level));
+                out.bgzf = true;
+            }
+            OutputCompression::Zstd => {
+                out.zstd = Some(
+                    zstd::bulk::Compressor::new(level.into())
+                        .with_context(|| format!("Failed to create zstd compressor"))?,
+                )
+            }
+        }
+        Ok(out)
+    }
+
+    pub(crate) fn pack(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        if bytes.is_empty() {
+            // Nothing to compress, avoid writing empty members
+            Ok(bytes)
+        } else if let Some(compressor) = &mut self.gzip {
+            if self.bgzf {
+                bgzf_pack(&bytes, compressor)
+            } else {
+                gzip_pack(&bytes, compressor)
+            }
+        } else if let Some(compressor) = &mut self.zstd {
+            compressor
+                .compress(&bytes)
+                .with_context(|| format!("Failed to compress chunk with zstd"))
+        } else {
+            Ok(bytes)
+        }
+    }
+}
+
+/// The path standing for the standard input or output
+pub(crate) const STDIO_PATH: &str = "-";
+
+pub(crate) fn is_stdio<P: AsRef<Path> + ?Sized>(file: &P) -> bool {
+    file.as_ref().as_os_str() == STDIO_PATH
+}
+
+/// Ensures the standard input (or output) is used by at most one of `paths`,
+/// since the stream can only be consumed once.
+pub(crate) fn check_stdio<'a, I: IntoIterator<Item = &'a str>>(paths: I) -> Result<()> {
+    if paths.into_iter().filter(|path| is_stdio(*path)).count() > 1 {
+        return Err(anyhow!(
+            "'{}' (standard input or output) can only be used once",
+            STDIO_PATH
+        ));
+    }
+    Ok(())
+}
+
+/// Opens a file for reading without decompression, `-` reads the standard
+/// input. Named pipes are opened as regular files.
+pub(crate) fn open_input<P: AsRef<Path> + ?Sized>(file: &P) -> Result<Box<dyn Read + Send>> {
+    let path: &Path = file.as_ref();
+    if is_stdio(path) {
+        return Ok(Box::new(std::io::stdin()));
+    }
+    let file =
+        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    Ok(Box::new(file))
+}
+
+/// Creates the output file, `-` writes to the standard output.
 pub(crate) fn new_writer<P: AsRef<Path> + ?Sized>(
     file: &P,
     progress_bar: Option<ProgressBar>,
 ) -> Result<Box<dyn Write>> {
     let path: &Path = file.as_ref();
-    let file = File::create(path)
-        .with_context(|| format!("Failed to create output file {}", path.display()))?;
+    let file: Box<dyn Write> = if is_stdio(path) {
+        Box::new(std::io::stdout())
+    } else {
+        Box::new(
+            File::create(path)
+                .with_context(|| format!("Failed to create output file {}", path.display()))?,
+        )
+    };
     let writer: Box<dyn Write>;
     if let Some(bar) = progress_bar {
         writer = Box::new(ProgressBarWriter::new(file, bar));
@@ -85,63 +268,434 @@ pub(crate) fn new_writer<P: AsRef<Path> + ?Sized>(
     Ok(writer)
 }
 
-#[cfg(feature = "isal")]
-pub(crate) fn new_reader<P: AsRef<Path> + ?Sized>(
-    file: &P,
-    buffer_size: usize,
-    progress_bar: Option<ProgressBar>,
-) -> Result<Box<dyn Read>> {
-    let path: &Path = file.as_ref();
-    let file =
-        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
-    let reader: Box<dyn Read>;
-    if gz_compressed(path) {
-        if let Some(bar) = progress_bar {
-            reader = Box::new(GzipDecoder::new(BufReader::with_capacity(
-                buffer_size,
-                ProgressBarReader::new(file, bar),
-            )));
-        } else {
-            reader = Box::new(GzipDecoder::new(BufReader::with_capacity(
-                buffer_size,
-                file,
-            )));
+// Magic bytes of the supported compression formats, BGZF is a gzip variant
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Whether `magic`, the leading bytes of a file, starts a compressed stream
+pub(crate) fn is_compressed(magic: &[u8]) -> bool {
+    [GZIP_MAGIC, ZSTD_MAGIC, BZIP2_MAGIC, XZ_MAGIC]
+        .iter()
+        .any(|compressed| magic.starts_with(compressed))
+}
+
+/// OutputWriter: Writes the chunks of the parser threads to an output file.
+///
+/// The chunks are written into a temporary sibling of the output file, only
+/// moved to the output path by [`commit_outputs`] once all threads succeeded.
+/// The standard output and existing special files, like named pipes, are
+/// written in place.
+///
+/// BGZF outputs are terminated with the BGZF EOF block when finished, along
+/// with their `.gzi` index if requested.
+pub(crate) struct OutputWriter {
+    path: PathBuf,
+    writer: BufWriter<Box<dyn Write>>,
+    bgzf: bool,
+    index: Option<BgzfIndex>,
+    // Declared after `writer`, so the file is closed before being removed
+    output: PendingOutput,
+}
+
+impl OutputWriter {
+    pub(crate) fn new<P: AsRef<Path> + ?Sized>(
+        file: &P,
+        progress_bar: Option<ProgressBar>,
+        chunk_bytes: usize,
+        options: OutputOptions,
+    ) -> Result<Self> {
+        let path: &Path = file.as_ref();
+        let bgzf = output_compression(path, options) == OutputCompression::Bgzf;
+        if options.bgzf_index && is_stdio(path) {
+            return Err(anyhow!("Cannot write the BGZF index of the standard output"));
         }
-    } else {
-        if let Some(bar) = progress_bar {
-            reader = Box::new(ProgressBarReader::new(file, bar));
+        let index = if bgzf && options.bgzf_index {
+            Some(BgzfIndex::new())
         } else {
-            reader = Box::new(file);
+            None
+        };
+        let mut output = PendingOutput::default();
+        let writer = new_writer(&output.add(path), progress_bar)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: BufWriter::with_capacity(chunk_bytes, writer),
+            bgzf,
+            index,
+            output,
+        })
+    }
+
+    pub(crate) fn write_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        if let Some(index) = &mut self.index {
+            index.add_blocks(chunk)?;
         }
+        self.writer.write_all(chunk)?;
+        Ok(())
     }
-    Ok(reader)
+
+    /// Completes the output, which still has to be committed with
+    /// [`commit_outputs`].
+    pub(crate) fn finish(mut self) -> Result<PendingOutput> {
+        if self.bgzf {
+            self.writer.write_all(&BGZF_EOF)?;
+        }
+        self.writer.flush()?;
+        let Self {
+            path,
+            writer,
+            index,
+            mut output,
+            ..
+        } = self;
+        drop(writer);
+        if let Some(index) = index {
+            let mut path = path.into_os_string();
+            path.push(".gzi");
+            let mut writer = BufWriter::new(new_writer(&output.add(path), None)?);
+            index
+                .write(&mut writer)
+                .and_then(|_| writer.flush())
+                .with_context(|| format!("Failed to write BGZF index"))?;
+        }
+        Ok(output)
+    }
+}
+
+/// The placeholder of the shard number in the output file names
+const SHARD_PLACEHOLDER: &str = "{shard}";
+
+/// The path of a shard, with the placeholder of the `template` file name
+/// replaced by the shard number, counted from 1
+pub(crate) fn shard_path(template: &Path, shard: usize) -> Result<PathBuf> {
+    let name = template
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| name.contains(SHARD_PLACEHOLDER))
+        .ok_or_else(|| {
+            anyhow!(
+                "Output file name must contain '{}' to be split into shards: {}",
+                SHARD_PLACEHOLDER,
+                template.display()
+            )
+        })?;
+    Ok(template.with_file_name(name.replace(SHARD_PLACEHOLDER, &format!("{:03}", shard + 1))))
+}
+
+/// ShardCounter: Picks the shard each chunk of an output is written to.
+///
+/// Chunks are never split, so a shard is closed before the chunk that would
+/// take it over its limit of records or bytes. Chunks are capped to fit in a
+/// shard (see [`OutputOptions::chunk_full`] and
+/// [`OutputOptions::chunk_sizes`]), so shards hold at most the limit, and
+/// exactly the limit of records while the parser threads send full chunks. A
+/// compressed chunk may still exceed a tiny byte limit, it then fills a shard
+/// alone. The mates of paired-end reads are sent in the same chunks, so a
+/// single counter keeps their shards in step.
+pub(crate) struct ShardCounter {
+    sharding: Option<Sharding>,
+    shard: usize,
+    chunks: usize,
+    size: usize,
+}
+
+impl ShardCounter {
+    pub(crate) fn new(sharding: Option<Sharding>) -> Self {
+        Self {
+            sharding,
+            shard: 0,
+            chunks: 0,
+            size: 0,
+        }
+    }
+
+    /// The shard of the next chunk, given as the chunks of each mate
+    pub(crate) fn next<'a, I: IntoIterator<Item = &'a Chunk>>(&mut self, chunks: I) -> usize {
+        let (records, bytes) = chunks.into_iter().fold((0, 0), |(records, bytes), chunk| {
+            (records.max(chunk.records), bytes.max(chunk.bytes.len()))
+        });
+        // Empty chunks, sent in ordered mode, are not counted
+        if records == 0 {
+            return self.shard;
+        }
+        let (size, limit) = match self.sharding {
+            None => return 0,
+            Some(Sharding::Count(n)) => {
+                self.shard = self.chunks % n;
+                self.chunks += 1;
+                return self.shard;
+            }
+            Some(Sharding::Records(limit)) => (records, limit),
+            Some(Sharding::Bytes(limit)) => (bytes, limit),
+        };
+        if self.size > 0 && self.size + size > limit {
+            self.shard += 1;
+            self.size = 0;
+        }
+        self.size += size;
+        self.shard
+    }
+}
+
+/// ShardedWriter: Writes an output split into shards.
+///
+/// The output path is a template, each shard is written to the path given by
+/// [`shard_path`]. With [`Sharding::Count`], all shards are created upfront,
+/// even if some stay empty. Otherwise shards are filled one after the other.
+/// Without sharding, the output path is written as is.
+pub(crate) struct ShardedWriter {
+    template: PathBuf,
+    progress_bar: Option<ProgressBar>,
+    chunk_bytes: usize,
+    options: OutputOptions,
+    // Open shards, with their number
+    writers: Vec<(usize, OutputWriter)>,
+    output: PendingOutput,
+}
+
+impl ShardedWriter {
+    pub(crate) fn new<P: AsRef<Path> + ?Sized>(
+        file: &P,
+        progress_bar: Option<ProgressBar>,
+        chunk_bytes: usize,
+        options: OutputOptions,
+    ) -> Result<Self> {
+        let mut out = Self {
+            template: file.as_ref().to_path_buf(),
+            progress_bar,
+            chunk_bytes,
+            options,
+            writers: Vec::new(),
+            output: PendingOutput::default(),
+        };
+        match options.sharding {
+            None => {
+                let writer =
+                    OutputWriter::new(&out.template, out.progress_bar.clone(), chunk_bytes, options)?;
+                out.writers.push((0, writer));
+            }
+            Some(Sharding::Count(n)) => {
+                for shard in 0 .. n {
+                    out.open(shard)?;
+                }
+            }
+            Some(_) => out.open(0)?,
+        }
+        Ok(out)
+    }
+
+    fn open(&mut self, shard: usize) -> Result<()> {
+        let path = shard_path(&self.template, shard)?;
+        let writer = OutputWriter::new(
+            &path,
+            self.progress_bar.clone(),
+            self.chunk_bytes,
+            self.options,
+        )?;
+        self.writers.push((shard, writer));
+        Ok(())
+    }
+
+    pub(crate) fn write_chunk(&mut self, shard: usize, chunk: &[u8]) -> Result<()> {
+        if matches!(
+            self.options.sharding,
+            Some(Sharding::Records(_) | Sharding::Bytes(_))
+        ) && self.writers.last().is_some_and(|(open, _)| *open != shard)
+        {
+            // The former shard is complete
+            if let Some((_, writer)) = self.writers.pop() {
+                self.output.merge(writer.finish()?);
+            }
+            self.open(shard)?;
+        }
+        let (_, writer) = self
+            .writers
+            .iter_mut()
+            .find(|(open, _)| *open == shard)
+            .ok_or_else(|| anyhow!("Shard {} is not open", shard + 1))?;
+        writer.write_chunk(chunk)
+    }
+
+    /// Completes all shards, which still have to be committed with
+    /// [`commit_outputs`].
+    pub(crate) fn finish(mut self) -> Result<PendingOutput> {
+        for (_, writer) in std::mem::take(&mut self.writers) {
+            self.output.merge(writer.finish()?);
+        }
+        Ok(std::mem::take(&mut self.output))
+    }
+}
+
+/// PendingOutput: Output files written under a temporary name.
+///
+/// Dropping it before [`commit_outputs`] removes the temporary files, so that
+/// a failed run leaves no truncated output behind.
+#[derive(Debug, Default)]
+pub(crate) struct PendingOutput {
+    // Pairs of temporary and final paths
+    files: Vec<(PathBuf, PathBuf)>,
+}
+
+impl PendingOutput {
+    /// Registers an output file, returning the path to write into.
+    fn add<P: AsRef<Path>>(&mut self, file: P) -> PathBuf {
+        let path: &Path = file.as_ref();
+        // Special files, like named pipes or `/dev/null`, cannot be replaced
+        if is_stdio(path) || std::fs::metadata(path).is_ok_and(|metadata| !metadata.is_file()) {
+            return path.to_path_buf();
+        }
+        let mut name = std::ffi::OsString::from(".");
+        name.push(path.file_name().unwrap_or_default());
+        name.push(format!(".{}.tmp", std::process::id()));
+        let temporary = path.with_file_name(name);
+        self.files.push((temporary.clone(), path.to_path_buf()));
+        temporary
+    }
+
+    /// Takes over the files of `other`, to be committed together.
+    fn merge(&mut self, mut other: PendingOutput) {
+        self.files.append(&mut other.files);
+    }
+}
+
+impl Drop for PendingOutput {
+    fn drop(&mut self) {
+        for (temporary, _) in &self.files {
+            // Just omit the Error message
+            let _ = std::fs::remove_file(temporary);
+        }
+    }
+}
+
+/// Moves the temporary files of all `outputs` to their final paths. Outputs
+/// are committed together: if one cannot be moved, the ones already moved are
+/// removed.
+pub(crate) fn commit_outputs<I: IntoIterator<Item = PendingOutput>>(outputs: I) -> Result<()> {
+    let files = outputs
+        .into_iter()
+        .flat_map(|mut output| std::mem::take(&mut output.files))
+        .collect::<Vec<_>>();
+    for (i, (temporary, path)) in files.iter().enumerate() {
+        if let Err(e) = std::fs::rename(temporary, path) {
+            for (_, committed) in &files[.. i] {
+                let _ = std::fs::remove_file(committed);
+            }
+            for (temporary, _) in &files[i ..] {
+                let _ = std::fs::remove_file(temporary);
+            }
+            return Err(e).with_context(|| format!("Failed to move output to {}", path.display()));
+        }
+    }
+    Ok(())
+}
+
+/// OrderedChunks: Restores the input order of the chunks sent by the parser
+/// threads.
+///
+/// In ordered mode, every input batch yields exactly one chunk, tagged with
+/// the index of the batch. A chunk is held back until the chunks of all
+/// former batches are released. Otherwise chunks are released on arrival.
+pub(crate) struct OrderedChunks<T> {
+    ordered: bool,
+    next: usize,
+    pending: BTreeMap<usize, T>,
+}
+
+impl<T> OrderedChunks<T> {
+    pub(crate) fn new(ordered: bool) -> Self {
+        Self {
+            ordered,
+            next: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn push<F>(&mut self, index: usize, chunk: T, mut release: F) -> Result<()>
+    where
+        F: FnMut(T) -> Result<()>,
+    {
+        if !self.ordered {
+            return release(chunk);
+        }
+        if index < self.next || self.pending.insert(index, chunk).is_some() {
+            return Err(anyhow!("Received batch {} twice", index));
+        }
+        while let Some(chunk) = self.pending.remove(&self.next) {
+            self.next += 1;
+            release(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Ensures no chunk is left behind a missing batch.
+    pub(crate) fn finish(self) -> Result<()> {
+        if let Some(index) = self.pending.keys().next() {
+            return Err(anyhow!(
+                "Missing batch {} before batch {}",
+                self.next,
+                index
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "isal")]
+fn gzip_decoder<R: BufRead + 'static>(reader: R) -> Box<dyn Read> {
+    Box::new(GzipDecoder::new(reader))
 }
 
 #[cfg(not(feature = "isal"))]
+fn gzip_decoder<R: BufRead + 'static>(reader: R) -> Box<dyn Read> {
+    // Decode all members: BGZF and concatenated gzip files
+    Box::new(MultiGzDecoder::new(reader))
+}
+
+/// Threads decompressing each of `inputs` files read at once, out of the
+/// `threads` of a command: half of them are left to the parsing threads.
+pub(crate) fn decode_threads(threads: usize, inputs: usize) -> usize {
+    (threads / 2 / inputs.max(1)).max(1)
+}
+
+/// Opens a file for reading, the compression (gzip, BGZF, zstd, bzip2 or xz)
+/// is detected from the leading magic bytes rather than the file extension.
+/// With several `threads`, gzip and BGZF members are inflated in parallel.
 pub(crate) fn new_reader<P: AsRef<Path> + ?Sized>(
     file: &P,
     buffer_size: usize,
     progress_bar: Option<ProgressBar>,
+    threads: usize,
 ) -> Result<Box<dyn Read>> {
     let path: &Path = file.as_ref();
-    let file =
-        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let file = open_input(path)?;
+    let file: Box<dyn Read + Send> = if let Some(bar) = progress_bar {
+        Box::new(ProgressBarReader::new(file, bar))
+    } else {
+        Box::new(file)
+    };
+    let mut buffer = BufReader::with_capacity(buffer_size, file);
+    let magic = buffer
+        .fill_buf()
+        .with_context(|| format!("Failed to read file: {}", path.display()))?;
     let reader: Box<dyn Read>;
-    if gz_compressed(path) {
-        if let Some(bar) = progress_bar {
-            reader = Box::new(GzDecoder::new(BufReader::with_capacity(
-                buffer_size,
-                ProgressBarReader::new(file, bar),
-            )));
+    if magic.starts_with(GZIP_MAGIC) {
+        if threads > 1 {
+            reader = Box::new(ParallelGzipReader::new(buffer, threads));
         } else {
-            reader = Box::new(GzDecoder::new(BufReader::with_capacity(buffer_size, file)));
+            reader = gzip_decoder(buffer);
         }
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        reader = Box::new(
+            zstd::Decoder::with_buffer(buffer)
+                .with_context(|| format!("Failed to open zstd file: {}", path.display()))?,
+        );
+    } else if magic.starts_with(BZIP2_MAGIC) {
+        reader = Box::new(MultiBzDecoder::new(buffer));
+    } else if magic.starts_with(XZ_MAGIC) {
+        reader = Box::new(XzDecoder::new_multi_decoder(buffer));
     } else {
-        if let Some(bar) = progress_bar {
-            reader = Box::new(ProgressBarReader::new(file, bar));
-        } else {
-            reader = Box::new(file);
-        }
+        reader = Box::new(buffer);
     }
     Ok(reader)
 }
@@ -170,8 +724,300 @@ pub(crate) fn progress_reader_style() -> std::result::Result<ProgressStyle, Temp
     )
 }
 
+/// Creates the progress bar of an input file, sized by its length. The standard
+/// input, named pipes and other unsized inputs fall back to a spinner.
+pub(crate) fn input_progress_bar<P: AsRef<Path> + ?Sized>(file: &P) -> Result<ProgressBar> {
+    inputs_progress_bar(&[file.as_ref()])
+}
+
+/// Creates a single progress bar for several input files read in order,
+/// sized by their total length.
+pub(crate) fn inputs_progress_bar<P: AsRef<Path>>(files: &[P]) -> Result<ProgressBar> {
+    let mut length = Some(0);
+    for file in files {
+        let path: &Path = file.as_ref();
+        let file_length = if is_stdio(path) {
+            None
+        } else {
+            let metadata = std::fs::metadata(path)
+                .with_context(|| format!("Failed to open file: {}", path.display()))?;
+            metadata.is_file().then_some(metadata.len())
+        };
+        length = length.zip(file_length).map(|(total, file_length)| total + file_length);
+    }
+    let bar = if let Some(length) = length {
+        ProgressBar::new(length).with_style(progress_reader_style()?)
+    } else {
+        ProgressBar::no_length().with_style(progress_spinner_style()?)
+    };
+    Ok(bar.with_finish(ProgressFinish::Abandon))
+}
+
+/// Checks that the mates of paired-end reads are split into the same number
+/// of files, which are paired in order
+pub(crate) fn check_mate_files<P: AsRef<Path>>(files1: &[P], files2: Option<&[P]>) -> Result<()> {
+    if let Some(files2) = files2 {
+        if files1.len() != files2.len() {
+            return Err(anyhow!(
+                "Both mates must have the same number of files (read1: {}, read2: {})",
+                files1.len(),
+                files2.len()
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn progress_spinner_style() -> std::result::Result<ProgressStyle, TemplateError> {
+    ProgressStyle::with_template(
+        "{prefix:.bold.cyan/blue} {decimal_bytes} {spinner:.green} [{elapsed_precise}] {decimal_bytes_per_sec}",
+    )
+}
+
 pub(crate) fn progress_writer_style() -> std::result::Result<ProgressStyle, TemplateError> {
     ProgressStyle::with_template(
         "{prefix:.bold.cyan/blue} {decimal_bytes} {spinner:.green} {decimal_bytes_per_sec}",
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_new_reader_detects_compression() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let data = b"@r1\nACGT\n+\n!!!!\n";
+
+        let mut gzip = ChunkCompressor::new(OutputCompression::Gzip, CompressionLvl::default())?;
+        let mut zstd = ChunkCompressor::new(OutputCompression::Zstd, CompressionLvl::default())?;
+        let mut bzip2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bzip2.write_all(data)?;
+        let mut xz = liblzma::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(data)?;
+        let files = [
+            ("plain.fq", data.to_vec()),
+            // Two members, as written by parser threads, under a misleading extension
+            ("gzip.fq", [gzip.pack(data[.. 4].to_vec())?, gzip.pack(data[4 ..].to_vec())?].concat()),
+            ("zstd.fq", [zstd.pack(data[.. 4].to_vec())?, zstd.pack(data[4 ..].to_vec())?].concat()),
+            ("bzip2.fq", bzip2.finish()?),
+            ("xz.fq", xz.finish()?),
+        ];
+        for (name, content) in files {
+            let path = tmp.path().join(name);
+            std::fs::write(&path, content)?;
+            let mut out = Vec::new();
+            new_reader(&path, 1024, None, 2)?.read_to_end(&mut out)?;
+            assert_eq!(out, data, "{}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_compression() {
+        let options = OutputOptions::default();
+        assert_eq!(output_compression(Path::new("a.fq.gz"), options), OutputCompression::Gzip);
+        assert_eq!(output_compression(Path::new("a.koutput.ZST"), options), OutputCompression::Zstd);
+        assert_eq!(output_compression(Path::new("a.fq"), options), OutputCompression::None);
+        let options = OutputOptions {
+            bgzf: true,
+            ..Default::default()
+        };
+        assert_eq!(output_compression(Path::new("a.fq.gz"), options), OutputCompression::Bgzf);
+        assert_eq!(output_compression(Path::new("a.fq.zst"), options), OutputCompression::Zstd);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_read_named_pipe() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("reads.fq");
+        let status = std::process::Command::new("mkfifo").arg(&path).status()?;
+        assert!(status.success());
+        let data = b"@r1\nACGT\n+\n!!!!\n";
+        let pipe = path.clone();
+        let writer = std::thread::spawn(move || std::fs::write(pipe, data));
+
+        // Unsized inputs get a spinner
+        assert_eq!(input_progress_bar(&path)?.length(), None);
+        let mut out = Vec::new();
+        new_reader(&path, 1024, None, 1)?.read_to_end(&mut out)?;
+        writer.join().unwrap()?;
+        assert_eq!(out, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_stdio() {
+        assert!(is_stdio("-"));
+        assert!(!is_stdio("./-"));
+        assert!(check_stdio(["-", "a.fq"]).is_ok());
+        assert!(check_stdio(["-", "a.fq", "-"]).is_err());
+    }
+
+    #[test]
+    fn test_ordered_chunks() -> Result<()> {
+        let mut written = Vec::new();
+        let mut chunks = OrderedChunks::new(true);
+        for index in [2, 0, 3, 1, 4] {
+            chunks.push(index, index, |chunk| {
+                written.push(chunk);
+                Ok(())
+            })?;
+        }
+        chunks.finish()?;
+        assert_eq!(written, vec![0, 1, 2, 3, 4]);
+
+        let mut chunks = OrderedChunks::new(true);
+        chunks.push(1, 1, |_| Ok(()))?;
+        assert!(chunks.push(1, 1, |_| Ok(())).is_err());
+        assert!(chunks.finish().is_err());
+
+        // Without ordering, chunks are released on arrival
+        let mut written = Vec::new();
+        let mut chunks = OrderedChunks::new(false);
+        for index in [2, 0, 1] {
+            chunks.push(index, index, |chunk| {
+                written.push(chunk);
+                Ok(())
+            })?;
+        }
+        assert_eq!(written, vec![2, 0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_writer_commit() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let files = || -> Result<Vec<_>> {
+            let mut files = std::fs::read_dir(tmp.path())?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<std::io::Result<Vec<_>>>()?;
+            files.sort();
+            Ok(files)
+        };
+
+        // Nothing is left behind by a failed or uncommitted writer
+        let path = tmp.path().join("out.fq");
+        let mut writer = OutputWriter::new(&path, None, 1024, OutputOptions::default())?;
+        writer.write_chunk(b"@r1\n")?;
+        assert_eq!(files()?.len(), 1);
+        drop(writer);
+        assert!(files()?.is_empty());
+        let mut writer = OutputWriter::new(&path, None, 1024, OutputOptions::default())?;
+        writer.write_chunk(b"@r1\n")?;
+        drop(writer.finish()?);
+        assert!(files()?.is_empty());
+
+        let mut writer = OutputWriter::new(&path, None, 1024, OutputOptions::default())?;
+        writer.write_chunk(b"@r1\n")?;
+        let output = writer.finish()?;
+        assert!(!path.exists());
+        commit_outputs([output])?;
+        assert_eq!(std::fs::read(&path)?, b"@r1\n");
+        assert_eq!(files()?, vec!["out.fq"]);
+
+        // Paired outputs are committed together, or none of them
+        let path1 = tmp.path().join("out1.fq");
+        let path2 = tmp.path().join("out2.fq");
+        let output1 = OutputWriter::new(&path1, None, 1024, OutputOptions::default())?.finish()?;
+        let output2 = OutputWriter::new(&path2, None, 1024, OutputOptions::default())?.finish()?;
+        // The second output cannot be moved onto a directory
+        std::fs::create_dir(&path2)?;
+        assert!(commit_outputs([output1, output2]).is_err());
+        assert_eq!(files()?, vec!["out.fq", "out2.fq"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shard_counter() {
+        let chunk = |records: usize, bytes: usize| Chunk {
+            bytes: vec![0; bytes],
+            records,
+        };
+        let mut shards = ShardCounter::new(None);
+        assert_eq!(shards.next([&chunk(10, 100)]), 0);
+
+        let mut shards = ShardCounter::new(Some(Sharding::Count(2)));
+        let picked = (0 .. 5)
+            .map(|_| shards.next([&chunk(1, 1)]))
+            .collect::<Vec<_>>();
+        assert_eq!(picked, vec![0, 1, 0, 1, 0]);
+
+        // A shard is closed before the chunk that would take it over the limit
+        let mut shards = ShardCounter::new(Some(Sharding::Records(4)));
+        let picked = [3, 1, 3, 0, 3, 1, 4]
+            .into_iter()
+            .map(|records| shards.next([&chunk(records, 1)]))
+            .collect::<Vec<_>>();
+        assert_eq!(picked, vec![0, 0, 1, 1, 2, 2, 3]);
+
+        // The larger mate counts, a chunk over the limit fills a shard alone
+        let mut shards = ShardCounter::new(Some(Sharding::Bytes(10)));
+        assert_eq!(shards.next([&chunk(1, 4), &chunk(1, 10)]), 0);
+        assert_eq!(shards.next([&chunk(1, 4), &chunk(1, 4)]), 1);
+        assert_eq!(shards.next([&chunk(1, 20)]), 2);
+        assert_eq!(shards.next([&chunk(1, 2)]), 3);
+    }
+
+    #[test]
+    fn test_chunk_limits() {
+        let options = OutputOptions {
+            sharding: Some(Sharding::Records(1000)),
+            ..Default::default()
+        };
+        assert!(!options.chunk_full(999));
+        assert!(options.chunk_full(1000));
+        assert_eq!(options.chunk_sizes(5000, 1 << 20), (5000, 1 << 20));
+        let options = OutputOptions {
+            ordered: true,
+            ..options
+        };
+        assert_eq!(options.chunk_sizes(5000, 1 << 20), (1000, 1 << 20));
+        let options = OutputOptions {
+            sharding: Some(Sharding::Bytes(4096)),
+            ..Default::default()
+        };
+        assert!(!options.chunk_full(usize::MAX));
+        assert_eq!(options.chunk_sizes(5000, 1 << 20), (5000, 4096));
+    }
+
+    #[test]
+    fn test_sharded_writer() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        assert!(shard_path(&tmp.path().join("out.fq"), 0).is_err());
+        let template = tmp.path().join("out_{shard}.fq");
+        assert_eq!(shard_path(&template, 9)?, tmp.path().join("out_010.fq"));
+
+        // All shards are created, even if empty
+        let options = OutputOptions {
+            sharding: Some(Sharding::Count(3)),
+            ..Default::default()
+        };
+        let mut writer = ShardedWriter::new(&template, None, 1024, options)?;
+        writer.write_chunk(0, b"@r1\n")?;
+        writer.write_chunk(1, b"@r2\n")?;
+        writer.write_chunk(0, b"@r3\n")?;
+        commit_outputs([writer.finish()?])?;
+        assert_eq!(std::fs::read(shard_path(&template, 0)?)?, b"@r1\n@r3\n");
+        assert_eq!(std::fs::read(shard_path(&template, 1)?)?, b"@r2\n");
+        assert_eq!(std::fs::read(shard_path(&template, 2)?)?, b"");
+
+        // Shards filled one after the other are all committed at the end
+        let template = tmp.path().join("part{shard}.fq");
+        let options = OutputOptions {
+            sharding: Some(Sharding::Records(1)),
+            ..Default::default()
+        };
+        let mut writer = ShardedWriter::new(&template, None, 1024, options)?;
+        writer.write_chunk(0, b"@r1\n")?;
+        writer.write_chunk(1, b"@r2\n")?;
+        assert!(!shard_path(&template, 0)?.exists());
+        commit_outputs([writer.finish()?])?;
+        assert_eq!(std::fs::read(shard_path(&template, 0)?)?, b"@r1\n");
+        assert_eq!(std::fs::read(shard_path(&template, 1)?)?, b"@r2\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::Result;
+use extendr_api::prelude::*;
+
+use crate::bam_reader::SequenceReader;
+use crate::fastq_reader::ParseOptions;
+use crate::quarantine::{BadRecordCounts, Quarantine, BAD_RECORD_KINDS};
+use crate::utils::*;
+
+/// Statistics of a validated file
+#[derive(Debug, Default)]
+struct FileStats {
+    records: usize,
+    bases: usize,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    bad: BadRecordCounts,
+    first_error: Option<String>,
+}
+
+#[extendr]
+fn validate_fastq(
+    files: Vec<String>,
+    multiline: bool,
+    threads: usize,
+) -> std::result::Result<List, String> {
+    check_stdio(files.iter().map(|file| file.as_str())).map_err(|e| format!("{:?}", e))?;
+    let threads = threads.max(1); // always use at least one thread
+    let stats = files
+        .iter()
+        .map(|file| validate_file(file, multiline, threads))
+        .collect::<Result<Vec<_>>>()
+        .map_err(|e| format!("{:?}", e))?;
+
+    // Counts may exceed the range of R integers
+    let count = |count: usize| count as f64;
+    let length = |length: Option<usize>| Rfloat::from(length.map(|length| length as f64));
+    let mut names = vec!["file", "records", "bases", "min_length", "max_length"];
+    let mut columns: Vec<Robj> = vec![
+        files.clone().into(),
+        stats.iter().map(|s| count(s.records)).collect::<Vec<_>>().into(),
+        stats.iter().map(|s| count(s.bases)).collect::<Vec<_>>().into(),
+        stats.iter().map(|s| length(s.min_length)).collect::<Vec<_>>().into(),
+        stats.iter().map(|s| length(s.max_length)).collect::<Vec<_>>().into(),
+    ];
+    for (i, kind) in BAD_RECORD_KINDS.iter().enumerate() {
+        names.push(kind);
+        columns.push(stats.iter().map(|s| count(s.bad[i])).collect::<Vec<_>>().into());
+    }
+    names.push("first_error");
+    columns.push(
+        stats
+            .iter()
+            .map(|s| s.first_error.as_deref().map_or_else(Rstr::na, Rstr::from))
+            .collect::<Vec<_>>()
+            .into(),
+    );
+    List::from_names_and_values(names, columns)
+        .map_err(|e| format!("Failed to create list for statistics: {:?}", e))
+}
+
+/// Reads all records of `file` in validation mode, malformed records are
+/// counted instead of aborting.
+fn validate_file(file: &str, multiline: bool, threads: usize) -> Result<FileStats> {
+    let quarantine = Quarantine::new(None, None)?;
+    let parse_options = ParseOptions {
+        multiline,
+        decode_threads: decode_threads(threads, 1),
+        validate: true,
+        quarantine: Some(&quarantine),
+        ..Default::default()
+    };
+    let bar = input_progress_bar(file)?;
+    bar.set_prefix("Validating");
+    let mut reader = SequenceReader::open(std::slice::from_ref(&file), Some(bar), parse_options)?;
+    let mut stats = FileStats::default();
+    while let Some(record) = reader.read_record()? {
+        let length = record.seq.len();
+        stats.records += 1;
+        stats.bases += length;
+        stats.min_length = Some(stats.min_length.map_or(length, |min| min.min(length)));
+        stats.max_length = Some(stats.max_length.map_or(length, |max| max.max(length)));
+    }
+    stats.bad = quarantine.counts();
+    stats.first_error = quarantine.first_error().map(|error| error.to_string());
+    Ok(stats)
+}
+
+extendr_module! {
+    mod validate;
+    fn validate_fastq;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_file() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("reads.fq");
+        std::fs::write(
+            &path,
+            b"@r1\nACGT\n+\n!!!!\n@r2\nAC-T\n+\n!!!!\n@r3\nACGTAC\n+\n!!!!\n@r4\nAC\n+\n!!\n",
+        )?;
+        let stats = validate_file(path.to_str().unwrap(), false, 1)?;
+        assert_eq!(stats.records, 2);
+        assert_eq!(stats.bases, 6);
+        assert_eq!(stats.min_length, Some(2));
+        assert_eq!(stats.max_length, Some(4));
+        // unequal_length for r3, invalid_base for r2
+        assert_eq!(stats.bad, [0, 0, 1, 0, 1, 0, 0]);
+        let first_error = stats.first_error.unwrap();
+        assert!(first_error.contains("(line: 6): invalid base '-'"), "{}", first_error);
+        Ok(())
+    }
+}